@@ -0,0 +1,123 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Samples per second of the generated audio
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// Pitch of the buzzer in Hz
+const BUZZER_FREQUENCY: u64 = 440;
+/// Amplitude of the square wave, a quarter of full scale
+const AMPLITUDE: i16 = i16::MAX / 4;
+/// Emulated frames per second
+const FRAME_RATE: u64 = 60;
+
+/*
+    Turns the buzzer state of every frame into 16-bit mono samples.
+    Frame n covers samples n * rate / 60 up to (n + 1) * rate / 60, so the
+    audio never drifts from the frames, and the square wave's phase only
+    depends on the sample number, so the same run always gives the same samples.
+*/
+#[derive(Clone, Debug)]
+pub struct Buzzer {
+    sample_rate: u32,
+    /// frames turned into samples so far
+    frames: u64,
+}
+
+impl Buzzer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self { sample_rate, frames: 0 }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Samples of the next frame, a square wave if the buzzer was on and silence otherwise
+    pub fn frame(&mut self, on: bool) -> Vec<i16> {
+        let rate = self.sample_rate as u64;
+        let first = self.frames * rate / FRAME_RATE;
+        let last = (self.frames + 1) * rate / FRAME_RATE;
+        self.frames += 1;
+
+        (first..last)
+            .map(|n| match (on, (n * 2 * BUZZER_FREQUENCY / rate) % 2) {
+                (false, _) => 0,
+                (true, 0) => AMPLITUDE,
+                (true, _) => -AMPLITUDE,
+            })
+            .collect()
+    }
+}
+
+/*
+    Writes 16-bit mono PCM samples as a WAV file.
+    The sizes in the header are filled in by finish once they are known.
+*/
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes the header, the sizes are left at 0 until finish
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per second
+        out.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { out, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.out.write_all(&bytes)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Fills in the sizes in the header, returns the number of samples written
+    pub fn finish(mut self) -> io::Result<u32> {
+        let data_size = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Buzzer on, on, off, on at 8000 Hz, written by a separate WAV writer
+    const FIXTURE: &[u8] = include_bytes!("../fixtures/buzzer.wav");
+
+    #[test]
+    fn wav_matches_the_fixture() {
+        let mut buzzer = Buzzer::new(8000);
+        let mut out = Cursor::new(Vec::new());
+        let mut wav = WavWriter::new(&mut out, buzzer.sample_rate()).unwrap();
+        for on in [true, true, false, true] {
+            wav.write_samples(&buzzer.frame(on)).unwrap();
+        }
+        assert_eq!(wav.finish().unwrap(), 533);
+        assert_eq!(out.into_inner(), FIXTURE);
+    }
+
+    #[test]
+    fn frames_cover_every_sample() {
+        let mut buzzer = Buzzer::new(DEFAULT_SAMPLE_RATE);
+        let total: usize = (0..60).map(|_| buzzer.frame(false).len()).sum();
+        assert_eq!(total, DEFAULT_SAMPLE_RATE as usize);
+    }
+}
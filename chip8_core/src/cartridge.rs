@@ -0,0 +1,218 @@
+use serde_json::{Map, Value};
+
+use crate::quirks::Quirks;
+use crate::romdb::{parse_color, RomInfo};
+
+/// Settings Octo stores alongside the program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OctoOptions {
+    /// instructions per frame
+    pub tickrate: Option<usize>,
+    pub quirks: Quirks,
+    /// colors as 0xRRGGBB, plane 1, plane 2, both planes and none
+    pub fill_color: Option<u32>,
+    pub fill_color2: Option<u32>,
+    pub blend_color: Option<u32>,
+    pub background_color: Option<u32>,
+    /// background while the sound timer is running / silent
+    pub buzz_color: Option<u32>,
+    pub quiet_color: Option<u32>,
+    /// clockwise rotation of the screen in degrees: 0, 90, 180 or 270
+    pub screen_rotation: u16,
+}
+
+/// Octo's defaults, used for cartridges without options and for options they leave out
+impl Default for OctoOptions {
+    fn default() -> Self {
+        parse_options(&Map::new())
+    }
+}
+
+impl OctoOptions {
+    /// Replaces the quirks, speed and colors of a ROM database entry with the cartridge's
+    pub fn apply_to(&self, info: &mut RomInfo) {
+        info.quirks = self.quirks;
+        if self.tickrate.is_some() {
+            info.ticks_per_frame = self.tickrate;
+        }
+        if let (Some(fg), Some(bg)) = (self.fill_color, self.background_color) {
+            info.colors = Some((fg, bg));
+        }
+    }
+}
+
+/// The contents of an Octo cartridge
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cartridge {
+    /// Octo assembly source, cartridges do not contain assembled bytes
+    pub program: String,
+    pub options: OctoOptions,
+}
+
+/*
+    Octo cartridges are GIF images that hide a JSON document in their pixels:
+        { "program": "<octo source>", "options": { ... } }
+    Every byte is spread over 4 consecutive pixels of the frames, 2 bits in
+    the low bits of each palette index, most significant bits first.
+    The first 4 bytes are the length of the JSON in big endian.
+*/
+impl Cartridge {
+    /// True if data starts like a GIF image
+    pub fn is_cartridge(data: &[u8]) -> bool {
+        data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+    }
+
+    /// Decodes a cartridge from the bytes of a GIF file
+    pub fn decode(gif: &[u8]) -> Result<Self, String> {
+        let payload = extract_payload(gif)?;
+        let json: Value = serde_json::from_slice(&payload).map_err(|e| format!("invalid cartridge JSON: {}", e))?;
+
+        let program = match json.get("program") {
+            Some(Value::String(program)) => program.clone(),
+            _ => return Err("cartridge has no program".to_string()),
+        };
+        let options = match json.get("options") {
+            Some(Value::Object(options)) => parse_options(options),
+            _ => OctoOptions::default(),
+        };
+        Ok(Self { program, options })
+    }
+}
+
+/// Reads the length prefixed payload out of the low bits of every frame
+fn extract_payload(gif: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(gif)
+        .map_err(|e| format!("invalid GIF: {}", e))?;
+
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| format!("invalid GIF: {}", e))? {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+
+    let bytes: Vec<u8> = pixels
+        .chunks_exact(4)
+        .map(|p| ((p[0] & 3) << 6) | ((p[1] & 3) << 4) | ((p[2] & 3) << 2) | (p[3] & 3))
+        .collect();
+    if bytes.len() < 4 {
+        return Err("cartridge is too small".to_string());
+    }
+
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    match bytes.get(4..4 + size) {
+        Some(payload) => Ok(payload.to_vec()),
+        None => Err("cartridge payload is truncated".to_string()),
+    }
+}
+
+/// Maps Octo's option names onto ours, unknown and unsupported ones are ignored
+fn parse_options(options: &Map<String, Value>) -> OctoOptions {
+    let flag = |name: &str| options.get(name).and_then(Value::as_bool).unwrap_or(false);
+    let color = |name: &str| options.get(name).and_then(Value::as_str).and_then(parse_color);
+
+    // Octo's quirks describe the SUPER-CHIP behaviour, some of them are the inverse of ours
+    let quirks = Quirks {
+        shift_vy: !flag("shiftQuirks"),
+        load_store_increment: !flag("loadStoreQuirks"),
+        jump_vx: flag("jumpQuirks"),
+        vf_reset: flag("logicQuirks"),
+        clip: flag("clipQuirks"),
+    };
+
+    let screen_rotation = match options.get("screenRotation").and_then(Value::as_u64) {
+        Some(r @ (90 | 180 | 270)) => r as u16,
+        _ => 0,
+    };
+
+    OctoOptions {
+        tickrate: options.get("tickrate").and_then(Value::as_u64).map(|t| t as usize),
+        quirks,
+        fill_color: color("fillColor"),
+        fill_color2: color("fillColor2"),
+        blend_color: color("blendColor"),
+        background_color: color("backgroundColor"),
+        buzz_color: color("buzzColor"),
+        quiet_color: color("quietColor"),
+        screen_rotation,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Cartridge written by a separate encoder, its options are
+    /// tickrate 20, #FFCC00 on #996600, shift and jump quirks, rotated 90 degrees
+    const FIXTURE: &[u8] = include_bytes!("../fixtures/cartridge.gif");
+
+    /// Hides payload in a GIF the way Octo does, 2 bits per pixel
+    fn cartridge(payload: &[u8], length: u32) -> Vec<u8> {
+        let data: Vec<u8> = length.to_be_bytes().iter().chain(payload).copied().collect();
+        let mut pixels: Vec<u8> = data.iter().flat_map(|b| [b >> 6, b >> 4, b >> 2, *b].map(|p| p & 3)).collect();
+        pixels.resize(pixels.len().div_ceil(16) * 16, 0);
+        let height = (pixels.len() / 16) as u16;
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 16, height, &[0; 12]).unwrap();
+            let frame = gif::Frame { width: 16, height, buffer: pixels.into(), ..Default::default() };
+            encoder.write_frame(&frame).unwrap();
+        }
+        gif
+    }
+
+    fn json_cartridge(json: Value) -> Vec<u8> {
+        let payload = json.to_string().into_bytes();
+        cartridge(&payload, payload.len() as u32)
+    }
+
+    #[test]
+    fn decodes_the_fixture() {
+        assert!(Cartridge::is_cartridge(FIXTURE));
+        let cart = Cartridge::decode(FIXTURE).unwrap();
+        assert_eq!(cart.program, ": main\n  v0 := 1\n  loop again\n");
+        let options = cart.options;
+        assert_eq!(options.tickrate, Some(20));
+        assert_eq!((options.fill_color, options.background_color), (Some(0xFFCC00), Some(0x996600)));
+        assert_eq!(options.fill_color2, None);
+        assert_eq!(options.screen_rotation, 90);
+        assert_eq!(options.quirks, Quirks::parse("load_store,jump").unwrap());
+    }
+
+    #[test]
+    fn missing_and_empty_options_use_octo_defaults() {
+        let defaults = OctoOptions::default();
+        assert_eq!(defaults.quirks, Quirks::parse("shift,load_store").unwrap());
+        assert_eq!((defaults.tickrate, defaults.screen_rotation), (None, 0));
+
+        let missing = Cartridge::decode(&json_cartridge(json!({ "program": "" }))).unwrap();
+        let empty = Cartridge::decode(&json_cartridge(json!({ "program": "", "options": {} }))).unwrap();
+        assert_eq!(missing.options, defaults);
+        assert_eq!(empty.options, defaults);
+    }
+
+    #[test]
+    fn options_map_onto_quirks() {
+        let options = json!({
+            "shiftQuirks": true,
+            "loadStoreQuirks": true,
+            "logicQuirks": true,
+            "clipQuirks": true,
+            "screenRotation": 45,
+            "fillColor": "nope",
+        });
+        let options = parse_options(options.as_object().unwrap());
+        assert_eq!(options.quirks, Quirks::parse("vf_reset,clip").unwrap());
+        assert_eq!((options.screen_rotation, options.fill_color), (0, None));
+    }
+
+    #[test]
+    fn broken_cartridges_are_rejected() {
+        assert!(!Cartridge::is_cartridge(b"\x00\xE0"));
+        assert!(extract_payload(&FIXTURE[..FIXTURE.len() / 2]).unwrap_err().starts_with("invalid GIF"));
+        assert_eq!(extract_payload(&cartridge(b"{}", 1000)).unwrap_err(), "cartridge payload is truncated");
+        assert_eq!(extract_payload(&cartridge(b"{}", 2)).unwrap(), b"{}");
+        assert_eq!(Cartridge::decode(&cartridge(b"{}", 2)).unwrap_err(), "cartridge has no program");
+        assert!(Cartridge::decode(&cartridge(b"{]", 2)).unwrap_err().starts_with("invalid cartridge JSON"));
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use toml::{Table, Value};
+
+use crate::romdb::sha1_hex;
+use crate::Emu;
+
+/// How a byte must compare for an address to stay in a memory search
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchFilter {
+    /// equal to the value
+    Equal(u8),
+    /// different from the last snapshot
+    Changed,
+    /// the same as in the last snapshot
+    Unchanged,
+    /// greater than in the last snapshot
+    Increased,
+    /// smaller than in the last snapshot
+    Decreased,
+}
+
+impl SearchFilter {
+    /// Parses "=N" (hex), "changed", "unchanged", "increased" or "decreased"
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.trim() {
+            "changed" => Ok(Self::Changed),
+            "unchanged" => Ok(Self::Unchanged),
+            "increased" => Ok(Self::Increased),
+            "decreased" => Ok(Self::Decreased),
+            other => other
+                .strip_prefix('=')
+                .and_then(|value| u8::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok())
+                .map(Self::Equal)
+                .ok_or_else(|| format!("unknown search filter '{}'", text)),
+        }
+    }
+
+    fn matches(&self, old: u8, new: u8) -> bool {
+        match *self {
+            Self::Equal(value) => new == value,
+            Self::Changed => new != old,
+            Self::Unchanged => new == old,
+            Self::Increased => new > old,
+            Self::Decreased => new < old,
+        }
+    }
+}
+
+/// Written the way parse reads it
+impl fmt::Display for SearchFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Equal(value) => write!(f, "={:02X}", value),
+            Self::Changed => write!(f, "changed"),
+            Self::Unchanged => write!(f, "unchanged"),
+            Self::Increased => write!(f, "increased"),
+            Self::Decreased => write!(f, "decreased"),
+        }
+    }
+}
+
+/*
+    Narrows down where a game keeps a value by comparing RAM between
+    snapshots, e.g. lose a life, search "decreased", lose another, search
+    "decreased" again, until only the lives counter is left.
+    Every search keeps the addresses that match and takes a new snapshot.
+*/
+#[derive(Clone, Debug)]
+pub struct MemorySearch {
+    candidates: Vec<u16>,
+    snapshot: Vec<u8>,
+}
+
+impl MemorySearch {
+    /// Starts a search over the whole of RAM
+    pub fn new(memory: &[u8]) -> Self {
+        Self { candidates: (0..memory.len() as u16).collect(), snapshot: memory.to_vec() }
+    }
+
+    /// Keeps the candidates that match filter, returns how many are left
+    pub fn refine(&mut self, memory: &[u8], filter: SearchFilter) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| filter.matches(snapshot[addr as usize], memory[addr as usize]));
+        self.snapshot = memory.to_vec();
+        self.candidates.len()
+    }
+
+    /// Addresses still matching every search so far
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// Value of an address when the last snapshot was taken
+    pub fn previous(&self, addr: u16) -> u8 {
+        self.snapshot[addr as usize]
+    }
+}
+
+/// A named set of bytes to keep frozen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub writes: Vec<(u16, u8)>,
+}
+
+/// Addresses rewritten with a fixed value at the start of every frame
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Freezer {
+    frozen: BTreeMap<u16, u8>,
+}
+
+impl Freezer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn freeze(&mut self, addr: u16, value: u8) {
+        self.frozen.insert(addr, value);
+    }
+
+    /// Returns false if addr was not frozen
+    pub fn unfreeze(&mut self, addr: u16) -> bool {
+        self.frozen.remove(&addr).is_some()
+    }
+
+    pub fn enable(&mut self, cheat: &Cheat) {
+        for &(addr, value) in cheat.writes.iter() {
+            self.freeze(addr, value);
+        }
+    }
+
+    pub fn disable(&mut self, cheat: &Cheat) {
+        for &(addr, _) in cheat.writes.iter() {
+            self.unfreeze(addr);
+        }
+    }
+
+    pub fn frozen(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.frozen.iter().map(|(addr, value)| (*addr, *value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frozen.is_empty()
+    }
+
+    /// Writes the frozen values into RAM, call before running each frame
+    pub fn apply(&self, emu: &mut Emu) {
+        for (&addr, &value) in self.frozen.iter() {
+            emu.write_memory(addr, &[value]);
+        }
+    }
+}
+
+/*
+    Cheat lists keyed by ROM SHA-1, each cheat a list of hex "address:value"
+    bytes that are frozen while it is enabled:
+
+        ["<sha1>"]
+        "Infinite lives" = ["2F0:03"]
+        "Max score" = ["2F1:99", "2F2:99"]
+*/
+#[derive(Clone, Debug, Default)]
+pub struct CheatDb {
+    entries: HashMap<String, Vec<Cheat>>,
+}
+
+impl CheatDb {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table: Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        let mut entries = HashMap::new();
+        for (sha1, value) in table.iter() {
+            let Value::Table(cheats) = value else {
+                return Err(format!("[{}] is not a table", sha1));
+            };
+            let cheats = cheats
+                .iter()
+                .map(|(name, writes)| parse_cheat(name, writes))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("[{}] {}", sha1, e))?;
+            entries.insert(sha1.to_lowercase(), cheats);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Cheats for a ROM, looked up by its contents
+    pub fn lookup(&self, rom: &[u8]) -> &[Cheat] {
+        self.get(&sha1_hex(rom))
+    }
+
+    pub fn get(&self, sha1: &str) -> &[Cheat] {
+        self.entries.get(&sha1.to_lowercase()).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn parse_cheat(name: &str, writes: &Value) -> Result<Cheat, String> {
+    let invalid = || format!("invalid cheat '{}'", name);
+    let writes = writes
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|write| {
+            let (addr, value) = write.as_str().and_then(|w| w.split_once(':')).ok_or_else(invalid)?;
+            let addr = u16::from_str_radix(addr.trim(), 16).ok().filter(|a| *a < 0x1000).ok_or_else(invalid)?;
+            let value = u8::from_str_radix(value.trim(), 16).map_err(|_| invalid())?;
+            Ok((addr, value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(Cheat { name: name.to_string(), writes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRIX: &[u8] = include_bytes!("../../games/BRIX");
+
+    /// RAM of 16 bytes with some of them set
+    fn memory(bytes: &[(usize, u8)]) -> Vec<u8> {
+        let mut memory = vec![0; 16];
+        for &(addr, value) in bytes {
+            memory[addr] = value;
+        }
+        memory
+    }
+
+    #[test]
+    fn filters_parse_and_print() {
+        for text in ["changed", "unchanged", "increased", "decreased", "=2A"] {
+            assert_eq!(SearchFilter::parse(text).unwrap().to_string(), text);
+        }
+        assert_eq!(SearchFilter::parse("=0x0f"), Ok(SearchFilter::Equal(0x0F)));
+        assert!(SearchFilter::parse("=100").is_err());
+        assert!(SearchFilter::parse("bigger").is_err());
+    }
+
+    #[test]
+    fn search_narrows_down_an_address() {
+        let mut search = MemorySearch::new(&memory(&[(3, 3), (5, 3), (7, 1)]));
+        assert_eq!(search.refine(&memory(&[(3, 3), (5, 3), (7, 1)]), SearchFilter::Equal(3)), 2);
+        assert_eq!(search.candidates(), &[3, 5]);
+
+        // The lives counter at 5 goes down, 3 stays
+        assert_eq!(search.refine(&memory(&[(3, 3), (5, 2), (7, 1)]), SearchFilter::Changed), 1);
+        assert_eq!(search.candidates(), &[5]);
+        assert_eq!(search.previous(5), 2);
+        assert_eq!(search.refine(&memory(&[(3, 3), (5, 2)]), SearchFilter::Unchanged), 1);
+        assert_eq!(search.refine(&memory(&[(5, 1)]), SearchFilter::Decreased), 1);
+        assert_eq!(search.refine(&memory(&[(5, 1)]), SearchFilter::Increased), 0);
+    }
+
+    #[test]
+    fn unchanged_and_changed_split_memory() {
+        let before = memory(&[(0, 1), (1, 1)]);
+        let after = memory(&[(0, 1), (1, 2)]);
+        let mut unchanged = MemorySearch::new(&before);
+        let mut changed = MemorySearch::new(&before);
+        assert_eq!(unchanged.refine(&after, SearchFilter::Unchanged), 15);
+        assert_eq!(changed.refine(&after, SearchFilter::Changed), 1);
+        assert!(!unchanged.candidates().contains(&1));
+    }
+
+    #[test]
+    fn freezer_rewrites_enabled_cheats() {
+        let lives = Cheat { name: "lives".to_string(), writes: vec![(0x300, 9)] };
+        let score = Cheat { name: "score".to_string(), writes: vec![(0x301, 0x99), (0x302, 0x99)] };
+        let mut freezer = Freezer::new();
+        assert!(freezer.is_empty());
+        freezer.enable(&lives);
+        freezer.enable(&score);
+        freezer.disable(&score);
+        assert_eq!(freezer.frozen().collect::<Vec<_>>(), [(0x300, 9)]);
+        assert!(!freezer.unfreeze(0x301));
+
+        let mut emu = Emu::new();
+        emu.load(BRIX).unwrap();
+        emu.write_memory(0x300, &[1, 2]);
+        freezer.apply(&mut emu);
+        assert_eq!(&emu.memory()[0x300..0x302], &[9, 2]);
+    }
+
+    #[test]
+    fn cheat_lists_are_looked_up_by_rom() {
+        let text = format!(
+            "[\"{}\"]\n\"Infinite lives\" = [\"2F0:03\"]\n\"Max score\" = [\"2F1:99\", \" 2F2 : 99 \"]\n",
+            sha1_hex(BRIX).to_uppercase()
+        );
+        let db = CheatDb::parse(&text).unwrap();
+        let cheats = db.lookup(BRIX);
+        assert_eq!(cheats.len(), 2);
+        let score = cheats.iter().find(|c| c.name == "Max score").unwrap();
+        assert_eq!(score.writes, [(0x2F1, 0x99), (0x2F2, 0x99)]);
+        assert!(db.lookup(b"other").is_empty());
+    }
+
+    #[test]
+    fn broken_cheat_lists_are_rejected() {
+        for text in [
+            "x = 1",
+            "[a]\nc = \"2F0:03\"",
+            "[a]\nc = [\"2F0\"]",
+            "[a]\nc = [\"1000:03\"]",
+            "[a]\nc = [\"2F0:100\"]",
+            "[a]\nc = [3]",
+        ] {
+            assert!(CheatDb::parse(text).is_err(), "{}", text);
+        }
+        assert_eq!(CheatDb::parse("[a]\nc = [\"2F0\"]").unwrap_err(), "[a] invalid cheat 'c'");
+    }
+}
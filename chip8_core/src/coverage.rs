@@ -0,0 +1,227 @@
+use std::fmt::Write;
+
+use crate::disasm::disassemble;
+use crate::{RAM_SIZE, START_ADDR};
+
+/// Summary of how the bytes of a ROM were used
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoverageSummary {
+    /// bytes executed as part of an instruction
+    pub executed: usize,
+    /// bytes never executed but read or written through I
+    pub data: usize,
+    /// bytes never touched at all
+    pub untouched: usize,
+}
+
+/*
+    Tracks for every address whether it was executed as an instruction,
+    read as data through I (DXYN, FX65) or written through I (FX33, FX55).
+    Installed into an Emu with Emu::set_coverage.
+*/
+pub struct Coverage {
+    /// number of times an instruction starting at each address was executed
+    exec: Vec<u32>,
+    /// address was part of an executed instruction
+    executed: Vec<bool>,
+    read: Vec<bool>,
+    written: Vec<bool>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            exec: vec![0; RAM_SIZE],
+            executed: vec![false; RAM_SIZE],
+            read: vec![false; RAM_SIZE],
+            written: vec![false; RAM_SIZE],
+        }
+    }
+
+    /// Records the instruction starting at pc
+    pub(crate) fn mark_exec(&mut self, pc: u16) {
+        let pc = pc as usize % RAM_SIZE;
+        self.exec[pc] = self.exec[pc].saturating_add(1);
+        self.executed[pc] = true;
+        self.executed[(pc + 1) % RAM_SIZE] = true;
+    }
+
+    /// Records len bytes read starting at addr
+    pub(crate) fn mark_read(&mut self, addr: u16, len: usize) {
+        for i in 0..len {
+            self.read[(addr as usize + i) % RAM_SIZE] = true;
+        }
+    }
+
+    /// Records len bytes written starting at addr
+    pub(crate) fn mark_write(&mut self, addr: u16, len: usize) {
+        for i in 0..len {
+            self.written[(addr as usize + i) % RAM_SIZE] = true;
+        }
+    }
+
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.executed[addr as usize % RAM_SIZE]
+    }
+
+    pub fn is_read(&self, addr: u16) -> bool {
+        self.read[addr as usize % RAM_SIZE]
+    }
+
+    pub fn is_written(&self, addr: u16) -> bool {
+        self.written[addr as usize % RAM_SIZE]
+    }
+
+    /// Addresses of a ROM of rom_len bytes loaded at 0x200
+    fn rom_addrs(rom_len: usize) -> std::ops::Range<u16> {
+        let end = (START_ADDR as usize + rom_len).min(RAM_SIZE);
+        START_ADDR..end as u16
+    }
+
+    /// Flags column of the annotated disassembly, X executed, R read, W written
+    fn flags(&self, addr: u16) -> String {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        [
+            flag(self.is_executed(addr), 'X'),
+            flag(self.is_read(addr), 'R'),
+            flag(self.is_written(addr), 'W'),
+        ]
+        .iter()
+        .collect()
+    }
+
+    pub fn summary(&self, rom_len: usize) -> CoverageSummary {
+        let mut summary = CoverageSummary::default();
+        for addr in Self::rom_addrs(rom_len) {
+            if self.is_executed(addr) {
+                summary.executed += 1;
+            } else if self.is_read(addr) || self.is_written(addr) {
+                summary.data += 1;
+            } else {
+                summary.untouched += 1;
+            }
+        }
+        summary
+    }
+
+    /// Disassembly of the ROM where executed bytes are shown as instructions
+    /// and everything else as data bytes, each line with its coverage flags
+    pub fn annotated(&self, memory: &[u8], rom_len: usize) -> String {
+        let mut out = String::new();
+        let range = Self::rom_addrs(rom_len);
+        let mut addr = range.start;
+        while addr < range.end {
+            let a = addr as usize;
+            if self.exec[a] > 0 && addr + 1 < range.end {
+                let op = ((memory[a] as u16) << 8) | memory[a + 1] as u16;
+                writeln!(out, "{:03X}  {:04X}  {}  {:>8}  {}", addr, op, self.flags(addr), self.exec[a], disassemble(op))
+                    .unwrap();
+                addr += 2;
+            } else {
+                writeln!(out, "{:03X}  {:02X}    {}            DB {:02X}", addr, memory[a], self.flags(addr), memory[a])
+                    .unwrap();
+                addr += 1;
+            }
+        }
+
+        let summary = self.summary(rom_len);
+        writeln!(
+            out,
+            "; {} bytes executed, {} bytes data, {} bytes untouched",
+            summary.executed, summary.data, summary.untouched
+        )
+        .unwrap();
+        out
+    }
+
+    /// lcov tracefile where every ROM address that could hold an instruction is a "line"
+    /// Bytes only ever used as data are left out, untouched ones count as missed
+    pub fn lcov(&self, rom_name: &str, rom_len: usize) -> String {
+        let mut out = String::new();
+        let (mut found, mut hit) = (0, 0);
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", rom_name).unwrap();
+        for addr in Self::rom_addrs(rom_len) {
+            let count = self.exec[addr as usize];
+            let is_data = !self.is_executed(addr) && (self.is_read(addr) || self.is_written(addr));
+            // The second byte of an executed instruction is not a line of its own
+            let is_operand = count == 0 && self.is_executed(addr);
+            if is_data || is_operand {
+                continue;
+            }
+            writeln!(out, "DA:{},{}", addr, count).unwrap();
+            found += 1;
+            if count > 0 {
+                hit += 1;
+            }
+        }
+        writeln!(out, "LF:{}", found).unwrap();
+        writeln!(out, "LH:{}", hit).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emu;
+
+    /// Draws and overwrites a data byte, then spins on a jump to itself
+    const ROM: [u8; 11] = [
+        0xA2, 0x0A, // 200 LD I, 20A
+        0xD0, 0x11, // 202 DRW V0, V1, 1
+        0xF0, 0x55, // 204 LD [I], V0
+        0x12, 0x06, // 206 JP 206
+        0x00, 0x00, // 208
+        0xFF, // 20A
+    ];
+
+    fn cover(ticks: usize) -> Emu {
+        let mut emu = Emu::new();
+        emu.load(&ROM).unwrap();
+        emu.set_coverage(Some(Coverage::new()));
+        for _ in 0..ticks {
+            emu.tick();
+        }
+        emu
+    }
+
+    #[test]
+    fn marks_executed_read_and_written_bytes() {
+        let emu = cover(5);
+        let coverage = emu.coverage().unwrap();
+        assert!((0x200..0x208).all(|a| coverage.is_executed(a)));
+        assert!(!coverage.is_executed(0x208) && !coverage.is_executed(0x20A));
+        assert!(coverage.is_read(0x20A) && coverage.is_written(0x20A));
+        assert!(!coverage.is_read(0x20B) && !coverage.is_written(0x209));
+        assert_eq!(coverage.summary(ROM.len()), CoverageSummary { executed: 8, data: 1, untouched: 2 });
+    }
+
+    #[test]
+    fn annotated_listing_shows_counts_and_flags() {
+        let emu = cover(5);
+        let listing = emu.coverage().unwrap().annotated(emu.memory(), ROM.len());
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[3], format!("206  1206  X--         2  {}", disassemble(0x1206)));
+        assert_eq!(lines[4], "208  00    ---            DB 00");
+        assert_eq!(lines[6], "20A  00    -RW            DB 00");
+        assert_eq!(lines[7], "; 8 bytes executed, 1 bytes data, 2 bytes untouched");
+    }
+
+    #[test]
+    fn lcov_skips_operands_and_data() {
+        let emu = cover(5);
+        let lcov = emu.coverage().unwrap().lcov("test.ch8", ROM.len());
+        let expected = "TN:\nSF:test.ch8\nDA:512,1\nDA:514,1\nDA:516,1\nDA:518,2\nDA:520,0\nDA:521,0\n\
+                        LF:6\nLH:4\nend_of_record\n";
+        assert_eq!(lcov, expected);
+    }
+}
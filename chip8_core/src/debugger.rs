@@ -0,0 +1,227 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::disasm::is_implemented;
+use crate::{Emu, STACK_SIZE};
+
+/// Machine registers as seen by a debugger
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u16,
+    pub dt: u8,
+    pub st: u8,
+}
+
+/// A data access through I made by the last instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub len: usize,
+    pub write: bool,
+}
+
+/// Which accesses trigger a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addrs: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// First watched address touched by an access, if it is of the watched kind
+    fn hit(&self, access: &MemAccess) -> Option<u16> {
+        let kind_matches = match self.kind {
+            WatchKind::Read => !access.write,
+            WatchKind::Write => access.write,
+            WatchKind::Access => true,
+        };
+        let last = access.addr as usize + access.len.max(1) - 1;
+        let first = (*self.addrs.start()).max(access.addr);
+        (kind_matches && first as usize <= last.min(*self.addrs.end() as usize)).then_some(first)
+    }
+}
+
+/// Why the machine stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// a single step finished
+    Step,
+    /// about to execute the instruction at a breakpoint
+    Breakpoint(u16),
+    /// the last instruction touched a watched address
+    Watchpoint { addr: u16, kind: WatchKind },
+    /// the instruction at this address cannot be executed, it was not run
+    IllegalInstruction(u16),
+    /// running the instruction at addr would break the machine, it was not run
+    Fault { addr: u16, fault: Fault },
+}
+
+/// What running an instruction would break
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// a call with every stack entry in use
+    StackOverflow,
+    /// a return with nothing on the stack
+    StackUnderflow,
+    /// an access through I past the end of RAM
+    OutOfRange,
+}
+
+impl Fault {
+    /// Checks the instruction op against the machine it is about to run on
+    fn check(emu: &Emu, op: u16) -> Option<Self> {
+        let regs = emu.registers();
+        let (i, sp, ram) = (regs.i as usize, regs.sp as usize, emu.memory().len());
+        let x = ((op >> 8) & 0xF) as usize;
+        match (op >> 12, op & 0xFF) {
+            _ if op == 0x00EE && sp == 0 => Some(Fault::StackUnderflow),
+            (0x2, _) if sp >= STACK_SIZE => Some(Fault::StackOverflow),
+            (0xD, _) if i + (op & 0xF) as usize > ram => Some(Fault::OutOfRange),
+            (0xF, 0x33) if i + 3 > ram => Some(Fault::OutOfRange),
+            (0xF, 0x55 | 0x65) if i + x + 1 > ram => Some(Fault::OutOfRange),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Fault::StackOverflow => "stack overflow",
+            Fault::StackUnderflow => "stack underflow",
+            Fault::OutOfRange => "access past RAM",
+        })
+    }
+}
+
+/*
+    Runs the machine instruction by instruction for debugger front-ends,
+    stopping at breakpoints, watchpoints and before instructions the
+    emulator cannot execute. It keeps count of the
+    instructions in the current frame so the timers still tick every
+    ticks_per_frame instructions.
+*/
+#[derive(Clone, Debug)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    ticks_per_frame: usize,
+    /// instructions run in the current frame
+    ticks: usize,
+    /// breakpoint the machine last stopped at, passed over when running again
+    stopped_at: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(ticks_per_frame: usize) -> Self {
+        Self { breakpoints: BTreeSet::new(), watchpoints: Vec::new(), ticks_per_frame: ticks_per_frame.max(1), ticks: 0, stopped_at: None }
+    }
+
+    /// Changes the speed, a frame already past the new length ends with the next instruction
+    pub fn set_ticks_per_frame(&mut self, ticks_per_frame: usize) {
+        self.ticks_per_frame = ticks_per_frame.max(1);
+        self.ticks = self.ticks.min(self.ticks_per_frame - 1);
+    }
+
+    /// True if some but not all instructions of the current frame have run
+    pub fn mid_frame(&self) -> bool {
+        self.ticks > 0
+    }
+
+    /// Returns false if there already was a breakpoint at addr
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Returns false if there was no breakpoint at addr
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Returns false if there was no such watchpoint
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Runs one instruction, ticking the timers when a frame is complete
+    /// An instruction the emulator does not implement, or one that would
+    /// overflow the stack or reach past RAM, is reported instead of run
+    pub fn step(&mut self, emu: &mut Emu) -> StopReason {
+        let pc = emu.pc();
+        let op = emu.memory().get(pc as usize..pc as usize + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let Some(op) = op.filter(|op| is_implemented(*op)) else {
+            return StopReason::IllegalInstruction(pc);
+        };
+        if let Some(fault) = Fault::check(emu, op) {
+            return StopReason::Fault { addr: pc, fault };
+        }
+        self.stopped_at = None;
+        emu.tick();
+        self.ticks += 1;
+        if self.ticks == self.ticks_per_frame {
+            emu.tick_timers();
+            self.ticks = 0;
+        }
+
+        let hit = emu.last_access().and_then(|access| {
+            self.watchpoints.iter().find_map(|w| w.hit(&access).map(|addr| (addr, w.kind)))
+        });
+        match hit {
+            Some((addr, kind)) => StopReason::Watchpoint { addr, kind },
+            None => StopReason::Step,
+        }
+    }
+
+    /// Runs up to max instructions, None if it did not stop before that
+    /// Continuing from a breakpoint runs its instruction instead of stopping right away
+    pub fn run(&mut self, emu: &mut Emu, max: usize) -> Option<StopReason> {
+        for _ in 0..max {
+            let pc = emu.pc();
+            if self.breakpoints.contains(&pc) && self.stopped_at != Some(pc) {
+                self.stopped_at = Some(pc);
+                return Some(StopReason::Breakpoint(pc));
+            }
+            match self.step(emu) {
+                StopReason::Step => (),
+                reason => return Some(reason),
+            }
+        }
+        None
+    }
+
+    /// Runs the rest of the current frame, None if it completed without stopping
+    pub fn run_frame(&mut self, emu: &mut Emu) -> Option<StopReason> {
+        self.run(emu, self.ticks_per_frame - self.ticks)
+    }
+}
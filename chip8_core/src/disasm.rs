@@ -0,0 +1,87 @@
+use crate::quirks::Quirks;
+
+/// Decodes an opcode into the mnemonic used in the README opcode table
+/// Opcodes the emulator does not implement are shown as DATA
+pub fn disassemble(op: u16) -> String {
+    disassemble_with_quirks(op, &Quirks::default())
+}
+
+/// True if the emulator can execute op, anything else stops it
+pub fn is_implemented(op: u16) -> bool {
+    !disassemble(op).starts_with("DATA")
+}
+
+/// Like disassemble, showing BXNN as VX + XNN when the jump quirk is on
+pub fn disassemble_with_quirks(op: u16, quirks: &Quirks) -> String {
+    let digit1 = (op & 0xF000) >> 12;
+    let digit2 = (op & 0x0F00) >> 8;
+    let digit3 = (op & 0x00F0) >> 4;
+    let digit4 = op & 0x000F;
+
+    let x = digit2;
+    let y = digit3;
+    let n = digit4;
+    let nn = op & 0xFF;
+    let nnn = op & 0xFFF;
+
+    match (digit1, digit2, digit3, digit4) {
+        (0, 0, 0, 0) => "NOP".to_string(),
+        (0, 0, 0xE, 0) => "CLS".to_string(),
+        (0, 0, 0xE, 0xE) => "RET".to_string(),
+        (1, _, _, _) => format!("JMP {:03X}", nnn),
+        (2, _, _, _) => format!("CALL {:03X}", nnn),
+        (3, _, _, _) => format!("SKIP V{:X} == {:02X}", x, nn),
+        (4, _, _, _) => format!("SKIP V{:X} != {:02X}", x, nn),
+        // The emulator ignores the last digit of 5XYN
+        (5, _, _, _) => format!("SKIP V{:X} == V{:X}", x, y),
+        (6, _, _, _) => format!("V{:X} = {:02X}", x, nn),
+        (7, _, _, _) => format!("V{:X} += {:02X}", x, nn),
+        (8, _, _, 0) => format!("V{:X} = V{:X}", x, y),
+        (8, _, _, 1) => format!("V{:X} |= V{:X}", x, y),
+        (8, _, _, 2) => format!("V{:X} &= V{:X}", x, y),
+        (8, _, _, 3) => format!("V{:X} ^= V{:X}", x, y),
+        (8, _, _, 4) => format!("V{:X} += V{:X}", x, y),
+        (8, _, _, 5) => format!("V{:X} -= V{:X}", x, y),
+        (8, _, _, 6) => format!("V{:X} >>= 1", x),
+        (8, _, _, 7) => format!("V{:X} = V{:X} - V{:X}", x, y, x),
+        (8, _, _, 0xE) => format!("V{:X} <<= 1", x),
+        (9, _, _, 0) => format!("SKIP V{:X} != V{:X}", x, y),
+        (0xA, _, _, _) => format!("I = {:03X}", nnn),
+        (0xB, _, _, _) if quirks.jump_vx => format!("JMP V{:X} + {:03X}", x, nnn),
+        (0xB, _, _, _) => format!("JMP V0 + {:03X}", nnn),
+        (0xC, _, _, _) => format!("V{:X} = rand() & {:02X}", x, nn),
+        (0xD, _, _, _) => format!("DRAW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 9, 0xE) => format!("SKIP KEY V{:X}", x),
+        (0xE, _, 0xA, 1) => format!("SKIP !KEY V{:X}", x),
+        (0xF, _, 0, 7) => format!("V{:X} = DT", x),
+        (0xF, _, 0, 0xA) => format!("WAIT KEY V{:X}", x),
+        (0xF, _, 1, 5) => format!("DT = V{:X}", x),
+        (0xF, _, 1, 8) => format!("ST = V{:X}", x),
+        (0xF, _, 1, 0xE) => format!("I += V{:X}", x),
+        (0xF, _, 2, 9) => format!("I = FONT V{:X}", x),
+        (0xF, _, 3, 3) => format!("BCD V{:X}", x),
+        (0xF, _, 5, 5) => format!("STORE V0 - V{:X}", x),
+        (0xF, _, 6, 5) => format!("LOAD V0 - V{:X}", x),
+        (_, _, _, _) => format!("DATA {:04X}", op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_5xyn_is_a_skip_like_the_emulator() {
+        assert_eq!(disassemble(0x5120), "SKIP V1 == V2");
+        assert_eq!(disassemble(0x5123), "SKIP V1 == V2");
+        assert!(is_implemented(0x5123));
+        assert!(!is_implemented(0x8128));
+    }
+
+    #[test]
+    fn jump_quirk_changes_bnnn() {
+        let quirks = Quirks { jump_vx: true, ..Quirks::default() };
+        assert_eq!(disassemble(0xB234), "JMP V0 + 234");
+        assert_eq!(disassemble_with_quirks(0xB234, &quirks), "JMP V2 + 234");
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+use crate::disasm::{disassemble_with_quirks, is_implemented};
+use crate::quirks::Quirks;
+use crate::START_ADDR;
+
+/// How control gets from one block to another
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// falls through to the next instruction
+    Next,
+    /// 1NNN
+    Jump,
+    /// taken branch of a skip instruction
+    Skip,
+    /// 2NNN, execution comes back to the Next edge of the same block
+    Call,
+}
+
+/// A run of instructions that is only entered at the start and left at the end
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    /// address of the last instruction
+    pub end: u16,
+    pub succs: Vec<(u16, EdgeKind)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// reachable instructions
+    Code,
+    /// not reachable but referenced by ANNN, most likely sprites or tables
+    Data,
+    /// neither reachable nor referenced
+    Unreachable,
+}
+
+/// A contiguous range of ROM addresses [start, end) of a single kind
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+/// A BNNN jump, whose target depends on a register at runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ComputedJump {
+    pub addr: u16,
+    /// V0, or VX with the jump quirk
+    pub reg: u8,
+    /// NNN, the register is added to it
+    pub base: u16,
+}
+
+impl ComputedJump {
+    /// Every address the jump can land on
+    pub fn targets(&self) -> RangeInclusive<u16> {
+        self.base..=self.base + 0xFF
+    }
+}
+
+/*
+    Control-flow graph of a ROM found by walking it statically from 0x200,
+    following jumps, calls and both sides of every skip.
+    BNNN jumps depend on V0 (VX with the jump quirk) at runtime so they
+    cannot be followed, they are listed in computed_jumps instead.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct FlowGraph {
+    /// basic blocks by start address
+    pub blocks: BTreeMap<u16, Block>,
+    /// subroutine entry -> start of every block belonging to it
+    /// the program itself is listed as a subroutine at 0x200
+    pub subroutines: BTreeMap<u16, Vec<u16>>,
+    /// BNNN instructions
+    pub computed_jumps: Vec<ComputedJump>,
+    /// (address, target) of jumps, calls and skips leaving the ROM
+    pub bad_targets: Vec<(u16, u16)>,
+    /// addresses loaded into I by ANNN
+    pub data_refs: BTreeSet<u16>,
+    /// the whole ROM split into code, data and unreachable regions
+    pub regions: Vec<Region>,
+    /// quirks the ROM was analyzed with
+    pub quirks: Quirks,
+}
+
+/// Successors of the instruction op at addr, None for RET and BNNN
+fn successors(addr: u16, op: u16) -> Vec<(u16, EdgeKind)> {
+    let next = addr.wrapping_add(2);
+    let skip = addr.wrapping_add(4);
+    match (op & 0xF000, op & 0xF00F, op & 0xF0FF) {
+        _ if op == 0x00EE => vec![],
+        (0x1000, _, _) => vec![(op & 0xFFF, EdgeKind::Jump)],
+        (0x2000, _, _) => vec![(op & 0xFFF, EdgeKind::Call), (next, EdgeKind::Next)],
+        (0x3000, _, _) | (0x4000, _, _) | (0x5000, _, _) | (_, 0x9000, _) | (_, _, 0xE09E) | (_, _, 0xE0A1) => {
+            vec![(next, EdgeKind::Next), (skip, EdgeKind::Skip)]
+        },
+        (0xB000, _, _) => vec![],
+        _ => vec![(next, EdgeKind::Next)],
+    }
+}
+
+impl FlowGraph {
+    /// Analyzes a ROM as it would be loaded at 0x200 and run with quirks
+    pub fn analyze(rom: &[u8], quirks: Quirks) -> Self {
+        let start = START_ADDR;
+        let end = START_ADDR as usize + rom.len();
+        let in_rom = |addr: u16| addr >= start && (addr as usize) + 1 < end;
+        let op_at = |addr: u16| {
+            let i = (addr - start) as usize;
+            ((rom[i] as u16) << 8) | rom[i + 1] as u16
+        };
+
+        let mut graph = Self { quirks, ..Self::default() };
+
+        // Walk every reachable instruction
+        let mut instrs: BTreeMap<u16, Vec<(u16, EdgeKind)>> = BTreeMap::new();
+        let mut leaders = BTreeSet::from([start]);
+        let mut entries = BTreeSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(addr) = queue.pop_front() {
+            if instrs.contains_key(&addr) || !in_rom(addr) {
+                continue;
+            }
+            let op = op_at(addr);
+            if !is_implemented(op) {
+                // Execution would stop here, keep the instruction without successors
+                instrs.insert(addr, vec![]);
+                continue;
+            }
+            if op & 0xF000 == 0xA000 {
+                graph.data_refs.insert(op & 0xFFF);
+            }
+            if op & 0xF000 == 0xB000 {
+                let reg = if quirks.jump_vx { ((op >> 8) & 0xF) as u8 } else { 0 };
+                graph.computed_jumps.push(ComputedJump { addr, reg, base: op & 0xFFF });
+            }
+
+            let succs = successors(addr, op);
+            let branches = !matches!(succs.as_slice(), [(_, EdgeKind::Next)]);
+            for &(target, kind) in succs.iter() {
+                if !in_rom(target) {
+                    graph.bad_targets.push((addr, target));
+                    continue;
+                }
+                if kind == EdgeKind::Call {
+                    entries.insert(target);
+                }
+                if branches {
+                    leaders.insert(target);
+                }
+                queue.push_back(target);
+            }
+            instrs.insert(addr, succs);
+        }
+
+        // Split the instructions into basic blocks
+        for &leader in leaders.iter().filter(|a| instrs.contains_key(a)) {
+            let mut last = leader;
+            loop {
+                let next = last.wrapping_add(2);
+                let falls_through = matches!(instrs[&last].as_slice(), [(n, EdgeKind::Next)] if *n == next);
+                if !falls_through || !instrs.contains_key(&next) || leaders.contains(&next) {
+                    break;
+                }
+                last = next;
+            }
+            let succs = instrs[&last].iter().filter(|(t, _)| in_rom(*t)).copied().collect();
+            graph.blocks.insert(leader, Block { start: leader, end: last, succs });
+        }
+
+        // Blocks of each subroutine, calls are not followed
+        for &entry in entries.iter().filter(|a| graph.blocks.contains_key(a)) {
+            let mut members = BTreeSet::new();
+            let mut queue = VecDeque::from([entry]);
+            while let Some(addr) = queue.pop_front() {
+                if !members.insert(addr) {
+                    continue;
+                }
+                for &(target, kind) in graph.blocks[&addr].succs.iter() {
+                    if kind != EdgeKind::Call && graph.blocks.contains_key(&target) {
+                        queue.push_back(target);
+                    }
+                }
+            }
+            graph.subroutines.insert(entry, members.into_iter().collect());
+        }
+
+        // Classify every byte of the ROM
+        let mut kinds = vec![RegionKind::Unreachable; rom.len()];
+        for &addr in instrs.keys() {
+            let i = (addr - start) as usize;
+            kinds[i] = RegionKind::Code;
+            kinds[i + 1] = RegionKind::Code;
+        }
+        let mut i = 0;
+        while i < kinds.len() {
+            let kind = kinds[i];
+            let mut j = i;
+            while j < kinds.len() && kinds[j] == kind {
+                j += 1;
+            }
+            let (r_start, r_end) = (start + i as u16, start + j as u16);
+            let kind = match kind {
+                RegionKind::Unreachable if graph.data_refs.range(r_start..r_end).next().is_some() => RegionKind::Data,
+                kind => kind,
+            };
+            graph.regions.push(Region { start: r_start, end: r_end, kind });
+            i = j;
+        }
+
+        graph
+    }
+
+    /// True if addr holds (part of) a reachable instruction
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.regions.iter().any(|r| r.kind == RegionKind::Code && (r.start..r.end).contains(&addr))
+    }
+
+    /// Graphviz DOT rendering, one cluster per subroutine
+    /// memory is the RAM with the ROM loaded, see Emu::memory
+    pub fn to_dot(&self, memory: &[u8]) -> String {
+        let mut out = String::new();
+        let op_at = |addr: u16| ((memory[addr as usize] as u16) << 8) | memory[addr as usize + 1] as u16;
+
+        writeln!(out, "digraph rom {{").unwrap();
+        writeln!(out, "    node [shape=box fontname=monospace];").unwrap();
+
+        let mut drawn = BTreeSet::new();
+        for (entry, members) in self.subroutines.iter() {
+            let label = if *entry == START_ADDR { "main".to_string() } else { format!("sub {:03X}", entry) };
+            writeln!(out, "    subgraph cluster_{:03X} {{", entry).unwrap();
+            writeln!(out, "        label=\"{}\";", label).unwrap();
+            for start in members.iter().filter(|a| drawn.insert(**a)) {
+                let block = &self.blocks[start];
+                let mut text = String::new();
+                for addr in (block.start..=block.end).step_by(2) {
+                    write!(text, "{:03X}: {}\\l", addr, disassemble_with_quirks(op_at(addr), &self.quirks)).unwrap();
+                }
+                let computed = self.computed_jumps.iter().any(|j| j.addr == block.end);
+                let color = if computed { " color=red" } else { "" };
+                writeln!(out, "        b{:03X} [label=\"{}\"{}];", start, text, color).unwrap();
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+
+        for block in self.blocks.values() {
+            for (target, kind) in block.succs.iter() {
+                let style = match kind {
+                    EdgeKind::Next => "",
+                    EdgeKind::Jump => " [label=jump]",
+                    EdgeKind::Skip => " [label=skip]",
+                    EdgeKind::Call => " [label=call style=dashed]",
+                };
+                writeln!(out, "    b{:03X} -> b{:03X}{};", block.start, target, style).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_5xyn_has_both_successors() {
+        // 200: 5121 skip, 202: 1202 loop, 204: 1204 loop
+        let graph = FlowGraph::analyze(&[0x51, 0x21, 0x12, 0x02, 0x12, 0x04], Quirks::default());
+        assert!(graph.is_code(0x204));
+        assert_eq!(graph.blocks[&0x200].succs, [(0x202, EdgeKind::Next), (0x204, EdgeKind::Skip)]);
+    }
+
+    #[test]
+    fn computed_jump_register_follows_the_quirk() {
+        let rom = [0xB3, 0x00];
+        let jump = FlowGraph::analyze(&rom, Quirks::default()).computed_jumps[0];
+        assert_eq!((jump.reg, jump.targets()), (0, 0x300..=0x3FF));
+        let jump = FlowGraph::analyze(&rom, Quirks { jump_vx: true, ..Quirks::default() }).computed_jumps[0];
+        assert_eq!((jump.reg, jump.base), (3, 0x300));
+    }
+
+    /// Main program calling a subroutine, with a skip, jumps, a dead word and a sprite
+    const ROM: [u8; 22] = [
+        0x60, 0x00, // 200 LD V0, 0
+        0x22, 0x10, // 202 CALL 210
+        0x30, 0x01, // 204 SE V0, 1
+        0x12, 0x0A, // 206 JP 20A
+        0x12, 0x08, // 208 JP 208
+        0xA2, 0x14, // 20A LD I, 214
+        0x12, 0x0C, // 20C JP 20C
+        0x00, 0x00, // 20E
+        0x70, 0x01, // 210 ADD V0, 1
+        0x00, 0xEE, // 212 RET
+        0xF0, 0x90, // 214 sprite
+    ];
+
+    fn block(start: u16, end: u16, succs: &[(u16, EdgeKind)]) -> Block {
+        Block { start, end, succs: succs.to_vec() }
+    }
+
+    #[test]
+    fn blocks_are_split_at_branch_targets() {
+        let graph = FlowGraph::analyze(&ROM, Quirks::default());
+        let blocks: Vec<_> = graph.blocks.values().cloned().collect();
+        assert_eq!(
+            blocks,
+            [
+                block(0x200, 0x202, &[(0x210, EdgeKind::Call), (0x204, EdgeKind::Next)]),
+                block(0x204, 0x204, &[(0x206, EdgeKind::Next), (0x208, EdgeKind::Skip)]),
+                block(0x206, 0x206, &[(0x20A, EdgeKind::Jump)]),
+                block(0x208, 0x208, &[(0x208, EdgeKind::Jump)]),
+                block(0x20A, 0x20A, &[(0x20C, EdgeKind::Next)]),
+                block(0x20C, 0x20C, &[(0x20C, EdgeKind::Jump)]),
+                block(0x210, 0x212, &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn subroutines_do_not_include_their_callees() {
+        let graph = FlowGraph::analyze(&ROM, Quirks::default());
+        let subs: Vec<_> = graph.subroutines.into_iter().collect();
+        assert_eq!(subs, [(0x200, vec![0x200, 0x204, 0x206, 0x208, 0x20A, 0x20C]), (0x210, vec![0x210])]);
+    }
+
+    #[test]
+    fn rom_is_split_into_regions() {
+        let graph = FlowGraph::analyze(&ROM, Quirks::default());
+        let region = |start, end, kind| Region { start, end, kind };
+        assert_eq!(
+            graph.regions,
+            [
+                region(0x200, 0x20E, RegionKind::Code),
+                region(0x20E, 0x210, RegionKind::Unreachable),
+                region(0x210, 0x214, RegionKind::Code),
+                region(0x214, 0x216, RegionKind::Data),
+            ]
+        );
+        assert!(graph.bad_targets.is_empty());
+        assert_eq!(FlowGraph::analyze(&[0x13, 0x00], Quirks::default()).bad_targets, [(0x200, 0x300)]);
+    }
+
+    #[test]
+    fn dot_has_a_cluster_per_subroutine() {
+        let mut emu = crate::Emu::new();
+        emu.load(&ROM).unwrap();
+        let quirks = Quirks::default();
+        let dot = FlowGraph::analyze(&ROM, quirks).to_dot(emu.memory());
+        assert!(dot.starts_with("digraph rom {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("    subgraph cluster_200 {\n        label=\"main\";\n"));
+        assert!(dot.contains("    subgraph cluster_210 {\n        label=\"sub 210\";\n"));
+        assert!(dot.contains(&format!(
+            "        b210 [label=\"210: {}\\l212: {}\\l\"];\n",
+            disassemble_with_quirks(0x7001, &quirks),
+            disassemble_with_quirks(0x00EE, &quirks)
+        )));
+        assert!(dot.contains("    b200 -> b210 [label=call style=dashed];\n"));
+        assert!(dot.contains("    b200 -> b204;\n"));
+        assert!(dot.contains("    b204 -> b208 [label=skip];\n"));
+        assert!(dot.contains("    b206 -> b20A [label=jump];\n"));
+        assert_eq!(dot.matches(" -> ").count(), 8);
+    }
+}
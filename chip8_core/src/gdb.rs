@@ -0,0 +1,568 @@
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::debugger::{Debugger, Registers, StopReason, WatchKind, Watchpoint};
+use crate::Emu;
+
+/// Largest packet we accept, advertised to the client in qSupported
+const PACKET_SIZE: usize = 0x4000;
+/// Byte a client sends to interrupt a running target
+const INTERRUPT: u8 = 0x03;
+/// Time between two emulated frames while continuing, about 60Hz
+const FRAME_TIME: Duration = Duration::from_micros(16_667);
+/// Number of registers: V0-VF, I, PC, SP, DT and ST
+const NUM_REGISTERS: usize = 21;
+/// Addresses past the 4KB of RAM are rejected
+const ADDRESS_SPACE: usize = 0x1000;
+
+/// Size in bytes of every register, in register number order
+fn register_size(regnum: usize) -> usize {
+    match regnum {
+        16..=18 => 2,
+        _ => 1,
+    }
+}
+
+/// Target description telling the client the register layout
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <feature name=\"org.chip8.core\">\n",
+    ));
+    for x in 0..16 {
+        let _ = writeln!(xml, "    <reg name=\"v{:x}\" bitsize=\"8\" regnum=\"{}\" type=\"uint8\"/>", x, x);
+    }
+    xml.push_str(concat!(
+        "    <reg name=\"i\" bitsize=\"16\" regnum=\"16\" type=\"data_ptr\"/>\n",
+        "    <reg name=\"pc\" bitsize=\"16\" regnum=\"17\" type=\"code_ptr\"/>\n",
+        "    <reg name=\"sp\" bitsize=\"16\" regnum=\"18\" type=\"uint16\"/>\n",
+        "    <reg name=\"dt\" bitsize=\"8\" regnum=\"19\" type=\"uint8\"/>\n",
+        "    <reg name=\"st\" bitsize=\"8\" regnum=\"20\" type=\"uint8\"/>\n",
+        "  </feature>\n",
+        "</target>\n",
+    ));
+    xml
+}
+
+/// Big endian bytes of one register
+fn read_register(regs: &Registers, regnum: usize) -> Vec<u8> {
+    match regnum {
+        0..=15 => vec![regs.v[regnum]],
+        16 => regs.i.to_be_bytes().to_vec(),
+        17 => regs.pc.to_be_bytes().to_vec(),
+        18 => regs.sp.to_be_bytes().to_vec(),
+        19 => vec![regs.dt],
+        _ => vec![regs.st],
+    }
+}
+
+/// Sets one register from its big endian bytes, which must have the register's size
+fn write_register(regs: &mut Registers, regnum: usize, bytes: &[u8]) {
+    let word = || u16::from_be_bytes([bytes[0], bytes[1]]);
+    match regnum {
+        0..=15 => regs.v[regnum] = bytes[0],
+        16 => regs.i = word(),
+        17 => regs.pc = word(),
+        18 => regs.sp = word(),
+        19 => regs.dt = bytes[0],
+        _ => regs.st = bytes[0],
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// Splits "addr,len" as sent by m, M, Z and qXfer
+fn parse_addr_len(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+/// Parses "addr,len" for memory, None unless addr is in RAM and addr + len does not overflow
+fn parse_memory_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = parse_addr_len(text)?;
+    (addr < ADDRESS_SPACE && addr.checked_add(len).is_some()).then_some((addr, len))
+}
+
+/// Escapes the bytes the protocol reserves in binary replies
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if matches!(b, b'$' | b'#' | b'}' | b'*') {
+            out.extend([b'}', b ^ 0x20]);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// What the client asked for after a packet has been handled
+enum Action {
+    Reply(Vec<u8>),
+    Continue,
+    Step,
+    /// the reply has already been sent
+    Sent,
+    /// detach or kill, the connection is closed
+    Close,
+}
+
+fn reply(text: &str) -> Action {
+    Action::Reply(text.as_bytes().to_vec())
+}
+
+/*
+    Serves the GDB remote serial protocol over a TCP connection so existing
+    debugger front-ends can inspect and control the emulator. Registers are
+    numbered V0-VF (0-15), I (16), PC (17), SP (18), DT (19) and ST (20) and
+    sent big endian like the CHIP-8's own opcodes; the layout is described
+    to the client by qXfer:features:read:target.xml.
+
+    Supports software and hardware breakpoints (both are handled the same),
+    read, write and access watchpoints on memory used through I, continue,
+    single-step and Ctrl-C. An instruction the emulator cannot execute stops
+    the machine with SIGILL instead of running it, and one that would
+    overflow the stack or reach past RAM through I with SIGSEGV. Registers
+    that would put SP or I outside the machine are refused. While continuing
+    the machine runs at 60 frames a second so the timers behave like they do
+    in a game.
+*/
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    ticks_per_frame: usize,
+    /// set by QStartNoAckMode, packets are no longer acknowledged
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream, ticks_per_frame: usize) -> Self {
+        Self { stream, debugger: Debugger::new(ticks_per_frame), ticks_per_frame: ticks_per_frame.max(1), no_ack: false }
+    }
+
+    /// Answers packets until the client detaches, kills the target or disconnects
+    pub fn serve(&mut self, emu: &mut Emu) -> io::Result<()> {
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                return Ok(());
+            };
+            let action = self.handle(&packet, emu);
+            match action {
+                Action::Reply(data) => self.send(&data)?,
+                Action::Step => {
+                    let reason = self.debugger.step(emu);
+                    self.send(stop_reply(reason).as_bytes())?;
+                },
+                Action::Continue => match self.resume(emu)? {
+                    Some(reply) => self.send(reply.as_bytes())?,
+                    None => return Ok(()),
+                },
+                Action::Sent => (),
+                Action::Close => return Ok(()),
+            }
+        }
+    }
+
+    /// Runs until a breakpoint, watchpoint or interrupt and returns the stop reply
+    /// None if the client disconnected in the meantime
+    fn resume(&mut self, emu: &mut Emu) -> io::Result<Option<String>> {
+        self.stream.set_nonblocking(true)?;
+        let result = loop {
+            if let Some(reason) = self.debugger.run(emu, self.ticks_per_frame) {
+                break Ok(Some(stop_reply(reason)));
+            }
+            let mut byte = [0];
+            match self.stream.read(&mut byte) {
+                Ok(0) => break Ok(None),
+                Ok(_) if byte[0] == INTERRUPT => break Ok(Some("S02".to_string())),
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(FRAME_TIME),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next "$data#xx" packet, None when the connection is closed
+    /// Packets with a bad checksum are rejected with '-' so the client resends them
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledgements and interrupts sent while already stopped
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) if data.len() < PACKET_SIZE => data.push(b),
+                    Some(_) => (),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if self.no_ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            if expected == Some(actual) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    /// Sends a packet, resending it until the client acknowledges it
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let checksum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        loop {
+            self.stream.write_all(&packet)?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str, emu: &mut Emu) -> Action {
+        let (kind, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let error = || reply("E01");
+        match kind {
+            "?" => reply("S05"),
+            "g" => {
+                let regs = emu.registers();
+                let bytes: Vec<u8> = (0..NUM_REGISTERS).flat_map(|r| read_register(&regs, r)).collect();
+                reply(&to_hex(&bytes))
+            },
+            "G" => {
+                let Some(bytes) = from_hex(args) else { return error() };
+                let total: usize = (0..NUM_REGISTERS).map(register_size).sum();
+                if bytes.len() < total {
+                    return error();
+                }
+                let mut regs = emu.registers();
+                let mut offset = 0;
+                for r in 0..NUM_REGISTERS {
+                    write_register(&mut regs, r, &bytes[offset..offset + register_size(r)]);
+                    offset += register_size(r);
+                }
+                match emu.set_registers(&regs) {
+                    Ok(()) => reply("OK"),
+                    Err(_) => error(),
+                }
+            },
+            "p" => match parse_hex(args) {
+                Some(r) if r < NUM_REGISTERS => reply(&to_hex(&read_register(&emu.registers(), r))),
+                _ => error(),
+            },
+            "P" => {
+                let Some((r, value)) = args.split_once('=') else { return error() };
+                match (parse_hex(r), from_hex(value)) {
+                    (Some(r), Some(bytes)) if r < NUM_REGISTERS && bytes.len() == register_size(r) => {
+                        let mut regs = emu.registers();
+                        write_register(&mut regs, r, &bytes);
+                        match emu.set_registers(&regs) {
+                            Ok(()) => reply("OK"),
+                            Err(_) => error(),
+                        }
+                    },
+                    _ => error(),
+                }
+            },
+            "m" => {
+                let Some((addr, len)) = parse_memory_range(args) else { return error() };
+                let memory = emu.memory();
+                reply(&to_hex(&memory[addr..(addr + len).min(memory.len())]))
+            },
+            "M" => {
+                let Some((range, data)) = args.split_once(':') else { return error() };
+                match (parse_memory_range(range), from_hex(data)) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len && addr + len <= emu.memory().len() => {
+                        emu.write_memory(addr as u16, &bytes);
+                        reply("OK")
+                    },
+                    _ => error(),
+                }
+            },
+            "c" | "s" => {
+                if !args.is_empty() {
+                    let Some(addr) = parse_hex(args).filter(|a| *a < ADDRESS_SPACE) else { return error() };
+                    let mut regs = emu.registers();
+                    regs.pc = addr as u16;
+                    if emu.set_registers(&regs).is_err() {
+                        return error();
+                    }
+                }
+                if kind == "c" { Action::Continue } else { Action::Step }
+            },
+            "Z" | "z" => self.breakpoint(kind == "Z", args),
+            "D" => {
+                let _ = self.send(b"OK");
+                Action::Close
+            },
+            "k" => Action::Close,
+            "H" => reply("OK"),
+            "q" | "Q" => self.query(packet),
+            _ => reply(""),
+        }
+    }
+
+    /// Inserts or removes a breakpoint or watchpoint for Z/z "type,addr,kind"
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let Some((kind, rest)) = args.split_once(',') else { return reply("E01") };
+        let Some((addr, len)) = parse_memory_range(rest) else { return reply("E01") };
+        let last = addr + len.max(1) - 1;
+        if last >= ADDRESS_SPACE {
+            return reply("E01");
+        }
+        let watch = |kind| Watchpoint { addrs: addr as u16..=last as u16, kind };
+        match (kind, insert) {
+            ("0" | "1", true) => {
+                self.debugger.add_breakpoint(addr as u16);
+            },
+            ("0" | "1", false) => {
+                self.debugger.remove_breakpoint(addr as u16);
+            },
+            ("2" | "3" | "4", _) => {
+                let watchpoint = watch(match kind {
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                });
+                if insert {
+                    self.debugger.add_watchpoint(watchpoint);
+                } else {
+                    self.debugger.remove_watchpoint(&watchpoint);
+                }
+            },
+            _ => return reply(""),
+        }
+        reply("OK")
+    }
+
+    fn query(&mut self, packet: &str) -> Action {
+        if packet.starts_with("qSupported") {
+            return reply(&format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+", PACKET_SIZE));
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:") {
+            let Some((annex, range)) = args.split_once(':') else { return reply("E01") };
+            if annex != "target.xml" {
+                return reply("E00");
+            }
+            let Some((offset, len)) = parse_addr_len(range) else { return reply("E01") };
+            let Some(end) = offset.checked_add(len) else { return reply("E01") };
+            let xml = target_xml();
+            let chunk = &xml.as_bytes()[offset.min(xml.len())..end.min(xml.len())];
+            let mut data = vec![if end >= xml.len() { b'l' } else { b'm' }];
+            data.extend(escape(chunk));
+            return Action::Reply(data);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                // The OK itself is still acknowledged
+                let _ = self.send(b"OK");
+                self.no_ack = true;
+                Action::Sent
+            },
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+}
+
+/// Reply telling the client why the machine stopped
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Step => "S05".to_string(),
+        StopReason::Breakpoint(_) => "T05swbreak:;".to_string(),
+        // SIGILL, the instruction at PC was not run
+        StopReason::IllegalInstruction(_) => "S04".to_string(),
+        // SIGSEGV, the instruction at PC would break the stack or RAM and was not run
+        StopReason::Fault { .. } => "S0b".to_string(),
+        StopReason::Watchpoint { addr, kind } => {
+            let name = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T05{}:{:x};", name, addr)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::Fault;
+    use crate::STACK_SIZE;
+    use std::net::TcpListener;
+
+    /// A stub connected to a throwaway local client, and a machine running rom
+    fn stub(rom: &[u8]) -> (GdbStub, TcpStream, Emu) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut emu = Emu::new();
+        emu.load(rom).unwrap();
+        (GdbStub::new(stream, 10), client, emu)
+    }
+
+    fn answer(stub: &mut GdbStub, emu: &mut Emu, packet: &str) -> String {
+        match stub.handle(packet, emu) {
+            Action::Reply(data) => String::from_utf8(data).unwrap(),
+            _ => panic!("{} did not get a reply", packet),
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let (mut stub, _client, mut emu) = stub(&[0x12, 0x34]);
+        assert_eq!(answer(&mut stub, &mut emu, "m200,2"), "1234");
+        assert_eq!(answer(&mut stub, &mut emu, "M300,2:abcd"), "OK");
+        assert_eq!(&emu.memory()[0x300..0x302], &[0xAB, 0xCD]);
+        // Reads are cut at the end of RAM
+        assert_eq!(answer(&mut stub, &mut emu, "mfff,10"), "00");
+    }
+
+    #[test]
+    fn rejects_memory_outside_ram() {
+        let (mut stub, _client, mut emu) = stub(&[]);
+        for packet in ["m1000,1", "mffffffffffffffff,2", "mfff,ffffffffffffffff", "Mfff,2:0000", "Mffffffffffffffff,1:00", "x"] {
+            let expected = if packet == "x" { "" } else { "E01" };
+            assert_eq!(answer(&mut stub, &mut emu, packet), expected, "{}", packet);
+        }
+        assert_eq!(answer(&mut stub, &mut emu, "c1000"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let (mut stub, _client, mut emu) = stub(&[]);
+        assert_eq!(answer(&mut stub, &mut emu, "Z0,202,2"), "OK");
+        assert_eq!(stub.debugger.breakpoints().collect::<Vec<_>>(), [0x202]);
+        assert_eq!(answer(&mut stub, &mut emu, "Z2,300,3"), "OK");
+        assert_eq!(stub.debugger.watchpoints()[0].addrs, 0x300..=0x302);
+        assert_eq!(answer(&mut stub, &mut emu, "z2,300,3"), "OK");
+        assert!(stub.debugger.watchpoints().is_empty());
+
+        for packet in ["Z0,1000,2", "Z2,ffffffffffffffff,1", "Z2,fff,2", "Z3,0,ffffffffffffffff"] {
+            assert_eq!(answer(&mut stub, &mut emu, packet), "E01", "{}", packet);
+        }
+        assert!(stub.debugger.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn target_description_in_chunks() {
+        let (mut stub, _client, mut emu) = stub(&[]);
+        let first = answer(&mut stub, &mut emu, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, "m<?xml version=\"1");
+        let rest = answer(&mut stub, &mut emu, "qXfer:features:read:target.xml:10,ffff");
+        assert!(rest.starts_with('l') && rest.ends_with("</target>\n"));
+        assert_eq!(answer(&mut stub, &mut emu, "qXfer:features:read:target.xml:1,ffffffffffffffff"), "E01");
+    }
+
+    #[test]
+    fn illegal_instruction_is_reported_not_run() {
+        let (mut stub, _client, mut emu) = stub(&[0xFF, 0xFF]);
+        let reason = stub.debugger.step(&mut emu);
+        assert_eq!(reason, StopReason::IllegalInstruction(0x200));
+        assert_eq!(stop_reply(reason), "S04");
+        assert_eq!(emu.pc(), 0x200);
+    }
+
+    #[test]
+    fn registers_outside_the_machine_are_rejected() {
+        let (mut stub, _client, mut emu) = stub(&[0x00, 0xEE]);
+        assert_eq!(answer(&mut stub, &mut emu, "P10=ffff"), "E01");
+        assert_eq!(answer(&mut stub, &mut emu, "P12=0011"), "E01");
+        assert_eq!(answer(&mut stub, &mut emu, "P10=0ffe"), "OK");
+        assert_eq!(emu.registers().i, 0xFFE);
+    }
+
+    #[test]
+    fn return_with_an_empty_stack_is_a_fault() {
+        let (mut stub, _client, mut emu) = stub(&[0x22, 0x04, 0x00, 0x00, 0x00, 0xEE]);
+        assert_eq!(stub.debugger.step(&mut emu), StopReason::Step);
+        assert_eq!(answer(&mut stub, &mut emu, "P12=0000"), "OK");
+        let reason = stub.debugger.step(&mut emu);
+        assert_eq!(reason, StopReason::Fault { addr: 0x204, fault: Fault::StackUnderflow });
+        assert_eq!(stop_reply(reason), "S0b");
+        assert_eq!(emu.pc(), 0x204);
+    }
+
+    #[test]
+    fn deep_recursion_is_a_fault() {
+        let (mut stub, _client, mut emu) = stub(&[0x22, 0x00]);
+        for _ in 0..STACK_SIZE {
+            assert_eq!(stub.debugger.step(&mut emu), StopReason::Step);
+        }
+        assert_eq!(stub.debugger.step(&mut emu), StopReason::Fault { addr: 0x200, fault: Fault::StackOverflow });
+        assert_eq!(emu.registers().sp as usize, STACK_SIZE);
+    }
+
+    #[test]
+    fn access_past_the_end_of_ram_is_a_fault() {
+        // I = FFF, V0 = 5, I += V0, then load, store, BCD and draw through I
+        for op in [0xF065u16, 0xF055, 0xF033, 0xD001] {
+            let [hi, lo] = op.to_be_bytes();
+            let (mut stub, _client, mut emu) = stub(&[0xAF, 0xFF, 0x60, 0x05, 0xF0, 0x1E, hi, lo]);
+            for _ in 0..3 {
+                assert_eq!(stub.debugger.step(&mut emu), StopReason::Step);
+            }
+            let reason = stub.debugger.step(&mut emu);
+            assert_eq!(reason, StopReason::Fault { addr: 0x206, fault: Fault::OutOfRange }, "{:04X}", op);
+        }
+
+        let (mut stub, _client, mut emu) = stub(&[0xF2, 0x65]);
+        assert_eq!(answer(&mut stub, &mut emu, "P10=0ffe"), "OK");
+        assert_eq!(stub.debugger.step(&mut emu), StopReason::Fault { addr: 0x200, fault: Fault::OutOfRange });
+        assert_eq!(answer(&mut stub, &mut emu, "P10=0ffd"), "OK");
+        assert_eq!(stub.debugger.step(&mut emu), StopReason::Step);
+    }
+}
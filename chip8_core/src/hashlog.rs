@@ -0,0 +1,214 @@
+use std::fmt;
+
+use crate::Emu;
+
+/// One line of a hash log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashEntry {
+    /// state after a whole frame was run
+    Frame { frame: u64, display: u64, state: u64 },
+    /// state after a single instruction of a frame was run
+    Instr { frame: u64, index: usize, pc: u16, state: u64 },
+}
+
+/*
+    Per frame (and optionally per instruction) hashes of a run.
+    Two logs of the same ROM and inputs can be diffed to find the first frame
+    and instruction where two runs or two emulator versions diverge.
+
+    Text format, one entry per line:
+        f <frame> <display hash> <state hash>
+        i <frame> <instruction index> <pc> <state hash>
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HashLog {
+    pub entries: Vec<HashEntry>,
+}
+
+/// One of the two logs being compared
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// the log first_divergence was called on
+    This,
+    /// the log passed to it
+    Other,
+}
+
+/// Where two hash logs stop agreeing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// first frame whose hashes differ, or that only one log has
+    pub frame: u64,
+    /// true if the screens differ, not just the rest of the state
+    pub display_differs: bool,
+    /// first differing instruction in that frame (index, pc)
+    /// only known if both logs recorded instructions for the frame
+    pub instr: Option<(usize, u16)>,
+    /// set when the frame is missing from the other log, e.g. one run is shorter
+    pub only_in: Option<Side>,
+}
+
+impl HashLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the state of emu after frame was run
+    pub fn record_frame(&mut self, frame: u64, emu: &Emu) {
+        self.entries.push(HashEntry::Frame {
+            frame,
+            display: emu.display_hash(),
+            state: emu.state_hash(),
+        });
+    }
+
+    /// Records the state of emu after the instruction at pc was run
+    pub fn record_instr(&mut self, frame: u64, index: usize, pc: u16, emu: &Emu) {
+        self.entries.push(HashEntry::Instr { frame, index, pc, state: emu.state_hash() });
+    }
+
+    /// Parses a hash log from its text format
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut log = Self::new();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = || format!("line {}: malformed '{}'", num + 1, line);
+            let hex = |s: &str| u64::from_str_radix(s, 16).map_err(|_| err());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields.as_slice() {
+                ["f", frame, display, state] => HashEntry::Frame {
+                    frame: frame.parse().map_err(|_| err())?,
+                    display: hex(display)?,
+                    state: hex(state)?,
+                },
+                ["i", frame, index, pc, state] => HashEntry::Instr {
+                    frame: frame.parse().map_err(|_| err())?,
+                    index: index.parse().map_err(|_| err())?,
+                    pc: u16::from_str_radix(pc, 16).map_err(|_| err())?,
+                    state: hex(state)?,
+                },
+                _ => return Err(err()),
+            };
+            log.entries.push(entry);
+        }
+        Ok(log)
+    }
+
+    /// Frame entries (frame, display, state) in log order
+    fn frames(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.entries.iter().filter_map(|e| match *e {
+            HashEntry::Frame { frame, display, state } => Some((frame, display, state)),
+            _ => None,
+        })
+    }
+
+    /// Instruction entries (index, pc, state) of a single frame
+    fn instrs(&self, frame: u64) -> impl Iterator<Item = (usize, u16, u64)> + '_ {
+        self.entries.iter().filter_map(move |e| match *e {
+            HashEntry::Instr { frame: f, index, pc, state } if f == frame => Some((index, pc, state)),
+            _ => None,
+        })
+    }
+
+    /// Finds the first frame (and instruction if logged) where the two logs differ
+    /// A frame only one of the logs has counts as a difference, so a truncated
+    /// log does not pass as matching. Returns None if the logs are the same.
+    pub fn first_divergence(&self, other: &HashLog) -> Option<Divergence> {
+        let only_in = |frame, side| Some(Divergence { frame, display_differs: false, instr: None, only_in: Some(side) });
+        let (mut ours, mut theirs) = (self.frames(), other.frames());
+        let (a, b) = loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return None,
+                (Some(a), None) => return only_in(a.0, Side::This),
+                (None, Some(b)) => return only_in(b.0, Side::Other),
+                (Some(a), Some(b)) if a.0 < b.0 => return only_in(a.0, Side::This),
+                (Some(a), Some(b)) if a.0 > b.0 => return only_in(b.0, Side::Other),
+                (Some(a), Some(b)) if a != b => break (a, b),
+                _ => (),
+            }
+        };
+
+        let frame = a.0;
+        let instr = self
+            .instrs(frame)
+            .zip(other.instrs(frame))
+            .find(|(a, b)| a != b)
+            .map(|(a, _)| (a.0, a.1));
+
+        Some(Divergence { frame, display_differs: a.1 != b.1, instr, only_in: None })
+    }
+}
+
+impl fmt::Display for HashLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in self.entries.iter() {
+            match *entry {
+                HashEntry::Frame { frame, display, state } => {
+                    writeln!(f, "f {} {:016x} {:016x}", frame, display, state)?
+                },
+                HashEntry::Instr { frame, index, pc, state } => {
+                    writeln!(f, "i {} {} {:03x} {:016x}", frame, index, pc, state)?
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(frames: &[(u64, u64, u64)]) -> HashLog {
+        HashLog { entries: frames.iter().map(|&(frame, display, state)| HashEntry::Frame { frame, display, state }).collect() }
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut original = log(&[(1, 0xAB, 0xCD)]);
+        original.entries.push(HashEntry::Instr { frame: 2, index: 3, pc: 0x204, state: 0xEF });
+        assert_eq!(HashLog::parse(&original.to_string()).unwrap(), original);
+        assert!(HashLog::parse("f 1 xyz 0").is_err());
+    }
+
+    #[test]
+    fn identical_logs_match() {
+        let a = log(&[(1, 1, 1), (2, 2, 2)]);
+        assert_eq!(a.first_divergence(&a.clone()), None);
+    }
+
+    #[test]
+    fn finds_the_first_differing_frame_and_instruction() {
+        let mut a = log(&[(1, 1, 1), (2, 2, 2), (3, 3, 3)]);
+        let mut b = log(&[(1, 1, 1), (2, 2, 9), (3, 9, 9)]);
+        for (log, state) in [(&mut a, 5), (&mut b, 6)] {
+            log.entries.push(HashEntry::Instr { frame: 2, index: 0, pc: 0x200, state: 4 });
+            log.entries.push(HashEntry::Instr { frame: 2, index: 1, pc: 0x202, state });
+        }
+        let div = a.first_divergence(&b).unwrap();
+        assert_eq!(div, Divergence { frame: 2, display_differs: false, instr: Some((1, 0x202)), only_in: None });
+        assert!(log(&[(1, 1, 1)]).first_divergence(&log(&[(1, 2, 1)])).unwrap().display_differs);
+    }
+
+    #[test]
+    fn logs_of_different_lengths_diverge() {
+        let short = log(&[(1, 1, 1), (2, 2, 2)]);
+        let long = log(&[(1, 1, 1), (2, 2, 2), (3, 3, 3)]);
+        assert_eq!(short.first_divergence(&long).unwrap().only_in, Some(Side::Other));
+        assert_eq!(long.first_divergence(&short).unwrap(), Divergence {
+            frame: 3,
+            display_differs: false,
+            instr: None,
+            only_in: Some(Side::This),
+        });
+        assert_eq!(short.first_divergence(&HashLog::new()).unwrap().frame, 1);
+
+        // A frame missing from the middle of a log
+        let gap = log(&[(1, 1, 1), (3, 3, 3)]);
+        let div = long.first_divergence(&gap).unwrap();
+        assert_eq!((div.frame, div.only_in), (2, Some(Side::This)));
+    }
+}
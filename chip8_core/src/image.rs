@@ -0,0 +1,101 @@
+use std::io::Write;
+
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// The display drawn into RGB pixels, 3 bytes per pixel row by row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Draws the display with every CHIP-8 pixel as a scale x scale square,
+    /// colors are 0xRRGGBB
+    pub fn from_display(display: &[bool], scale: u32, fg: u32, bg: u32) -> Self {
+        let levels: Vec<u8> = display.iter().map(|lit| if *lit { 255 } else { 0 }).collect();
+        Self::from_levels(&levels, scale, fg, bg)
+    }
+
+    /// Same as from_display for pixel brightness levels such as Phosphor::levels,
+    /// 0 is the background and 255 the foreground
+    pub fn from_levels(levels: &[u8], scale: u32, fg: u32, bg: u32) -> Self {
+        let scale = scale.max(1) as usize;
+        let (width, height) = (SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale);
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let row = &levels[(y / scale) * SCREEN_WIDTH..][..SCREEN_WIDTH];
+            for x in 0..width {
+                pixels.extend_from_slice(&shade(row[x / scale], fg, bg));
+            }
+        }
+        Self { width: width as u32, height: height as u32, pixels }
+    }
+
+    /// RGB of the pixel at (x, y)
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let idx = ((y * self.width + x) * 3) as usize;
+        [self.pixels[idx], self.pixels[idx + 1], self.pixels[idx + 2]]
+    }
+
+    /// Encodes the image as a PNG file
+    pub fn write_png<W: Write>(&self, out: W) -> Result<(), String> {
+        let mut encoder = png::Encoder::new(out, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer.write_image_data(&self.pixels).map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())
+    }
+}
+
+/// Color level/255 of the way from bg to fg
+fn shade(level: u8, fg: u32, bg: u32) -> [u8; 3] {
+    let channel = |shift: u32| {
+        let (f, b) = ((fg >> shift) & 0xFF, (bg >> shift) & 0xFF);
+        ((f * level as u32 + b * (255 - level as u32)) / 255) as u8
+    };
+    [channel(16), channel(8), channel(0)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Levels (x * 4 + y * 8) % 256 at scale 2, 0xFF8000 on 0x102030, written by a separate encoder
+    const FIXTURE: &[u8] = include_bytes!("../fixtures/levels.png");
+
+    /// Size and RGB pixels of a PNG file
+    fn decode(data: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(data).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::Rgb, png::BitDepth::Eight));
+        pixels.truncate(info.buffer_size());
+        (info.width, info.height, pixels)
+    }
+
+    #[test]
+    fn png_matches_the_fixture() {
+        let levels: Vec<u8> = (0..SCREEN_HEIGHT)
+            .flat_map(|y| (0..SCREEN_WIDTH).map(move |x| ((x * 4 + y * 8) % 256) as u8))
+            .collect();
+        let image = Image::from_levels(&levels, 2, 0xFF8000, 0x102030);
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        assert_eq!(decode(&png), decode(FIXTURE));
+        assert_eq!((image.width, image.height), (128, 64));
+        assert_eq!(image.pixel(0, 0), [0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn lit_pixels_use_the_foreground() {
+        let mut display = vec![false; SCREEN_WIDTH * SCREEN_HEIGHT];
+        display[SCREEN_WIDTH + 1] = true;
+        let image = Image::from_display(&display, 3, 0xFF8000, 0x102030);
+        assert_eq!(image.pixel(3, 3), [0xFF, 0x80, 0x00]);
+        assert_eq!(image.pixel(5, 5), [0xFF, 0x80, 0x00]);
+        assert_eq!(image.pixel(6, 5), [0x10, 0x20, 0x30]);
+    }
+}
@@ -1,14 +1,47 @@
-use rand::Rng;
+pub mod audio;
+pub mod cartridge;
+pub mod cheats;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod flowgraph;
+pub mod gdb;
+mod hash;
+pub mod hashlog;
+pub mod image;
+pub mod movie;
+pub mod patch;
+pub mod phosphor;
+pub mod profiler;
+pub mod quirks;
+pub mod reload;
+pub mod romdb;
+pub mod symbols;
+pub mod trace;
+pub mod video;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub use hash::hash_bytes;
+use coverage::Coverage;
+use debugger::{MemAccess, Registers};
+use profiler::Profiler;
+use quirks::Quirks;
+use reload::Preserve;
+use trace::{TraceRecord, Tracer};
 
 pub const SCREEN_HEIGHT: usize = 32;
 pub const SCREEN_WIDTH: usize = 64;
+pub const NUM_KEYS: usize = 16;
 
 const RAM_SIZE: usize = 4096;   // RAM is 4KB for chip8
 const NUM_REGS: usize = 16;     // 16 8-bit registers V0-VF
-const STACK_SIZE: usize = 16;   // Stack Size in numbers
-const NUM_KEYS: usize = 16;
+pub const STACK_SIZE: usize = 16; // Stack Size in numbers
 
 const START_ADDR: u16 = 0x200;  // all programs are loaded into RAM starting at 0x200
+/// Largest ROM that fits in RAM after START_ADDR
+pub const MAX_ROM_SIZE: usize = RAM_SIZE - START_ADDR as usize;
 
 const FONTSET_SIZE: usize = 80;
 
@@ -58,12 +91,48 @@ pub struct Emu {
     dt: u8,
     /// sound timer: emits a sound if = 0
     st: u8,
+    /// seed of rng, kept so runs can be replayed deterministically
+    seed: u64,
+    /// random number generator used by CXNN
+    rng: StdRng,
+    /// number of frames (tick_timers calls) since the last reset
+    frame: u64,
+    /// whether the buzzer sounded during the last frame
+    buzzer: bool,
+    /// interpreter behaviours the loaded game expects
+    quirks: Quirks,
+    /// optional hook recording every executed instruction
+    tracer: Option<Tracer>,
+    /// optional hook counting executions per address and subroutine
+    profiler: Option<Profiler>,
+    /// optional hook tracking which addresses are code and which are data
+    coverage: Option<Coverage>,
+    /// data read or written through I by the last instruction, for watchpoints
+    last_access: Option<MemAccess>,
 }
 
-impl Emu {
-    /// Creates an Emulator 
+impl Default for Emu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fails if a ROM is too large to be loaded after START_ADDR
+fn check_rom_size(data: &[u8]) -> Result<(), String> {
+    if data.len() > MAX_ROM_SIZE {
+        return Err(format!("ROM is {} bytes, more than the {} that fit in RAM", data.len(), MAX_ROM_SIZE));
+    }
+    Ok(())
+}
 
+impl Emu {
+    /// Creates an Emulator with a random seed
     pub fn new() -> Self {
+        Self::with_seed(rand::random())
+    }
+
+    /// Creates an Emulator whose random numbers are generated from seed
+    pub fn with_seed(seed: u64) -> Self {
         let mut new_emu = Self {
             pc: START_ADDR,
             ram: [0; RAM_SIZE],
@@ -74,7 +143,16 @@ impl Emu {
             stack: [0; STACK_SIZE],
             keys: [false; NUM_KEYS],
             dt: 0,
-            st: 0
+            st: 0,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            frame: 0,
+            buzzer: false,
+            quirks: Quirks::default(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            last_access: None,
         };
         
         // copying the fontset to the first FONTSET_SIZE bytes in the RAM
@@ -95,6 +173,10 @@ impl Emu {
         self.keys = [false; NUM_KEYS];
         self.dt = 0;
         self.st = 0;
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.frame = 0;
+        self.buzzer = false;
+        self.last_access = None;
         self.ram[..FONTSET_SIZE].copy_from_slice(&FONTSET);
     }
 
@@ -116,9 +198,191 @@ impl Emu {
 
     pub fn tick(&mut self) {
         // Fetch
+        let pc = self.pc;
         let op = self.fetch();
+        self.last_access = None;
+        // Trace the state before the instruction changes it
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(&TraceRecord {
+                frame: self.frame,
+                pc,
+                op,
+                v_reg: self.v_reg,
+                i_reg: self.i_reg,
+                sp: self.sp as u8,
+                dt: self.dt,
+                st: self.st,
+            });
+        }
         // Decode & Executer
         self.execute(op);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, op, self.pc);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.mark_exec(pc);
+        }
+    }
+
+    /// Runs one 60Hz frame: ticks_per_frame instructions followed by the timers
+    pub fn run_frame(&mut self, ticks_per_frame: usize) {
+        for _ in 0..ticks_per_frame {
+            self.tick();
+        }
+        self.tick_timers();
+    }
+
+    /// Number of frames run since the last reset
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// True if the sound timer was running during the last frame
+    pub fn buzzer(&self) -> bool {
+        self.buzzer
+    }
+
+    /// Seed the random number generator was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Reseeds the random number generator
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Address of the next instruction
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Selects the interpreter behaviours to emulate, survives reset
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Hash of the screen buffer, equal hashes mean equal pictures
+    pub fn display_hash(&self) -> u64 {
+        let mut hasher = hash::Fnv1a::new();
+        for pixel in self.screen.iter() {
+            hasher.write(&[*pixel as u8]);
+        }
+        hasher.finish()
+    }
+
+    /// Hash of the whole machine state, equal hashes mean equal machines
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = hash::Fnv1a::new();
+        hasher.write_u16(self.pc);
+        hasher.write(&self.ram);
+        for pixel in self.screen.iter() {
+            hasher.write(&[*pixel as u8]);
+        }
+        hasher.write(&self.v_reg);
+        hasher.write_u16(self.i_reg);
+        hasher.write_u16(self.sp);
+        for val in self.stack.iter() {
+            hasher.write_u16(*val);
+        }
+        for key in self.keys.iter() {
+            hasher.write(&[*key as u8]);
+        }
+        hasher.write(&[self.dt, self.st]);
+        hasher.write_u64(self.frame);
+        hasher.finish()
+    }
+
+    /// Installs (or removes with None) the instruction trace hook
+    /// Returns the previous tracer so it can be finished
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Installs (or removes with None) the profiler hook
+    /// Returns the previous profiler so its report can be read
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Installs (or removes with None) the coverage hook
+    /// Returns the previous coverage so its report can be read
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        std::mem::replace(&mut self.coverage, coverage)
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Notes that len bytes at addr were read as data
+    fn note_read(&mut self, addr: u16, len: usize) {
+        self.last_access = Some(MemAccess { addr, len, write: false });
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.mark_read(addr, len);
+        }
+    }
+
+    /// Notes that len bytes at addr were written as data
+    fn note_write(&mut self, addr: u16, len: usize) {
+        self.last_access = Some(MemAccess { addr, len, write: true });
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.mark_write(addr, len);
+        }
+    }
+
+    /// Passes pointer to the whole RAM to the frontend
+    pub fn memory(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Copies data into RAM at addr, anything past the end of RAM is dropped
+    pub fn write_memory(&mut self, addr: u16, data: &[u8]) {
+        let start = (addr as usize).min(RAM_SIZE);
+        let len = data.len().min(RAM_SIZE - start);
+        self.ram[start..start + len].copy_from_slice(&data[..len]);
+    }
+
+    /// Data access made by the last instruction through I, if any
+    pub fn last_access(&self) -> Option<MemAccess> {
+        self.last_access
+    }
+
+    /// Return addresses on the stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers { v: self.v_reg, i: self.i_reg, pc: self.pc, sp: self.sp, dt: self.dt, st: self.st }
+    }
+
+    /// Overwrites the registers, sp is clamped to the stack size
+    /// Fails without changing anything if SP is past the end of the stack, or
+    /// I is moved outside of RAM (only the ROM itself can take it there)
+    pub fn set_registers(&mut self, regs: &Registers) -> Result<(), String> {
+        if regs.sp as usize > STACK_SIZE {
+            return Err(format!("SP {} is past the end of the {} entry stack", regs.sp, STACK_SIZE));
+        }
+        if regs.i != self.i_reg && regs.i as usize >= RAM_SIZE {
+            return Err(format!("I {:04X} is outside of RAM", regs.i));
+        }
+        self.v_reg = regs.v;
+        self.i_reg = regs.i;
+        self.pc = regs.pc;
+        self.sp = regs.sp;
+        self.dt = regs.dt;
+        self.st = regs.st;
+        Ok(())
     }
 
     /// Passes pointer to our screen buffer array to the frontend
@@ -131,25 +395,48 @@ impl Emu {
         self.keys[idx] = pressed;
     }
 
-    /// load a file into the RAM
-    pub fn load(&mut self, data: &[u8]) {
+    /// load a file into the RAM, fails if it does not fit
+    pub fn load(&mut self, data: &[u8]) -> Result<(), String> {
+        check_rom_size(data)?;
         let start = START_ADDR as usize;
         let end = (START_ADDR as usize) + data.len();
         self.ram[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Resets and loads a new build of the ROM, keeping what preserve asks for.
+    /// A ROM that does not fit leaves the machine untouched.
+    pub fn reload(&mut self, data: &[u8], preserve: &Preserve) -> Result<(), String> {
+        check_rom_size(data)?;
+        let (v_reg, i_reg) = (self.v_reg, self.i_reg);
+        let ram = self.ram;
+
+        self.reset();
+        self.load(data)?;
+
+        if preserve.registers {
+            self.v_reg = v_reg;
+            self.i_reg = i_reg;
+        }
+        for range in preserve.ram.iter() {
+            let range = *range.start() as usize..=*range.end() as usize;
+            self.ram[range.clone()].copy_from_slice(&ram[range]);
+        }
+        Ok(())
     }
 
     /// Implements tick timers, each frame dt and st decrement
-    /// if st == 1 before decrement, beeps
+    /// the buzzer sounds for every frame st is above 0
+    /// Also marks the end of a frame
     pub fn tick_timers(&mut self) {
         if self.dt > 0 {
             self.dt -= 1;
         }
+        self.buzzer = self.st > 0;
         if self.st > 0 {
-            if self.st == 1 {
-                // BEEP TODO (might not be implemented due to complexity)
-            }
             self.st -= 1;
         }
+        self.frame += 1;
     }
 
     /// Fetches the opcode for the current instruction
@@ -176,7 +463,7 @@ impl Emu {
         match (digit1, digit2, digit3, digit4) {
             // 0000
             // NOP : No operation
-            (0, 0, 0, 0) => return,
+            (0, 0, 0, 0) => (),
             // 00E0
             // CLS : clear screen
             (0, 0, 0xE, 0) => {
@@ -261,6 +548,9 @@ impl Emu {
                 let x = digit2 as usize;
                 let y = digit3 as usize;
                 self.v_reg[x] |= self.v_reg[y];
+                if self.quirks.vf_reset {
+                    self.v_reg[0xF] = 0;
+                }
             },
             // 8XY2
             // VX &= VY
@@ -268,6 +558,9 @@ impl Emu {
                 let x = digit2 as usize;
                 let y = digit3 as usize;
                 self.v_reg[x] &= self.v_reg[y];
+                if self.quirks.vf_reset {
+                    self.v_reg[0xF] = 0;
+                }
             },
             // 8XY3
             // VX ^= VY
@@ -275,6 +568,9 @@ impl Emu {
                 let x = digit2 as usize;
                 let y = digit3 as usize;
                 self.v_reg[x] ^= self.v_reg[y];
+                if self.quirks.vf_reset {
+                    self.v_reg[0xF] = 0;
+                }
             },
             // 8XY4
             // VX += VY
@@ -310,8 +606,12 @@ impl Emu {
             // VX >>= 1
             // We need to catch the dropped bit and store it into the VF register
             // the dropped bit is the least significant bit (lsb)
+            // With the shift quirk VY is shifted into VX instead
             (8, _, _, 6) => {
                 let x = digit2 as usize;
+                if self.quirks.shift_vy {
+                    self.v_reg[x] = self.v_reg[digit3 as usize];
+                }
                 let lsb = self.v_reg[x] & 1;
                 self.v_reg[x] >>= 1;
                 self.v_reg[0xF] = lsb;
@@ -332,8 +632,12 @@ impl Emu {
             // 8XYE
             // VX <<= 1
             // Overflowed value is stored in VF
+            // With the shift quirk VY is shifted into VX instead
             (8, _, _, 0xE) => {
                 let x = digit2 as usize;
+                if self.quirks.shift_vy {
+                    self.v_reg[x] = self.v_reg[digit3 as usize];
+                }
                 let msb = (self.v_reg[x] >> 7) & 1;
                 self.v_reg[0xF] = msb;
                 self.v_reg[x] <<= 1;
@@ -355,19 +659,21 @@ impl Emu {
             },
             // BNNN
             // JMP V0 + NNN : jumps to the value of V0 + nnn
+            // With the jump quirk it is BXNN and VX is used instead of V0
             (0xB, _, _, _) => {
                 let nnn = op & 0xFFF;
-                self.pc = (self.v_reg[0] as u16) + nnn;
+                let reg = if self.quirks.jump_vx { digit2 as usize } else { 0 };
+                self.pc = (self.v_reg[reg] as u16) + nnn;
             },
             // CXNN
             // VX = rand() & NN : gets a random number, AND it with nn
-            // We use the random() function to generate a random num
-            // We have to define u8 for rng so random() knows what
+            // We use the seeded rng so runs can be replayed
+            // We have to define u8 for rng so gen() knows what
             // type of number to generate
             (0xC, _, _, _) => {
                 let x = digit2 as usize;
                 let nn = (op & 0xFF) as u8;
-                let rng: u8 = rand::thread_rng().gen();
+                let rng: u8 = self.rng.gen();
                 self.v_reg[x] = rng & nn;
             },
             // DXYN
@@ -383,19 +689,30 @@ impl Emu {
                 
                 // Mutable flipped variable
                 let mut flipped = false;
+                self.note_read(self.i_reg, num_rows as usize);
 
                 // Iterate through each line in num_rows
                 for y_line in 0..num_rows {
                     // get the row of pixels
-                    let addr = self.i_reg + y_line as u16;
+                    let addr = self.i_reg + y_line;
                     let pixels = self.ram[addr as usize];
 
                     // iterate through each pixel
                     for x_line in 0..8 {
                         // if it is set find the coordinates for x and y on screen
                         if (pixels & (0b1000_0000 >> x_line)) != 0 {
-                            let x = (x_coord + x_line) as usize % SCREEN_WIDTH;
-                            let y = (y_coord + y_line) as usize % SCREEN_HEIGHT;
+                            // With the clip quirk only the start position wraps,
+                            // pixels past the edges are not drawn
+                            let (x, y) = if self.quirks.clip {
+                                let x = x_coord as usize % SCREEN_WIDTH + x_line as usize;
+                                let y = y_coord as usize % SCREEN_HEIGHT + y_line as usize;
+                                if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+                                    continue;
+                                }
+                                (x, y)
+                            } else {
+                                ((x_coord + x_line) as usize % SCREEN_WIDTH, (y_coord + y_line) as usize % SCREEN_HEIGHT)
+                            };
                             
                             // Find index of pixel in the screen as it is a 1-D array
                             let idx = x + SCREEN_WIDTH * y;
@@ -498,6 +815,7 @@ impl Emu {
                 let hundreds = (vx / 100.0).floor() as u8;
                 let tens = ((vx / 10.0) % 10.0).floor() as u8;
                 let ones = (vx % 10.0) as u8;
+                self.note_write(self.i_reg, 3);
 
                 self.ram[self.i_reg as usize] = hundreds;
                 self.ram[(self.i_reg + 1) as usize] = tens;
@@ -506,23 +824,124 @@ impl Emu {
             // FX55
             // STORE V0 - VX
             // Stores V0 thru VX in the RAM using the address in register I
+            // With the load/store quirk I is left pointing after VX
             (0xF, _, 5, 5) => {
                 let x = digit2 as usize;
                 let i = self.i_reg as usize;
+                self.note_write(self.i_reg, x + 1);
                 for idx in 0..=x{
                     self.ram[i + idx] = self.v_reg[idx];
                 }
+                if self.quirks.load_store_increment {
+                    self.i_reg = self.i_reg.wrapping_add(x as u16 + 1);
+                }
             },
             // FX65
             // LOAD V0 - VX
+            // With the load/store quirk I is left pointing after VX
             (0xF, _, 6, 5) => {
                 let x = digit2 as usize;
                 let i = self.i_reg as usize;
+                self.note_read(self.i_reg, x + 1);
                 for idx in 0..=x{
                     self.v_reg[idx] = self.ram[i + idx];
                 }
+                if self.quirks.load_store_increment {
+                    self.i_reg = self.i_reg.wrapping_add(x as u16 + 1);
+                }
             },
             (_, _, _, _) => unimplemented!("Unimplemented opcode: {}", op),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_rom_is_rejected() {
+        let mut emu = Emu::new();
+        assert!(emu.load(&[0; MAX_ROM_SIZE]).is_ok());
+        assert!(emu.load(&[0; MAX_ROM_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn oversized_reload_keeps_the_old_rom() {
+        let mut emu = Emu::new();
+        emu.load(&[0x12, 0x34]).unwrap();
+        assert!(emu.reload(&[0xFF; MAX_ROM_SIZE + 1], &Preserve::default()).is_err());
+        assert_eq!(&emu.memory()[0x200..0x202], &[0x12, 0x34]);
+    }
+
+    /// Runs the first ticks instructions of rom with only the given quirks on
+    fn run(rom: &[u8], ticks: usize, quirks: Quirks) -> Emu {
+        let mut emu = Emu::new();
+        emu.set_quirks(quirks);
+        emu.load(rom).unwrap();
+        for _ in 0..ticks {
+            emu.tick();
+        }
+        emu
+    }
+
+    /// Runs rom once with the quirk off and once with only it on
+    fn both(rom: &[u8], ticks: usize, quirk: &str) -> (Emu, Emu) {
+        let mut on = Quirks::default();
+        assert!(on.set(quirk, true));
+        (run(rom, ticks, Quirks::default()), run(rom, ticks, on))
+    }
+
+    #[test]
+    fn shift_quirk_shifts_vy() {
+        // V1 = 5, V2 = 6, V1 = V2 >> 1 or V1 >>= 1
+        let (off, on) = both(&[0x61, 0x05, 0x62, 0x06, 0x81, 0x26], 3, "shift");
+        assert_eq!((off.registers().v[1], off.registers().v[0xF]), (2, 1));
+        assert_eq!((on.registers().v[1], on.registers().v[0xF]), (3, 0));
+    }
+
+    #[test]
+    fn load_store_quirk_moves_i() {
+        // I = 300, V0 = 1, V1 = 2, store V0-V1
+        let (off, on) = both(&[0xA3, 0x00, 0x60, 0x01, 0x61, 0x02, 0xF1, 0x55], 4, "load_store");
+        assert_eq!(off.registers().i, 0x300);
+        assert_eq!(on.registers().i, 0x302);
+        assert_eq!(&on.memory()[0x300..0x302], &[1, 2]);
+    }
+
+    #[test]
+    fn load_store_quirk_at_the_end_of_ram() {
+        let mut emu = Emu::new();
+        emu.set_quirks(Quirks::parse("load_store").unwrap());
+        emu.load(&[0xF0, 0x65]).unwrap();
+        emu.i_reg = 0xFFF;
+        emu.tick();
+        assert_eq!(emu.registers().i, 0x1000);
+    }
+
+    #[test]
+    fn jump_quirk_adds_vx() {
+        // V0 = 4, V1 = 10, jump to 120 plus V0 or V1
+        let (off, on) = both(&[0x60, 0x04, 0x61, 0x10, 0xB1, 0x20], 3, "jump");
+        assert_eq!(off.pc(), 0x124);
+        assert_eq!(on.pc(), 0x130);
+    }
+
+    #[test]
+    fn vf_reset_quirk_clears_vf() {
+        // VF = 5, V0 = 1, V1 = 2, V0 |= V1
+        let (off, on) = both(&[0x6F, 0x05, 0x60, 0x01, 0x61, 0x02, 0x80, 0x11], 4, "vf_reset");
+        assert_eq!((off.registers().v[0], off.registers().v[0xF]), (3, 5));
+        assert_eq!((on.registers().v[0], on.registers().v[0xF]), (3, 0));
+    }
+
+    #[test]
+    fn clip_quirk_cuts_sprites_at_the_edge() {
+        // V0 = 62, V1 = 0, I = font 0, draw its top row F0 at (62, 0)
+        let (off, on) = both(&[0x60, 0x3E, 0x61, 0x00, 0xA0, 0x00, 0xD0, 0x11], 4, "clip");
+        assert!(off.get_display()[62] && off.get_display()[63]);
+        assert!(off.get_display()[0] && off.get_display()[1]);
+        assert!(on.get_display()[62] && on.get_display()[63]);
+        assert!(!on.get_display()[0] && !on.get_display()[1]);
+    }
+}
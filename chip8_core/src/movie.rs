@@ -0,0 +1,348 @@
+use std::fmt;
+
+use crate::cheats::Freezer;
+use crate::quirks::Quirks;
+use crate::{hash_bytes, Emu, NUM_KEYS};
+
+/// First line of every movie file, bumped if the format changes
+const MOVIE_HEADER: &str = "chip8-movie 1";
+
+/// A change of a single key at the start of a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    /// frame number (see Emu::frame) the change is applied before
+    pub frame: u64,
+    /// keypad index 0x0 - 0xF
+    pub key: usize,
+    pub pressed: bool,
+}
+
+/*
+    Recording of every keypad change of a session together with everything
+    needed to replay it: the rng seed, the quirks, the speed, the cheats
+    that were on and the ROM it was recorded on.
+
+    Text format, one item per line, everything but the freeze lines and the
+    events is required:
+        chip8-movie 1
+        seed <u64>
+        quirks <quirk list, see Quirks>
+        ticks <ticks per frame>
+        rom <hash of the rom in hex>
+        end <frame> <state hash in hex>
+        freeze <address in hex> <value in hex>    # one per frozen byte
+        ...
+        <frame> <key in hex> <1 if pressed, 0 if released>
+        ...
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub ticks_per_frame: usize,
+    pub rom_hash: u64,
+    /// frame the recording stopped at
+    pub end_frame: u64,
+    /// Emu::state_hash at end_frame
+    pub end_hash: u64,
+    /// cheats that were on, applied before every frame
+    pub freezer: Freezer,
+    pub events: Vec<InputEvent>,
+}
+
+impl Movie {
+    /// Starts an empty recording for a freshly loaded emulator, with the
+    /// cheats that will be applied before every frame
+    pub fn new(emu: &Emu, rom: &[u8], ticks_per_frame: usize, freezer: &Freezer) -> Self {
+        Self {
+            seed: emu.seed(),
+            quirks: emu.quirks(),
+            ticks_per_frame,
+            rom_hash: hash_bytes(rom),
+            end_frame: 0,
+            end_hash: 0,
+            freezer: freezer.clone(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Parses a movie from its text format
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty());
+
+        if !matches!(lines.next(), Some((_, MOVIE_HEADER))) {
+            return Err(format!("missing '{}' header", MOVIE_HEADER));
+        }
+
+        let (mut seed, mut quirks, mut ticks_per_frame, mut rom_hash, mut end) = (None, None, None, None, None);
+        let mut freezer = Freezer::new();
+        let mut events = Vec::new();
+        for (num, line) in lines {
+            let err = || format!("line {}: malformed '{}'", num + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["seed", value] => seed = Some(value.parse().map_err(|_| err())?),
+                ["quirks", value] => quirks = Some(Quirks::parse(value).map_err(|e| format!("line {}: {}", num + 1, e))?),
+                ["ticks", value] => ticks_per_frame = Some(value.parse().ok().filter(|t| *t > 0).ok_or_else(err)?),
+                ["rom", hash] => rom_hash = Some(u64::from_str_radix(hash, 16).map_err(|_| err())?),
+                ["end", frame, hash] => {
+                    end = Some((frame.parse().map_err(|_| err())?, u64::from_str_radix(hash, 16).map_err(|_| err())?));
+                },
+                ["freeze", addr, value] => {
+                    let addr = u16::from_str_radix(addr, 16).ok().filter(|a| *a < 0x1000).ok_or_else(err)?;
+                    freezer.freeze(addr, u8::from_str_radix(value, 16).map_err(|_| err())?);
+                },
+                [frame, key, pressed] => {
+                    let key = usize::from_str_radix(key, 16).map_err(|_| err())?;
+                    if key >= NUM_KEYS {
+                        return Err(err());
+                    }
+                    let pressed = match *pressed {
+                        "1" => true,
+                        "0" => false,
+                        _ => return Err(err()),
+                    };
+                    events.push(InputEvent {
+                        frame: frame.parse().map_err(|_| err())?,
+                        key,
+                        pressed,
+                    });
+                },
+                _ => return Err(err()),
+            }
+        }
+
+        let missing = |name: &str| format!("missing '{}' line", name);
+        let (end_frame, end_hash) = end.ok_or_else(|| missing("end"))?;
+        let movie = Self {
+            seed: seed.ok_or_else(|| missing("seed"))?,
+            quirks: quirks.ok_or_else(|| missing("quirks"))?,
+            ticks_per_frame: ticks_per_frame.ok_or_else(|| missing("ticks"))?,
+            rom_hash: rom_hash.ok_or_else(|| missing("rom"))?,
+            end_frame,
+            end_hash,
+            freezer,
+            events,
+        };
+
+        if movie.events.windows(2).any(|w| w[0].frame > w[1].frame) {
+            return Err("events are not sorted by frame".to_string());
+        }
+        Ok(movie)
+    }
+
+    /// Checks that rom is the one the movie was recorded on
+    pub fn matches_rom(&self, rom: &[u8]) -> bool {
+        self.rom_hash == hash_bytes(rom)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "quirks {}", self.quirks)?;
+        writeln!(f, "ticks {}", self.ticks_per_frame)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        writeln!(f, "end {} {:016x}", self.end_frame, self.end_hash)?;
+        for (addr, value) in self.freezer.frozen() {
+            writeln!(f, "freeze {:03x} {:02x}", addr, value)?;
+        }
+        for evt in self.events.iter() {
+            writeln!(f, "{} {:x} {}", evt.frame, evt.key, evt.pressed as u8)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records keypad changes into a Movie
+pub struct MovieRecorder {
+    movie: Movie,
+    /// last recorded state of each key so repeats are not recorded
+    keys: [bool; NUM_KEYS],
+}
+
+impl MovieRecorder {
+    pub fn new(emu: &Emu, rom: &[u8], ticks_per_frame: usize, freezer: &Freezer) -> Self {
+        Self {
+            movie: Movie::new(emu, rom, ticks_per_frame, freezer),
+            keys: [false; NUM_KEYS],
+        }
+    }
+
+    /// Records a keypress on the frame the emulator is currently at
+    /// Call alongside Emu::keypress
+    pub fn keypress(&mut self, emu: &Emu, idx: usize, pressed: bool) {
+        if self.keys[idx] == pressed {
+            return;
+        }
+        self.keys[idx] = pressed;
+        self.movie.events.push(InputEvent { frame: emu.frame(), key: idx, pressed });
+    }
+
+    /// Stops recording and stores the final state of the emulator
+    pub fn finish(mut self, emu: &Emu) -> Movie {
+        self.movie.end_frame = emu.frame();
+        self.movie.end_hash = emu.state_hash();
+        self.movie
+    }
+}
+
+/// Result of replaying a movie to its end
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// state hash equals the recorded one
+    Match,
+    /// state hash differs, replay is not deterministic
+    Mismatch { expected: u64, actual: u64 },
+}
+
+/// Feeds the inputs of a Movie back into an emulator
+pub struct MoviePlayer {
+    movie: Movie,
+    /// index of the next event to apply
+    next: usize,
+}
+
+impl MoviePlayer {
+    /// Sets up emu with the movie seed and quirks, emu should be freshly loaded
+    pub fn new(movie: Movie, emu: &mut Emu) -> Self {
+        emu.set_seed(movie.seed);
+        emu.set_quirks(movie.quirks);
+        Self { movie, next: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Applies every event due before the emulator's current frame
+    /// Call before running each frame
+    pub fn apply(&mut self, emu: &mut Emu) {
+        while let Some(evt) = self.movie.events.get(self.next) {
+            if evt.frame > emu.frame() {
+                break;
+            }
+            emu.keypress(evt.key, evt.pressed);
+            self.next += 1;
+        }
+    }
+
+    /// True once the emulator reached the frame the recording stopped at
+    pub fn is_finished(&self, emu: &Emu) -> bool {
+        emu.frame() >= self.movie.end_frame
+    }
+
+    /// Compares the emulator's state against the recorded final state
+    /// Keys changed after the last frame are applied first, as they were when recording stopped
+    pub fn verify(&mut self, emu: &mut Emu) -> Verdict {
+        self.apply(emu);
+        let actual = emu.state_hash();
+        if actual == self.movie.end_hash {
+            Verdict::Match
+        } else {
+            Verdict::Mismatch { expected: self.movie.end_hash, actual }
+        }
+    }
+
+    /// Runs the rest of the movie without a frontend and verifies the result
+    pub fn run_to_end(&mut self, emu: &mut Emu) -> Verdict {
+        while !self.is_finished(emu) {
+            self.apply(emu);
+            self.movie.freezer.apply(emu);
+            emu.run_frame(self.movie.ticks_per_frame);
+        }
+        self.verify(emu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = include_bytes!("../../games/BRIX");
+
+    /// Plays BRIX for a second holding key 4 for a while
+    fn record() -> Movie {
+        record_with(&Freezer::new())
+    }
+
+    fn record_with(freezer: &Freezer) -> Movie {
+        let mut emu = Emu::with_seed(7);
+        emu.set_quirks(Quirks::chip8());
+        emu.load(ROM).unwrap();
+        let mut recorder = MovieRecorder::new(&emu, ROM, 10, freezer);
+        for frame in 0..60 {
+            if frame == 5 || frame == 20 {
+                recorder.keypress(&emu, 4, frame == 5);
+                emu.keypress(4, frame == 5);
+            }
+            freezer.apply(&mut emu);
+            emu.run_frame(10);
+        }
+        recorder.finish(&emu)
+    }
+
+    fn replay(movie: Movie) -> Verdict {
+        let mut emu = Emu::new();
+        emu.load(ROM).unwrap();
+        MoviePlayer::new(movie, &mut emu).run_to_end(&mut emu)
+    }
+
+    #[test]
+    fn text_round_trip_replays() {
+        let movie = record();
+        let text = movie.to_string();
+        assert!(text.starts_with("chip8-movie 1\n"));
+        let parsed = Movie::parse(&text).unwrap();
+        assert_eq!(parsed, movie);
+        assert!(parsed.matches_rom(ROM));
+        assert_eq!(replay(parsed), Verdict::Match);
+    }
+
+    #[test]
+    fn different_final_state_does_not_match() {
+        let mut movie = record();
+        movie.end_hash ^= 1;
+        assert!(matches!(replay(movie), Verdict::Mismatch { .. }));
+    }
+
+    #[test]
+    fn required_lines() {
+        let text = record().to_string();
+        for name in ["seed", "quirks", "ticks", "rom", "end"] {
+            let without: String = text.lines().filter(|l| !l.starts_with(name)).map(|l| format!("{}\n", l)).collect();
+            assert_eq!(Movie::parse(&without).unwrap_err(), format!("missing '{}' line", name));
+        }
+        assert!(Movie::parse(&text.replace("ticks 10", "ticks 0")).is_err());
+        assert!(Movie::parse(&text.replace("chip8-movie 1", "chip8-movie 2")).is_err());
+    }
+
+    #[test]
+    fn cheats_are_recorded_and_replayed() {
+        let mut freezer = Freezer::new();
+        freezer.freeze(0xF00, 0x42);
+        let movie = record_with(&freezer);
+        let text = movie.to_string();
+        assert!(text.contains("\nfreeze f00 42\n"));
+        let parsed = Movie::parse(&text).unwrap();
+        assert_eq!(parsed.freezer, freezer);
+        assert_eq!(replay(parsed.clone()), Verdict::Match);
+
+        let without = Movie { freezer: Freezer::new(), ..parsed };
+        assert!(matches!(replay(without), Verdict::Mismatch { .. }));
+        assert!(Movie::parse(&text.replace("freeze f00 42", "freeze 1000 42")).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_events() {
+        let text = record().to_string();
+        assert!(Movie::parse(&format!("{}3 10 1\n", text)).is_err());
+        assert!(Movie::parse(&format!("{}3 1 2\n", text)).is_err());
+        assert_eq!(Movie::parse(&format!("{}3 1 1\n", text)).unwrap_err(), "events are not sorted by frame");
+    }
+}
@@ -0,0 +1,326 @@
+use std::path::{Path, PathBuf};
+
+use crate::MAX_ROM_SIZE;
+
+/// Extensions of the patch files looked for next to a ROM, in order
+pub const PATCH_EXTENSIONS: [&str; 2] = ["bps", "ips"];
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// Source, target and patch CRC32s at the end of a BPS patch
+const BPS_FOOTER_SIZE: usize = 12;
+
+/// Applies an IPS or BPS patch to a ROM, telling them apart by their header.
+/// Fails if the patched ROM would not fit in RAM.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let out = if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)?
+    } else if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)?
+    } else {
+        return Err("not an IPS or BPS patch".to_string());
+    };
+    if out.len() > MAX_ROM_SIZE {
+        return Err(too_large(out.len()));
+    }
+    Ok(out)
+}
+
+fn too_large(len: usize) -> String {
+    format!("patched ROM is {} bytes, more than the {} that fit in RAM", len, MAX_ROM_SIZE)
+}
+
+/// Patch file next to a ROM: game.ch8 is patched by game.bps or game.ips
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter().map(|ext| rom_path.with_extension(ext)).find(|path| path.is_file())
+}
+
+/*
+    IPS: "PATCH", then records until "EOF":
+        offset (3 bytes BE), size (2 bytes BE), size bytes of data
+    a record of size 0 is run-length encoded instead:
+        offset (3 bytes BE), 0 (2 bytes), count (2 bytes BE), value (1 byte)
+    An optional 3 byte length after "EOF" truncates the result.
+*/
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut pos = IPS_MAGIC.len();
+
+    loop {
+        if patch.get(pos..).is_some_and(|rest| rest.starts_with(IPS_EOF)) {
+            pos += IPS_EOF.len();
+            break;
+        }
+        let offset = read_ips(patch, &mut pos, 3)?;
+        let size = read_ips(patch, &mut pos, 2)?;
+        let data = if size > 0 {
+            read_bytes(patch, &mut pos, size)?.to_vec()
+        } else {
+            let count = read_ips(patch, &mut pos, 2)?;
+            vec![read_ips(patch, &mut pos, 1)? as u8; count]
+        };
+        // Offsets are at most 3 bytes and sizes 2, so this cannot overflow
+        let end = offset + data.len();
+        if out.len() < end {
+            out.resize(end, 0);
+        }
+        out[offset..end].copy_from_slice(&data);
+    }
+
+    if patch.len() - pos >= 3 {
+        out.truncate(read_ips(patch, &mut pos, 3)?);
+    }
+    Ok(out)
+}
+
+/// Reads len bytes of an IPS patch at pos
+fn read_bytes<'a>(patch: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    let end = pos.checked_add(len).ok_or("truncated IPS patch")?;
+    let bytes = patch.get(*pos..end).ok_or("truncated IPS patch")?;
+    *pos = end;
+    Ok(bytes)
+}
+
+/// Reads a big endian IPS number of len bytes at pos
+fn read_ips(patch: &[u8], pos: &mut usize, len: usize) -> Result<usize, String> {
+    Ok(read_bytes(patch, pos, len)?.iter().fold(0, |n, b| n << 8 | *b as usize))
+}
+
+/// Reads a BPS variable length number at pos
+fn read_number(patch: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let malformed = || "malformed BPS number".to_string();
+    let mut data = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = *patch.get(*pos).ok_or("truncated BPS patch")?;
+        *pos += 1;
+        let digit = ((byte & 0x7F) as usize).checked_mul(shift).ok_or_else(malformed)?;
+        data = data.checked_add(digit).ok_or_else(malformed)?;
+        if byte & 0x80 != 0 {
+            return Ok(data);
+        }
+        shift = shift.checked_mul(0x80).ok_or_else(malformed)?;
+        data = data.checked_add(shift).ok_or_else(malformed)?;
+    }
+}
+
+/// Moves a BPS copy offset by a signed delta, the sign is the lowest bit
+fn relative(offset: usize, delta: usize) -> Result<usize, String> {
+    let moved = if delta & 1 == 1 { offset.checked_sub(delta >> 1) } else { offset.checked_add(delta >> 1) };
+    moved.ok_or_else(|| "copy offset out of range in BPS patch".to_string())
+}
+
+fn read_crc(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/*
+    BPS: "BPS1", source size, target size, metadata size and metadata, then
+    actions until the footer. Each action is a number: the low 2 bits pick
+    the command, the rest is the length - 1.
+        0 SourceRead: copy from the source at the same offset
+        1 TargetRead: copy bytes from the patch
+        2 SourceCopy: copy from the source at a relative offset
+        3 TargetCopy: copy from the output so far at a relative offset
+    The footer has the CRC32 of the source, the target and the patch so far,
+    all three are checked.
+*/
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err("truncated BPS patch".to_string());
+    }
+    let footer = &patch[patch.len() - BPS_FOOTER_SIZE..];
+    if crc32fast::hash(&patch[..patch.len() - 4]) != read_crc(&footer[8..]) {
+        return Err("BPS patch is corrupted (patch checksum mismatch)".to_string());
+    }
+    if crc32fast::hash(rom) != read_crc(&footer[0..]) {
+        return Err("BPS patch is for a different ROM (source checksum mismatch)".to_string());
+    }
+
+    let mut pos = BPS_MAGIC.len();
+    let source_size = read_number(patch, &mut pos)?;
+    let target_size = read_number(patch, &mut pos)?;
+    let metadata_size = read_number(patch, &mut pos)?;
+    pos = pos.checked_add(metadata_size).ok_or("truncated BPS patch")?;
+    if source_size != rom.len() {
+        return Err("BPS patch is for a different ROM (size mismatch)".to_string());
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(too_large(target_size));
+    }
+
+    let actions_end = patch.len() - BPS_FOOTER_SIZE;
+    let out_of_range = || "BPS patch reads past the end of its data".to_string();
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while pos < actions_end {
+        let action = read_number(patch, &mut pos)?;
+        let len = (action >> 2) + 1;
+        match action & 3 {
+            0 => {
+                let start = out.len();
+                let end = start.checked_add(len).ok_or_else(out_of_range)?;
+                out.extend_from_slice(rom.get(start..end).ok_or_else(out_of_range)?);
+            },
+            1 => {
+                let end = pos.checked_add(len).filter(|end| *end <= actions_end).ok_or_else(out_of_range)?;
+                out.extend_from_slice(&patch[pos..end]);
+                pos = end;
+            },
+            2 => {
+                source_offset = relative(source_offset, read_number(patch, &mut pos)?)?;
+                let end = source_offset.checked_add(len).ok_or_else(out_of_range)?;
+                out.extend_from_slice(rom.get(source_offset..end).ok_or_else(out_of_range)?);
+                source_offset = end;
+            },
+            _ => {
+                target_offset = relative(target_offset, read_number(patch, &mut pos)?)?;
+                if out.len().saturating_add(len) > target_size {
+                    return Err("BPS patch writes past the target size".to_string());
+                }
+                // The copy may overlap what it writes, so it goes byte by byte
+                for _ in 0..len {
+                    let byte = *out.get(target_offset).ok_or_else(out_of_range)?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            },
+        }
+        if out.len() > target_size {
+            return Err("BPS patch writes past the target size".to_string());
+        }
+    }
+
+    if out.len() != target_size {
+        return Err("BPS patch did not produce the whole target".to_string());
+    }
+    if crc32fast::hash(&out) != read_crc(&footer[4..]) {
+        return Err("patched ROM does not match the BPS target checksum".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            n -= 1;
+        }
+    }
+
+    /// Wraps BPS actions in a header and a footer with valid checksums
+    fn bps(source: &[u8], target: &[u8], target_size: usize, actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target_size));
+        patch.extend(number(0));
+        patch.extend_from_slice(actions);
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend(crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ips_records_rle_and_truncation() {
+        let rom = [1, 2, 3, 4];
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0, 0, 1, 0, 2, 9, 9]);
+        patch.extend([0, 0, 4, 0, 0, 0, 3, 7]);
+        patch.extend(IPS_EOF);
+        assert_eq!(apply(&rom, &patch).unwrap(), [1, 9, 9, 4, 7, 7, 7]);
+
+        patch.extend([0, 0, 5]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [1, 9, 9, 4, 7]);
+    }
+
+    #[test]
+    fn ips_rejects_truncated_and_oversized() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0, 0, 1, 0, 4, 9]);
+        assert!(apply(&[0; 4], &patch).is_err());
+
+        let mut patch = IPS_MAGIC.to_vec();
+        let offset = MAX_ROM_SIZE.to_be_bytes();
+        patch.extend(&offset[offset.len() - 3..]);
+        patch.extend([0, 1, 9]);
+        patch.extend(IPS_EOF);
+        assert!(apply(&[0; 4], &patch).unwrap_err().contains("fit in RAM"));
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        assert!(apply(&[0; 4], b"junk").is_err());
+    }
+
+    #[test]
+    fn bps_every_action() {
+        let source: Vec<u8> = (0..40).collect();
+        let mut target = source.clone();
+        target[0x20] ^= 0xFF;
+        target.extend([0xAA; 10]);
+
+        let mut actions = number((0x20 - 1) << 2);
+        actions.extend(number(1));
+        actions.push(target[0x20]);
+        actions.extend(number(((source.len() - 0x21 - 1) << 2) | 2));
+        actions.extend(number(0x21 << 1));
+        actions.extend(number(1));
+        actions.push(0xAA);
+        actions.extend(number(((9 - 1) << 2) | 3));
+        actions.extend(number(source.len() << 1));
+
+        let patch = bps(&source, &target, target.len(), &actions);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_checks_checksums() {
+        let source = [1, 2, 3];
+        let target = [1, 2, 4];
+        let mut actions = number((2 - 1) << 2);
+        actions.extend(number(1));
+        actions.push(4);
+        let patch = bps(&source, &target, 3, &actions);
+
+        assert!(apply(&[1, 2, 5], &patch).unwrap_err().contains("different ROM"));
+        let mut corrupted = patch.clone();
+        corrupted[BPS_MAGIC.len() + 4] ^= 1;
+        assert!(apply(&source, &corrupted).unwrap_err().contains("corrupted"));
+        let wrong_target = bps(&source, &[0, 0, 0], 3, &actions);
+        assert!(apply(&source, &wrong_target).unwrap_err().contains("target checksum"));
+    }
+
+    #[test]
+    fn bps_rejects_malformed_numbers_and_lengths() {
+        let source = [0; 4];
+        let mut actions = vec![0; 12];
+        actions.push(0x80);
+        let patch = bps(&source, &source, 4, &actions);
+        assert_eq!(apply(&source, &patch).unwrap_err(), "malformed BPS number");
+
+        // A TargetRead longer than the patch, and a TargetCopy reaching past the output
+        let patch = bps(&source, &source, 4, &number(((usize::MAX >> 2) - 1) << 2 | 1));
+        assert!(apply(&source, &patch).is_err());
+        let mut actions = number(3 << 2);
+        actions.extend(number(0));
+        let patch = bps(&source, &source, 4, &actions);
+        assert!(apply(&source, &patch).is_err());
+
+        let patch = bps(&source, &source, MAX_ROM_SIZE + 1, &[]);
+        assert!(apply(&source, &patch).unwrap_err().contains("fit in RAM"));
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Share of a pixel's brightness kept every frame in decay mode, out of 256
+const DEFAULT_DECAY: u16 = 160;
+/// Number of frames combined in OR mode
+const DEFAULT_OR_FRAMES: usize = 2;
+/// Most frames OR mode can combine, a second's worth
+const MAX_OR_FRAMES: usize = 60;
+
+/// How consecutive frames are combined
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PhosphorMode {
+    /// only the current frame is shown
+    #[default]
+    Off,
+    /// pixels that turn off fade out over a few frames like on a CRT
+    Decay,
+    /// a pixel lit in any of the last frames is shown fully lit
+    Or,
+}
+
+impl PhosphorMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Self::Off),
+            "decay" => Some(Self::Decay),
+            "or" => Some(Self::Or),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Decay => "decay",
+            Self::Or => "or",
+        }
+    }
+
+    /// Mode after this one, wrapping around
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Decay,
+            Self::Decay => Self::Or,
+            Self::Or => Self::Off,
+        }
+    }
+}
+
+/*
+    Post-processes the display to hide the flicker of sprites being erased
+    and redrawn with XOR. It is fed the display once per emulated frame and
+    gives a brightness from 0 (off) to 255 (fully lit) for every pixel.
+*/
+#[derive(Clone, Debug)]
+pub struct Phosphor {
+    mode: PhosphorMode,
+    /// brightness of every pixel after the last frame
+    levels: Vec<u8>,
+    /// last frames, most recent at the back, only kept in OR mode
+    history: VecDeque<Vec<bool>>,
+    /// share of brightness kept every frame in decay mode, out of 256
+    decay: u16,
+    /// frames combined in OR mode
+    or_frames: usize,
+}
+
+impl Phosphor {
+    pub fn new(mode: PhosphorMode) -> Self {
+        Self {
+            mode,
+            levels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            history: VecDeque::new(),
+            decay: DEFAULT_DECAY,
+            or_frames: DEFAULT_OR_FRAMES,
+        }
+    }
+
+    /// Parses a mode with an optional setting: "off", "decay", "decay:F" where F
+    /// is the share of brightness a pixel keeps every frame (between 0 and 1),
+    /// "or", or "or:N" to combine the last N frames (1 to 60)
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (name, setting) = match text.split_once(':') {
+            Some((name, setting)) => (name, Some(setting)),
+            None => (text, None),
+        };
+        let mode = PhosphorMode::parse(name).ok_or_else(|| format!("unknown phosphor mode '{}'", name))?;
+        let mut phosphor = Self::new(mode);
+        match (mode, setting) {
+            (_, None) => (),
+            (PhosphorMode::Decay, Some(setting)) => {
+                let fraction = setting.parse().map_err(|_| format!("invalid decay '{}'", setting))?;
+                phosphor.set_decay(fraction)?;
+            },
+            (PhosphorMode::Or, Some(setting)) => {
+                let frames = setting.parse().map_err(|_| format!("invalid frame count '{}'", setting))?;
+                phosphor.set_or_frames(frames)?;
+            },
+            (PhosphorMode::Off, Some(_)) => return Err("phosphor mode 'off' has no setting".to_string()),
+        }
+        Ok(phosphor)
+    }
+
+    /// Sets the share of brightness a pixel keeps every frame in decay mode, between 0 and 1
+    pub fn set_decay(&mut self, fraction: f32) -> Result<(), String> {
+        if !(fraction > 0.0 && fraction < 1.0) {
+            return Err(format!("decay {} is not between 0 and 1", fraction));
+        }
+        self.decay = (fraction * 256.0) as u16;
+        Ok(())
+    }
+
+    /// Sets how many of the last frames OR mode combines, 1 to 60
+    pub fn set_or_frames(&mut self, frames: usize) -> Result<(), String> {
+        if !(1..=MAX_OR_FRAMES).contains(&frames) {
+            return Err(format!("{} frames is not between 1 and {}", frames, MAX_OR_FRAMES));
+        }
+        self.or_frames = frames;
+        self.history.clear();
+        Ok(())
+    }
+
+    pub fn mode(&self) -> PhosphorMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PhosphorMode) {
+        self.mode = mode;
+        self.history.clear();
+    }
+
+    /// Combines a new frame with the previous ones
+    pub fn push(&mut self, display: &[bool]) {
+        match self.mode {
+            PhosphorMode::Off => {
+                for (level, lit) in self.levels.iter_mut().zip(display) {
+                    *level = if *lit { 255 } else { 0 };
+                }
+            },
+            PhosphorMode::Decay => {
+                for (level, lit) in self.levels.iter_mut().zip(display) {
+                    *level = if *lit { 255 } else { ((*level as u16 * self.decay) >> 8) as u8 };
+                }
+            },
+            PhosphorMode::Or => {
+                self.history.push_back(display.to_vec());
+                while self.history.len() > self.or_frames {
+                    self.history.pop_front();
+                }
+                for (i, level) in self.levels.iter_mut().enumerate() {
+                    *level = if self.history.iter().any(|frame| frame[i]) { 255 } else { 0 };
+                }
+            },
+        }
+    }
+
+    /// Brightness of every pixel, in the same order as Emu::get_display
+    pub fn levels(&self) -> &[u8] {
+        &self.levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A display with only the first pixel lit, or nothing lit
+    fn display(lit: bool) -> Vec<bool> {
+        let mut display = vec![false; SCREEN_WIDTH * SCREEN_HEIGHT];
+        display[0] = lit;
+        display
+    }
+
+    /// Brightness of the first pixel after each frame
+    fn first_pixel(phosphor: &mut Phosphor, frames: &[bool]) -> Vec<u8> {
+        frames
+            .iter()
+            .map(|lit| {
+                phosphor.push(&display(*lit));
+                phosphor.levels()[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn off_shows_the_current_frame() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Off);
+        assert_eq!(first_pixel(&mut phosphor, &[true, false, true]), [255, 0, 255]);
+        assert!(phosphor.levels()[1..].iter().all(|l| *l == 0));
+    }
+
+    #[test]
+    fn decay_fades_out() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Decay);
+        assert_eq!(first_pixel(&mut phosphor, &[true, false, false, true]), [255, 159, 99, 255]);
+        let mut half = Phosphor::parse("decay:0.5").unwrap();
+        assert_eq!(first_pixel(&mut half, &[true, false, false, false]), [255, 127, 63, 31]);
+    }
+
+    #[test]
+    fn or_keeps_the_last_frames() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Or);
+        assert_eq!(first_pixel(&mut phosphor, &[true, false, false]), [255, 255, 0]);
+        let mut three = Phosphor::parse("or:3").unwrap();
+        assert_eq!(first_pixel(&mut three, &[true, false, false, false, true]), [255, 255, 255, 0, 255]);
+        let mut one = Phosphor::parse("or:1").unwrap();
+        assert_eq!(first_pixel(&mut one, &[true, false]), [255, 0]);
+    }
+
+    #[test]
+    fn changing_mode_forgets_old_frames() {
+        let mut phosphor = Phosphor::parse("or:3").unwrap();
+        first_pixel(&mut phosphor, &[true]);
+        phosphor.set_mode(PhosphorMode::Decay);
+        phosphor.set_mode(PhosphorMode::Or);
+        assert_eq!(first_pixel(&mut phosphor, &[false]), [0]);
+    }
+
+    #[test]
+    fn parse_checks_settings() {
+        assert_eq!(Phosphor::parse("off").unwrap().mode(), PhosphorMode::Off);
+        assert_eq!(Phosphor::parse("decay").unwrap().decay, DEFAULT_DECAY);
+        assert_eq!(Phosphor::parse("or:4").unwrap().or_frames, 4);
+        for text in ["glow", "or:0", "or:61", "or:x", "decay:1", "decay:0", "decay:-0.5", "decay:NaN", "off:2"] {
+            assert!(Phosphor::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::disasm::disassemble;
+use crate::RAM_SIZE;
+
+/// Longest loop body (in bytes) still counted as a tight loop
+const MAX_LOOP_BYTES: u16 = 16;
+/// Number of entries printed per report section
+const REPORT_ROWS: usize = 20;
+
+/// Counters for a single subroutine, times are in executed instructions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubStats {
+    pub calls: u64,
+    /// instructions executed inside the subroutine and everything it called
+    pub inclusive: u64,
+    /// instructions executed inside the subroutine itself
+    pub exclusive: u64,
+}
+
+/// What a tight loop is waiting for, found by looking at its body
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopKind {
+    /// reads DT (FX07), usually waiting for the delay timer to run out
+    DelayTimer,
+    /// checks the keypad (EX9E, EXA1, FX0A)
+    Key,
+    Other,
+}
+
+/// An entry of a subroutine that was called but has not returned yet
+struct Frame {
+    addr: u16,
+    /// instruction count when it was called
+    entered: u64,
+}
+
+/*
+    Counts executions per address and per subroutine, and finds tight loops.
+    Installed into an Emu with Emu::set_profiler, the Emu calls record after
+    every executed instruction.
+*/
+pub struct Profiler {
+    /// number of times each address was executed
+    hits: Vec<u64>,
+    /// total number of executed instructions
+    instructions: u64,
+    subs: HashMap<u16, SubStats>,
+    /// subroutines currently being executed, mirrors the emulator's stack
+    calls: Vec<Frame>,
+    /// (loop start, loop end) -> times the backward branch was taken
+    loops: HashMap<(u16, u16), u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; RAM_SIZE],
+            instructions: 0,
+            subs: HashMap::new(),
+            calls: Vec::new(),
+            loops: HashMap::new(),
+        }
+    }
+
+    /// Records the instruction op at pc, new_pc is where execution continues
+    pub(crate) fn record(&mut self, pc: u16, op: u16, new_pc: u16) {
+        self.hits[pc as usize % RAM_SIZE] += 1;
+        self.instructions += 1;
+        if let Some(top) = self.calls.last() {
+            self.subs.entry(top.addr).or_default().exclusive += 1;
+        }
+
+        match op & 0xF000 {
+            // CALL
+            0x2000 => {
+                self.subs.entry(new_pc).or_default().calls += 1;
+                self.calls.push(Frame { addr: new_pc, entered: self.instructions });
+            },
+            // RET
+            0x0000 if op == 0x00EE => {
+                if let Some(frame) = self.calls.pop() {
+                    let inclusive = self.instructions - frame.entered;
+                    self.subs.entry(frame.addr).or_default().inclusive += inclusive;
+                }
+            },
+            // Any other instruction looping back a short distance
+            _ => {
+                if new_pc <= pc && pc - new_pc <= MAX_LOOP_BYTES {
+                    *self.loops.entry((new_pc, pc)).or_default() += 1;
+                }
+            },
+        }
+    }
+
+    /// Number of times the instruction at addr was executed
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.hits[addr as usize % RAM_SIZE]
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Subroutines sorted by inclusive time, hottest first
+    pub fn subroutines(&self) -> Vec<(u16, SubStats)> {
+        let mut subs: Vec<_> = self.subs.iter().map(|(a, s)| (*a, *s)).collect();
+        subs.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        subs
+    }
+
+    /// Tight loops (start, end, iterations, kind) sorted by iterations
+    /// memory is used to look at the loop bodies, see Emu::memory
+    pub fn loops(&self, memory: &[u8]) -> Vec<(u16, u16, u64, LoopKind)> {
+        let mut loops: Vec<_> = self
+            .loops
+            .iter()
+            .map(|(&(start, end), &count)| (start, end, count, loop_kind(memory, start, end)))
+            .collect();
+        loops.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        loops
+    }
+
+    /// Text report of the hottest addresses, subroutines and loops
+    pub fn report(&self, memory: &[u8]) -> String {
+        let mut out = String::new();
+        let percent = |n: u64| 100.0 * n as f64 / self.instructions.max(1) as f64;
+        let op_at = |addr: u16| {
+            let addr = addr as usize;
+            match (memory.get(addr), memory.get(addr + 1)) {
+                (Some(hi), Some(lo)) => ((*hi as u16) << 8) | *lo as u16,
+                _ => 0,
+            }
+        };
+
+        writeln!(out, "{} instructions executed", self.instructions).unwrap();
+
+        writeln!(out, "\nHottest addresses:").unwrap();
+        let mut addrs: Vec<_> = (0..RAM_SIZE as u16).filter(|a| self.hits(*a) > 0).collect();
+        addrs.sort_by(|a, b| self.hits(*b).cmp(&self.hits(*a)).then(a.cmp(b)));
+        for addr in addrs.into_iter().take(REPORT_ROWS) {
+            let hits = self.hits(addr);
+            let op = op_at(addr);
+            writeln!(out, "  {:03X} {:>10} {:>6.2}%  {:04X} {}", addr, hits, percent(hits), op, disassemble(op))
+                .unwrap();
+        }
+
+        writeln!(out, "\nSubroutines (calls, inclusive, exclusive):").unwrap();
+        for (addr, sub) in self.subroutines().into_iter().take(REPORT_ROWS) {
+            writeln!(
+                out,
+                "  {:03X} {:>8} calls {:>10} ({:>6.2}%) {:>10} ({:>6.2}%)",
+                addr,
+                sub.calls,
+                sub.inclusive,
+                percent(sub.inclusive),
+                sub.exclusive,
+                percent(sub.exclusive)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nTight loops:").unwrap();
+        for (start, end, count, kind) in self.loops(memory).into_iter().take(REPORT_ROWS) {
+            let kind = match kind {
+                LoopKind::DelayTimer => "polls DT",
+                LoopKind::Key => "waits for a key",
+                LoopKind::Other => "",
+            };
+            writeln!(out, "  {:03X}-{:03X} {:>10} iterations  {}", start, end, count, kind).unwrap();
+        }
+        out
+    }
+}
+
+/// Looks at the instructions between start and end to guess what a loop waits for
+fn loop_kind(memory: &[u8], start: u16, end: u16) -> LoopKind {
+    let mut kind = LoopKind::Other;
+    for addr in (start..=end).step_by(2) {
+        let addr = addr as usize;
+        let (Some(hi), Some(lo)) = (memory.get(addr), memory.get(addr + 1)) else {
+            break;
+        };
+        match (hi & 0xF0, *lo) {
+            (0xF0, 0x07) => return LoopKind::DelayTimer,
+            (0xE0, 0x9E) | (0xE0, 0xA1) | (0xF0, 0x0A) => kind = LoopKind::Key,
+            _ => (),
+        }
+    }
+    kind
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emu;
+
+    /// Calls a subroutine once and then spins on a jump to itself
+    const ROM: [u8; 12] = [
+        0x60, 0x05, // 200 LD V0, 5
+        0x22, 0x08, // 202 CALL 208
+        0x12, 0x04, // 204 JP 204
+        0x00, 0x00, // 206
+        0x70, 0x01, // 208 ADD V0, 1
+        0x00, 0xEE, // 20A RET
+    ];
+
+    fn profile(ticks: usize) -> Emu {
+        let mut emu = Emu::new();
+        emu.load(&ROM).unwrap();
+        emu.set_profiler(Some(Profiler::new()));
+        for _ in 0..ticks {
+            emu.tick();
+        }
+        emu
+    }
+
+    #[test]
+    fn counts_every_address() {
+        let emu = profile(10);
+        let profiler = emu.profiler().unwrap();
+        assert_eq!(profiler.instructions(), 10);
+        for (addr, hits) in [(0x200, 1), (0x202, 1), (0x204, 6), (0x206, 0), (0x208, 1), (0x20A, 1)] {
+            assert_eq!(profiler.hits(addr), hits, "{:03X}", addr);
+        }
+    }
+
+    #[test]
+    fn times_subroutines_and_loops() {
+        let emu = profile(10);
+        let profiler = emu.profiler().unwrap();
+        assert_eq!(profiler.subroutines(), [(0x208, SubStats { calls: 1, inclusive: 2, exclusive: 2 })]);
+        assert_eq!(profiler.loops(emu.memory()), [(0x204, 0x204, 6, LoopKind::Other)]);
+    }
+
+    #[test]
+    fn loop_bodies_are_classified() {
+        let mut memory = vec![0; 8];
+        memory[..6].copy_from_slice(&[0xF1, 0x07, 0x31, 0x00, 0x12, 0x00]);
+        assert_eq!(loop_kind(&memory, 0, 4), LoopKind::DelayTimer);
+        memory[..2].copy_from_slice(&[0xE1, 0xA1]);
+        assert_eq!(loop_kind(&memory, 0, 4), LoopKind::Key);
+        assert_eq!(loop_kind(&memory, 2, 4), LoopKind::Other);
+    }
+
+    #[test]
+    fn report_lists_the_hot_spots() {
+        let emu = profile(10);
+        let report = emu.profiler().unwrap().report(emu.memory());
+        assert!(report.starts_with("10 instructions executed\n"));
+        assert!(report.contains(&format!("  204          6  60.00%  1204 {}\n", disassemble(0x1204))));
+        assert!(report.contains("  208        1 calls          2 ( 20.00%)          2 ( 20.00%)\n"));
+        assert!(report.contains("  204-204          6 iterations  \n"));
+    }
+}
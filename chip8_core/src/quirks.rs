@@ -0,0 +1,131 @@
+use std::fmt;
+
+/// Names of the quirks as used in movies and ROM database files
+const QUIRK_NAMES: [&str; 5] = ["shift", "load_store", "jump", "vf_reset", "clip"];
+
+/*
+    Behaviours that differ between CHIP-8 interpreters.
+    Games are written against one of them, so running them on the wrong one
+    breaks them in subtle ways. All off is how this emulator always behaved.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    /// FX55 and FX65 leave I pointing after the last register stored or loaded
+    pub load_store_increment: bool,
+    /// BXNN jumps to VX + XNN instead of V0 + NNN
+    pub jump_vx: bool,
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// sprites are cut at the screen edges instead of wrapping around
+    pub clip: bool,
+}
+
+impl Quirks {
+    /// Original COSMAC VIP interpreter
+    pub fn chip8() -> Self {
+        Self { shift_vy: true, load_store_increment: true, jump_vx: false, vf_reset: true, clip: true }
+    }
+
+    /// SUPER-CHIP 1.1 on the HP48
+    pub fn schip() -> Self {
+        Self { shift_vy: false, load_store_increment: false, jump_vx: true, vf_reset: false, clip: true }
+    }
+
+    /// Looks up a named profile: default, chip8 or schip
+    pub fn profile(name: &str) -> Option<Self> {
+        match name {
+            "default" | "none" => Some(Self::default()),
+            "chip8" => Some(Self::chip8()),
+            "schip" => Some(Self::schip()),
+            _ => None,
+        }
+    }
+
+    fn flags_mut(&mut self) -> [&mut bool; 5] {
+        [
+            &mut self.shift_vy,
+            &mut self.load_store_increment,
+            &mut self.jump_vx,
+            &mut self.vf_reset,
+            &mut self.clip,
+        ]
+    }
+
+    fn flags(&self) -> [bool; 5] {
+        [self.shift_vy, self.load_store_increment, self.jump_vx, self.vf_reset, self.clip]
+    }
+
+    /// Turns on a single quirk by name, returns false if the name is unknown
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        match QUIRK_NAMES.iter().position(|n| *n == name) {
+            Some(idx) => {
+                *self.flags_mut()[idx] = on;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Parses a profile name or a comma separated list of quirk names
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if let Some(quirks) = Self::profile(text) {
+            return Ok(quirks);
+        }
+        let mut quirks = Self::default();
+        for name in text.split(',').map(str::trim) {
+            if !quirks.set(name, true) {
+                return Err(format!("unknown quirk '{}'", name));
+            }
+        }
+        Ok(quirks)
+    }
+}
+
+/// Comma separated list of the quirks that are on, "none" if there are none
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = QUIRK_NAMES
+            .iter()
+            .zip(self.flags())
+            .filter(|(_, on)| *on)
+            .map(|(name, _)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_round_trips_through_parse() {
+        for bits in 0..32u8 {
+            let mut quirks = Quirks::default();
+            for (idx, name) in QUIRK_NAMES.iter().enumerate() {
+                quirks.set(name, bits & (1 << idx) != 0);
+            }
+            assert_eq!(Quirks::parse(&quirks.to_string()), Ok(quirks), "{}", quirks);
+        }
+    }
+
+    #[test]
+    fn profiles_and_lists_parse() {
+        assert_eq!(Quirks::parse("chip8"), Ok(Quirks::chip8()));
+        assert_eq!(Quirks::parse(" schip "), Ok(Quirks::schip()));
+        assert_eq!(Quirks::parse("none"), Ok(Quirks::default()));
+        assert_eq!(Quirks::default().to_string(), "none");
+        let quirks = Quirks::parse("clip, jump").unwrap();
+        assert!(quirks.clip && quirks.jump_vx && !quirks.shift_vy);
+        assert_eq!(quirks.to_string(), "jump,clip");
+        assert_eq!(Quirks::parse("clip,wobble"), Err("unknown quirk 'wobble'".to_string()));
+        assert!(Quirks::parse("").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::ops::RangeInclusive;
+
+/*
+    What is carried over when a ROM is reloaded during development, so a new
+    build can pick up where the old one was, e.g. with a level or the score
+    kept in a RAM region. Everything else starts over like after a reset.
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Preserve {
+    /// keep V0-VF and I
+    pub registers: bool,
+    /// RAM regions to keep, applied after the new ROM is loaded
+    pub ram: Vec<RangeInclusive<u16>>,
+}
+
+impl Preserve {
+    /// Parses a hex address range "300-3FF" or a single address and adds it to the kept RAM
+    pub fn add_ram(&mut self, text: &str) -> Result<(), String> {
+        let hex = |s: &str| {
+            u16::from_str_radix(s.trim().trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|addr| *addr < 0x1000)
+                .ok_or_else(|| format!("invalid address '{}'", s))
+        };
+        let range = match text.split_once('-') {
+            Some((first, last)) => hex(first)?..=hex(last)?,
+            None => {
+                let addr = hex(text)?;
+                addr..=addr
+            },
+        };
+        if range.is_empty() {
+            return Err(format!("empty range '{}'", text));
+        }
+        self.ram.push(range);
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use sha1::{Digest, Sha1};
+use toml::{Table, Value};
+
+use crate::quirks::Quirks;
+
+/// What is known about a ROM and how it should be run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RomInfo {
+    pub title: String,
+    pub author: Option<String>,
+    /// machine the game was written for, e.g. COSMAC VIP or CHIP-48
+    pub platform: Option<String>,
+    pub quirks: Quirks,
+    /// instructions per frame, None for the frontend's default
+    pub ticks_per_frame: Option<usize>,
+    /// extra host keys (by frontend key name) and the keypad index they press
+    pub keys: Vec<(String, usize)>,
+    /// (foreground, background) as 0xRRGGBB
+    pub colors: Option<(u32, u32)>,
+}
+
+/// sha1, title, author, platform, quirks, ticks per frame
+type BuiltinEntry = (&'static str, &'static str, Option<&'static str>, &'static str, &'static str, Option<usize>);
+
+/// ROMs shipped in games/
+const BUILTIN: &[BuiltinEntry] = &[
+    ("ea9af3c09b0d9e265fcd92bcc5d51a2939fdf27a", "15 Puzzle", Some("Roger Ivie"), "CHIP-48", "default", None),
+    ("d40abc54374e4343639f993e897e00904ddf85d9", "Blinky", Some("Hans Christian Egeberg"), "CHIP-48", "default", Some(15)),
+    ("6f6509f38220e057a7e32ebb22dd353c1078e3e7", "Blitz", Some("David Winter"), "CHIP-48", "clip", None),
+    ("f13766c14aeb02ad8d4d103cb5eadd282d20cddc", "Brix", Some("Andreas Gustafsson"), "CHIP-48", "default", None),
+    ("2d10c07b532f4fa7c07a07324ba26ca39fe484fd", "Connect 4", Some("David Winter"), "CHIP-48", "default", None),
+    ("5260f8931e0e9f41e555b382a14a88368e3ed886", "Guess", Some("David Winter"), "CHIP-48", "default", None),
+    ("050f07a54371da79f924dd0227b89d07b4f2aed0", "Hidden", Some("David Winter"), "CHIP-48", "default", None),
+    ("f100197f0f2f05b4f3c8c31ab9c2c3930d3e9571", "Space Invaders", Some("David Winter"), "CHIP-48", "default", None),
+    ("d6fa9dc9005dc0496f39ba52fef56f9fd0a5a158", "Kaleidoscope", Some("Joseph Weisbecker"), "COSMAC VIP", "chip8", None),
+    ("b9272ae1acdaaa79ab649f6b48b72088ca2b1d74", "Maze", Some("David Winter"), "CHIP-48", "default", None),
+    ("d979858bb9ffd07b48f52f92a8bcac0199f3623e", "Merlin", Some("David Winter"), "CHIP-48", "default", None),
+    ("0d0cc129dad3c45ba672f85fec71a668232212cc", "Missile Command", Some("David Winter"), "CHIP-48", "default", None),
+    ("b232ef880bd6060fb45fa6effed7edf0ae95670e", "Pong", Some("Paul Vervalin"), "CHIP-48", "default", None),
+    ("a60611339661e3ab2d8af024ad1da5880a6f8665", "Pong 2", None, "CHIP-48", "default", None),
+    ("1293db0ccccbe7dd3fc5a09a2abc5d7b175e18e0", "Puzzle", None, "CHIP-48", "default", None),
+    ("1bdb4ddaa7049266fa3226851f28855a365cfd12", "Syzygy", Some("Roy Trevino"), "CHIP-48", "default", None),
+    ("18b9d15f4c159e1f0ed58c2d8ec1d89325d3a3b6", "Tank", None, "CHIP-48", "default", None),
+    ("5f518084744bf3cb8733f6e5454dfd1634320563", "Tetris", Some("Fran Dachille"), "CHIP-48", "default", None),
+    ("429d455a4bc53167942bf6fd934d72b0f648dce3", "Tic-Tac-Toe", Some("David Winter"), "CHIP-48", "default", None),
+    ("bdb92475acfe11bc7814a2f5eade13fcd09b756a", "UFO", Some("Lutz V"), "CHIP-48", "default", None),
+    ("da710f631f8e35534d0b9170bcf892a60f49c43d", "Vertical Brix", Some("Paul Robson"), "CHIP-48", "default", None),
+    ("ade839585ddeb0e3633177df03c1d91589e629eb", "Vers", Some("JMN"), "CHIP-48", "default", None),
+    ("d666688a8fce468a7d88b536bc1ef5f35ba12031", "Wipe Off", Some("Joseph Weisbecker"), "COSMAC VIP", "chip8", None),
+    ("9f9a4affbf7afd70bb594fb321e16579318c0164", "Spacejam!", Some("William Donnelly"), "CHIP-48", "default", Some(15)),
+];
+
+/// Lowercase hex SHA-1 of a ROM, the key of the database
+pub fn sha1_hex(rom: &[u8]) -> String {
+    Sha1::digest(rom).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a "#RRGGBB" or "RRGGBB" color
+pub fn parse_color(text: &str) -> Option<u32> {
+    let hex = text.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/*
+    ROM metadata keyed by SHA-1, so frontends can pick quirks, speed, keys
+    and colors for a game without the user having to know them.
+    Starts from the built-in entries, a user file can add or change entries:
+
+        ["<sha1>"]
+        title = "My Game"
+        author = "Me"
+        platform = "CHIP-8"
+        quirks = "chip8"                # profile or list, see Quirks::parse
+        ticks = 15
+        colors = ["#FFB000", "#000000"] # foreground, background
+        keys = { Left = 4, Right = 6 }  # host key name = keypad index
+*/
+#[derive(Clone, Debug, Default)]
+pub struct RomDb {
+    entries: HashMap<String, RomInfo>,
+}
+
+impl RomDb {
+    /// Database with only the built-in entries
+    pub fn builtin() -> Self {
+        let entries = BUILTIN
+            .iter()
+            .map(|&(sha1, title, author, platform, quirks, ticks_per_frame)| {
+                let info = RomInfo {
+                    title: title.to_string(),
+                    author: author.map(str::to_string),
+                    platform: Some(platform.to_string()),
+                    quirks: Quirks::parse(quirks).expect("invalid built-in quirks"),
+                    ticks_per_frame,
+                    keys: Vec::new(),
+                    colors: None,
+                };
+                (sha1.to_string(), info)
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Looks up a ROM by its contents
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.get(&sha1_hex(rom))
+    }
+
+    pub fn get(&self, sha1: &str) -> Option<&RomInfo> {
+        self.entries.get(&sha1.to_lowercase())
+    }
+
+    pub fn insert(&mut self, sha1: &str, info: RomInfo) {
+        self.entries.insert(sha1.to_lowercase(), info);
+    }
+
+    /// Applies a user override file, fields it sets replace the existing ones
+    pub fn load_overrides(&mut self, text: &str) -> Result<(), String> {
+        let table: Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        for (sha1, value) in table.iter() {
+            let Value::Table(fields) = value else {
+                return Err(format!("[{}] is not a table", sha1));
+            };
+            let info = self.entries.entry(sha1.to_lowercase()).or_default();
+            apply_override(info, fields).map_err(|e| format!("[{}] {}", sha1, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the fields of one override table into info
+fn apply_override(info: &mut RomInfo, fields: &Table) -> Result<(), String> {
+    for (key, value) in fields.iter() {
+        let invalid = || format!("invalid {}", key);
+        match (key.as_str(), value) {
+            ("title", Value::String(s)) => info.title = s.clone(),
+            ("author", Value::String(s)) => info.author = Some(s.clone()),
+            ("platform", Value::String(s)) => info.platform = Some(s.clone()),
+            ("quirks", Value::String(s)) => info.quirks = Quirks::parse(s)?,
+            ("quirks", Value::Array(names)) => {
+                let mut quirks = Quirks::default();
+                for name in names {
+                    let name = name.as_str().ok_or_else(invalid)?;
+                    if !quirks.set(name, true) {
+                        return Err(format!("unknown quirk '{}'", name));
+                    }
+                }
+                info.quirks = quirks;
+            },
+            ("ticks", Value::Integer(n)) if *n > 0 => info.ticks_per_frame = Some(*n as usize),
+            ("colors", Value::Array(colors)) => match colors.as_slice() {
+                [Value::String(fg), Value::String(bg)] => {
+                    info.colors = Some((parse_color(fg).ok_or_else(invalid)?, parse_color(bg).ok_or_else(invalid)?));
+                },
+                _ => return Err(invalid()),
+            },
+            ("keys", Value::Table(keys)) => {
+                info.keys.clear();
+                for (name, idx) in keys.iter() {
+                    match idx.as_integer() {
+                        Some(idx @ 0..=0xF) => info.keys.push((name.clone(), idx as usize)),
+                        _ => return Err(invalid()),
+                    }
+                }
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRIX: &str = "f13766c14aeb02ad8d4d103cb5eadd282d20cddc";
+
+    #[test]
+    fn builtin_entries_match_the_games() {
+        let info = RomDb::builtin().lookup(include_bytes!("../../games/BRIX")).cloned().unwrap();
+        assert_eq!(info.title, "Brix");
+        assert_eq!(info.quirks, Quirks::default());
+    }
+
+    #[test]
+    fn overrides_change_and_add_entries() {
+        let mut db = RomDb::builtin();
+        let text = format!(
+            "[\"{}\"]\nticks = 20\nquirks = [\"clip\", \"shift\"]\ncolors = [\"#FFB000\", \"000000\"]\nkeys = {{ Left = 4, Right = 6 }}\n\n\
+             [\"ABCDEF\"]\ntitle = \"Mine\"\nquirks = \"chip8\"\n",
+            BRIX.to_uppercase()
+        );
+        db.load_overrides(&text).unwrap();
+
+        let brix = db.get(BRIX).unwrap();
+        assert_eq!(brix.title, "Brix");
+        assert_eq!(brix.ticks_per_frame, Some(20));
+        assert_eq!(brix.quirks, Quirks::parse("shift,clip").unwrap());
+        assert_eq!(brix.colors, Some((0xFFB000, 0x000000)));
+        let mut keys = brix.keys.clone();
+        keys.sort();
+        assert_eq!(keys, [("Left".to_string(), 4), ("Right".to_string(), 6)]);
+
+        let mine = db.get("abcdef").unwrap();
+        assert_eq!((mine.title.as_str(), mine.quirks), ("Mine", Quirks::chip8()));
+    }
+
+    #[test]
+    fn invalid_overrides_are_rejected() {
+        let mut info = RomInfo::default();
+        let table = |text: &str| text.parse::<Table>().unwrap();
+        assert_eq!(apply_override(&mut info, &table("ticks = 0")), Err("invalid ticks".to_string()));
+        assert_eq!(apply_override(&mut info, &table("keys = { Up = 16 }")), Err("invalid keys".to_string()));
+        assert_eq!(apply_override(&mut info, &table("colors = [\"#FFF\", \"#000\"]")), Err("invalid colors".to_string()));
+        assert_eq!(apply_override(&mut info, &table("quirks = [\"wobble\"]")), Err("unknown quirk 'wobble'".to_string()));
+        assert_eq!(apply_override(&mut info, &table("speed = 3")), Err("invalid speed".to_string()));
+        assert!(RomDb::builtin().load_overrides("title = \"x\"").is_err());
+    }
+}
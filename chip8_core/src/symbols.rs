@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+
+/// Place in an assembly source file, lines start at 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+/*
+    Symbol map kept next to an assembled ROM, linking addresses back to the
+    source. This is the emulator's own format, no assembler writes it, so
+    source lines are only known when the map was written by hand. One entry
+    per line, addresses in hex:
+        200 main.8o:12      # the instruction at 0x200 comes from line 12
+        200 main            # label
+        24A draw-player
+    Blank lines and text after '#' are ignored.
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    lines: BTreeMap<u16, SourceLine>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl SymbolMap {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid entry '{}'", n + 1, line);
+            let (addr, target) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let addr = u16::from_str_radix(addr.trim_start_matches("0x"), 16).map_err(|_| invalid())?;
+            let target = target.trim();
+
+            // A label may contain ':' too, it is a source line only if a number follows the last one
+            match target.rsplit_once(':').map(|(file, line)| (file, line.parse::<u32>())) {
+                Some((file, Ok(line))) if !file.is_empty() && line > 0 => {
+                    map.lines.insert(addr, SourceLine { file: file.to_string(), line });
+                },
+                _ => {
+                    map.labels.insert(addr, target.to_string());
+                },
+            }
+        }
+        Ok(map)
+    }
+
+    /// Source line of the instruction at addr
+    pub fn source(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.get(&addr)
+    }
+
+    /// Source line of the closest mapped address at or before addr
+    pub fn nearest_source(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.range(..=addr).next_back().map(|(_, line)| line)
+    }
+
+    /// Lowest address assembled from line of file
+    /// If the line has no code (a comment or a label) the next line with code is used
+    /// Returns the address and the line it really belongs to
+    pub fn address(&self, file: &str, line: u32) -> Option<(u16, u32)> {
+        self.lines
+            .iter()
+            .filter(|(_, source)| source.file == file && source.line >= line)
+            .min_by_key(|(addr, source)| (source.line, **addr))
+            .map(|(addr, source)| (*addr, source.line))
+    }
+
+    /// Source files mentioned in the map
+    pub fn files(&self) -> Vec<&str> {
+        let mut files: Vec<&str> = self.lines.values().map(|s| s.file.as_str()).collect();
+        files.sort_unstable();
+        files.dedup();
+        files
+    }
+
+    /// Label at addr
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// Closest label at or before addr, e.g. the subroutine it is in
+    pub fn enclosing_label(&self, addr: u16) -> Option<(u16, &str)> {
+        self.labels.range(..=addr).next_back().map(|(addr, name)| (*addr, name.as_str()))
+    }
+}
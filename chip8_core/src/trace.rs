@@ -0,0 +1,257 @@
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+use crate::disasm::disassemble;
+use crate::NUM_REGS;
+
+/// First bytes of a binary trace file
+const BINARY_MAGIC: &[u8; 4] = b"C8TR";
+/// Size of a single record in a binary trace
+const BINARY_RECORD_SIZE: usize = 8 + 2 + 2 + NUM_REGS + 2 + 1 + 1 + 1;
+
+/// Machine state right before an instruction is executed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub frame: u64,
+    pub pc: u16,
+    pub op: u16,
+    pub v_reg: [u8; NUM_REGS],
+    pub i_reg: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl TraceRecord {
+    /// One line of text: frame, pc, opcode, mnemonic, registers, I, SP and timers
+    pub fn to_text(&self) -> String {
+        let regs: Vec<String> = self.v_reg.iter().map(|v| format!("{:02X}", v)).collect();
+        format!(
+            "{:>6} {:03X} {:04X} {:<20} V={} I={:03X} SP={:X} DT={:02X} ST={:02X}",
+            self.frame,
+            self.pc,
+            self.op,
+            disassemble(self.op),
+            regs.join(" "),
+            self.i_reg,
+            self.sp,
+            self.dt,
+            self.st
+        )
+    }
+
+    /// Fixed size little endian encoding used by binary traces
+    pub fn to_bytes(&self) -> [u8; BINARY_RECORD_SIZE] {
+        let mut bytes = [0; BINARY_RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.frame.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.op.to_le_bytes());
+        bytes[12..28].copy_from_slice(&self.v_reg);
+        bytes[28..30].copy_from_slice(&self.i_reg.to_le_bytes());
+        bytes[30] = self.sp;
+        bytes[31] = self.dt;
+        bytes[32] = self.st;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; BINARY_RECORD_SIZE]) -> Self {
+        let mut v_reg = [0; NUM_REGS];
+        v_reg.copy_from_slice(&bytes[12..28]);
+        Self {
+            frame: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            pc: u16::from_le_bytes([bytes[8], bytes[9]]),
+            op: u16::from_le_bytes([bytes[10], bytes[11]]),
+            v_reg,
+            i_reg: u16::from_le_bytes([bytes[28], bytes[29]]),
+            sp: bytes[30],
+            dt: bytes[31],
+            st: bytes[32],
+        }
+    }
+}
+
+/// Reads every record of a binary trace
+pub fn read_binary_trace(mut reader: impl Read) -> io::Result<Vec<TraceRecord>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != BINARY_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary trace"));
+    }
+
+    let mut records = Vec::new();
+    let mut buf = [0; BINARY_RECORD_SIZE];
+    loop {
+        match reader.read_exact(&mut buf) {
+            Ok(()) => records.push(TraceRecord::from_bytes(&buf)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// one human readable line per instruction
+    Text,
+    /// fixed size records, see TraceRecord::to_bytes
+    Binary,
+}
+
+/// Decides which instructions end up in the trace
+/// Every filter left at None lets everything through
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// only instructions at these addresses
+    pub addrs: Option<RangeInclusive<u16>>,
+    /// bit n set keeps opcodes whose first digit is n (0x0NNN - 0xFNNN)
+    pub classes: Option<u16>,
+    /// only instructions executed during these frames
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        let class = record.op >> 12;
+        self.addrs.as_ref().is_none_or(|r| r.contains(&record.pc))
+            && self.classes.is_none_or(|mask| mask & (1 << class) != 0)
+            && self.frames.as_ref().is_none_or(|r| r.contains(&record.frame))
+    }
+}
+
+/*
+    Writes a TraceRecord for every executed instruction that passes the filter.
+    Installed into an Emu with Emu::set_tracer.
+    Writing stops at the first io error, which is returned by finish.
+*/
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Self {
+        let mut tracer = Self { writer, format, filter, error: None };
+        if format == TraceFormat::Binary {
+            let res = tracer.writer.write_all(BINARY_MAGIC);
+            tracer.error = res.err();
+        }
+        tracer
+    }
+
+    pub(crate) fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_some() || !self.filter.matches(record) {
+            return;
+        }
+        let res = match self.format {
+            TraceFormat::Text => writeln!(self.writer, "{}", record.to_text()),
+            TraceFormat::Binary => self.writer.write_all(&record.to_bytes()),
+        };
+        self.error = res.err();
+    }
+
+    /// Flushes the writer, returns the first error hit while tracing
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emu;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Output the test can read back after the tracer wrote to it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const ROM: [u8; 6] = [
+        0x60, 0x01, // 200 LD V0, 1
+        0x70, 0x01, // 202 ADD V0, 1
+        0x12, 0x02, // 204 JP 202
+    ];
+
+    /// Runs ROM for 3 frames of 2 instructions and returns what was traced
+    fn trace(format: TraceFormat, filter: TraceFilter) -> Vec<u8> {
+        let out = Shared::default();
+        let mut emu = Emu::new();
+        emu.load(&ROM).unwrap();
+        emu.set_tracer(Some(Tracer::new(Box::new(out.clone()), format, filter)));
+        for _ in 0..3 {
+            emu.tick();
+            emu.tick();
+            emu.tick_timers();
+        }
+        emu.set_tracer(None).unwrap().finish().unwrap();
+        out.0.take()
+    }
+
+    #[test]
+    fn binary_trace_round_trip() {
+        let records = read_binary_trace(&trace(TraceFormat::Binary, TraceFilter::default())[..]).unwrap();
+        let steps: Vec<_> = records.iter().map(|r| (r.frame, r.pc, r.op, r.v_reg[0])).collect();
+        assert_eq!(
+            steps,
+            [
+                (0, 0x200, 0x6001, 0),
+                (0, 0x202, 0x7001, 1),
+                (1, 0x204, 0x1202, 2),
+                (1, 0x202, 0x7001, 2),
+                (2, 0x204, 0x1202, 3),
+                (2, 0x202, 0x7001, 3),
+            ]
+        );
+        assert_eq!(TraceRecord::from_bytes(&records[3].to_bytes()), records[3]);
+    }
+
+    #[test]
+    fn broken_binary_traces_are_rejected() {
+        assert!(read_binary_trace(&b"C8T"[..]).is_err());
+        assert!(read_binary_trace(&b"C8TX"[..]).is_err());
+        // A truncated last record is dropped
+        let mut bytes = trace(TraceFormat::Binary, TraceFilter::default());
+        bytes.pop();
+        assert_eq!(read_binary_trace(&bytes[..]).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn text_trace_has_a_line_per_instruction() {
+        let text = String::from_utf8(trace(TraceFormat::Text, TraceFilter::default())).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[2].starts_with(&format!("     1 204 1202 {:<20} V=02 00", disassemble(0x1202))));
+        assert!(lines[2].ends_with(" I=000 SP=0 DT=00 ST=00"));
+    }
+
+    #[test]
+    fn filter_keeps_matching_instructions() {
+        let pcs = |filter| -> Vec<_> {
+            let bytes = trace(TraceFormat::Binary, filter);
+            read_binary_trace(&bytes[..]).unwrap().iter().map(|r| (r.frame, r.pc)).collect()
+        };
+        let addrs = TraceFilter { addrs: Some(0x204..=0x2FF), ..Default::default() };
+        assert_eq!(pcs(addrs), [(1, 0x204), (2, 0x204)]);
+        let classes = TraceFilter { classes: Some(1 << 6 | 1 << 1), ..Default::default() };
+        assert_eq!(pcs(classes), [(0, 0x200), (1, 0x204), (2, 0x204)]);
+        let frames = TraceFilter { frames: Some(1..=1), ..Default::default() };
+        assert_eq!(pcs(frames), [(1, 0x204), (1, 0x202)]);
+        let all = TraceFilter { addrs: Some(0x202..=0x202), classes: Some(1 << 7), frames: Some(2..=5) };
+        assert_eq!(pcs(all), [(2, 0x202)]);
+    }
+}
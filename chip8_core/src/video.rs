@@ -0,0 +1,282 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::image::Image;
+
+/// GIF delays are in hundredths of a second, browsers slow down anything shorter than this
+const GIF_MIN_DELAY: u64 = 2;
+
+/// Container a recording is written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// animated GIF for sharing, timed as closely to 60Hz as GIF delays allow
+    Gif,
+    /// uncompressed YUV4MPEG2 stream at 60 frames per second
+    Y4m,
+    /// directory of numbered binary PPM images, one per frame
+    Ppm,
+}
+
+impl VideoFormat {
+    /// Format for a path: .gif, .y4m, or a directory of PPM frames for anything else
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("gif") => Self::Gif,
+            Some("y4m") => Self::Y4m,
+            _ => Self::Ppm,
+        }
+    }
+}
+
+enum Sink {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        /// frame waiting for its delay to be known, with the time it was first shown in hundredths
+        pending: Option<(Image, u64)>,
+    },
+    Y4m(BufWriter<File>),
+    Ppm(PathBuf),
+}
+
+/*
+    Writes one image for every emulated 60Hz frame, however fast the host runs.
+    In GIFs identical frames are merged and frames shown for less than the
+    minimum delay are dropped, so the animation keeps the real timing.
+*/
+pub struct VideoRecorder {
+    sink: Sink,
+    width: u32,
+    height: u32,
+    frames: u64,
+}
+
+impl VideoRecorder {
+    /// Starts a recording of width x height images
+    pub fn create(path: &str, format: VideoFormat, width: u32, height: u32) -> Result<Self, String> {
+        let create = || File::create(path).map(BufWriter::new).map_err(|e| e.to_string());
+        let sink = match format {
+            VideoFormat::Gif => {
+                if width > u16::MAX as u32 || height > u16::MAX as u32 {
+                    return Err(format!("GIF frames can be at most {0}x{0}, not {1}x{2}", u16::MAX, width, height));
+                }
+                let mut encoder = gif::Encoder::new(create()?, width as u16, height as u16, &[]).map_err(|e| e.to_string())?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+                Sink::Gif { encoder, pending: None }
+            },
+            VideoFormat::Y4m => {
+                let mut out = create()?;
+                writeln!(out, "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444", width, height).map_err(|e| e.to_string())?;
+                Sink::Y4m(out)
+            },
+            VideoFormat::Ppm => {
+                fs::create_dir_all(path).map_err(|e| e.to_string())?;
+                Sink::Ppm(PathBuf::from(path))
+            },
+        };
+        Ok(Self { sink, width, height, frames: 0 })
+    }
+
+    /// Adds the image of the next frame
+    pub fn add_frame(&mut self, image: &Image) -> Result<(), String> {
+        if (image.width, image.height) != (self.width, self.height) {
+            return Err(format!("frame is {}x{}, the recording is {}x{}", image.width, image.height, self.width, self.height));
+        }
+        let time = self.frames * 100 / 60;
+        match &mut self.sink {
+            Sink::Gif { encoder, pending } => match pending.take() {
+                Some((shown, start)) if shown == *image => *pending = Some((shown, start)),
+                Some((_, start)) if time - start < GIF_MIN_DELAY => *pending = Some((image.clone(), start)),
+                Some((shown, start)) => {
+                    write_gif_frame(encoder, &shown, time - start)?;
+                    *pending = Some((image.clone(), time));
+                },
+                None => *pending = Some((image.clone(), time)),
+            },
+            Sink::Y4m(out) => {
+                out.write_all(b"FRAME\n").map_err(|e| e.to_string())?;
+                out.write_all(&to_yuv444(image)).map_err(|e| e.to_string())?;
+            },
+            Sink::Ppm(dir) => {
+                let path = dir.join(format!("frame-{:06}.ppm", self.frames));
+                let mut out = File::create(&path).map(BufWriter::new).map_err(|e| e.to_string())?;
+                write!(out, "P6\n{} {}\n255\n", image.width, image.height).map_err(|e| e.to_string())?;
+                out.write_all(&image.pixels).map_err(|e| e.to_string())?;
+                out.flush().map_err(|e| e.to_string())?;
+            },
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Number of frames added so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Writes whatever is still buffered, returns the number of frames recorded
+    pub fn finish(self) -> Result<u64, String> {
+        match self.sink {
+            Sink::Gif { mut encoder, pending } => {
+                if let Some((shown, start)) = pending {
+                    let end = self.frames * 100 / 60;
+                    write_gif_frame(&mut encoder, &shown, (end - start).max(GIF_MIN_DELAY))?;
+                }
+                encoder.into_inner().map_err(|e| e.to_string())?.flush().map_err(|e| e.to_string())?;
+            },
+            Sink::Y4m(mut out) => out.flush().map_err(|e| e.to_string())?,
+            Sink::Ppm(_) => (),
+        }
+        Ok(self.frames)
+    }
+}
+
+/// Writes an image shown for delay hundredths of a second
+fn write_gif_frame(encoder: &mut gif::Encoder<BufWriter<File>>, image: &Image, delay: u64) -> Result<(), String> {
+    // Screens only use a handful of colors, so they fit a palette without quantizing
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut indices = Vec::with_capacity(image.pixels.len() / 3);
+    for rgb in image.pixels.chunks_exact(3) {
+        let rgb = [rgb[0], rgb[1], rgb[2]];
+        let idx = match palette.iter().position(|c| *c == rgb) {
+            Some(idx) => idx,
+            None => {
+                palette.push(rgb);
+                palette.len() - 1
+            },
+        };
+        indices.push(idx);
+    }
+
+    let (width, height) = (image.width as u16, image.height as u16);
+    let mut frame = if palette.len() <= 256 {
+        let buffer: Vec<u8> = indices.iter().map(|i| *i as u8).collect();
+        gif::Frame::from_palette_pixels(width, height, buffer, palette.concat(), None)
+    } else {
+        gif::Frame::from_rgb(width, height, &image.pixels)
+    };
+    frame.delay = delay.min(u16::MAX as u64) as u16;
+    encoder.write_frame(&frame).map_err(|e| e.to_string())
+}
+
+/// Converts to the Y, U and V planes of a 4:4:4 Y4M frame (BT.601, studio range)
+fn to_yuv444(image: &Image) -> Vec<u8> {
+    let count = image.pixels.len() / 3;
+    let mut planes = vec![0; count * 3];
+    for (i, rgb) in image.pixels.chunks_exact(3).enumerate() {
+        let (r, g, b) = (rgb[0] as i32, rgb[1] as i32, rgb[2] as i32);
+        planes[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        planes[count + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        planes[2 * count + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// Path in the temporary directory only this test process uses
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("chip8-video-{}-{}", process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    /// 2x1 image with the given colors
+    fn image(left: [u8; 3], right: [u8; 3]) -> Image {
+        Image { width: 2, height: 1, pixels: [left, right].concat() }
+    }
+
+    const BLACK: [u8; 3] = [0, 0, 0];
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const RED: [u8; 3] = [255, 0, 0];
+
+    /// Records images as a GIF and returns the RGB pixels and delay of every frame in it
+    fn gif_frames(name: &str, images: &[Image]) -> Vec<(Vec<u8>, u16)> {
+        let path = temp_path(name);
+        let mut recorder = VideoRecorder::create(&path, VideoFormat::Gif, 2, 1).unwrap();
+        for image in images {
+            recorder.add_frame(image).unwrap();
+        }
+        assert_eq!(recorder.finish().unwrap(), images.len() as u64);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(&bytes[..]).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            let rgb = frame.buffer.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect();
+            frames.push((rgb, frame.delay));
+        }
+        frames
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        assert_eq!(VideoFormat::from_path("out.gif"), VideoFormat::Gif);
+        assert_eq!(VideoFormat::from_path("dir/out.y4m"), VideoFormat::Y4m);
+        assert_eq!(VideoFormat::from_path("frames"), VideoFormat::Ppm);
+    }
+
+    #[test]
+    fn identical_gif_frames_are_merged() {
+        let (a, b) = (image(WHITE, BLACK), image(BLACK, RED));
+        let frames = gif_frames("merged.gif", &[a.clone(), a.clone(), a.clone(), b.clone(), b.clone(), b.clone()]);
+        // 6 frames at 60Hz are 10 hundredths, split where the image changes at 3 * 100 / 60
+        assert_eq!(frames, [(a.pixels, 5), (b.pixels, 5)]);
+    }
+
+    #[test]
+    fn short_gif_frames_are_dropped() {
+        let (a, b, c) = (image(WHITE, BLACK), image(BLACK, RED), image(RED, RED));
+        let frames = gif_frames("dropped.gif", &[a, b.clone(), c.clone()]);
+        // a is shown for less than GIF_MIN_DELAY, the last frame is stretched to it
+        assert_eq!(frames, [(b.pixels, 3), (c.pixels, GIF_MIN_DELAY as u16)]);
+    }
+
+    #[test]
+    fn oversized_gifs_are_rejected() {
+        let path = temp_path("huge.gif");
+        let err = VideoRecorder::create(&path, VideoFormat::Gif, 64 * 1100, 32 * 1100).err().unwrap();
+        assert_eq!(err, "GIF frames can be at most 65535x65535, not 70400x35200");
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn y4m_has_a_header_and_yuv_planes() {
+        let path = temp_path("video.y4m");
+        let mut recorder = VideoRecorder::create(&path, VideoFormat::Y4m, 2, 1).unwrap();
+        recorder.add_frame(&image(WHITE, RED)).unwrap();
+        recorder.add_frame(&image(BLACK, BLACK)).unwrap();
+        assert!(recorder.add_frame(&Image { width: 1, height: 1, pixels: WHITE.to_vec() }).is_err());
+        assert_eq!(recorder.finish().unwrap(), 2);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut expected = b"YUV4MPEG2 W2 H1 F60:1 Ip A1:1 C444\n".to_vec();
+        // Studio range BT.601: white is Y 235, red is Y 82 U 90 V 240
+        expected.extend_from_slice(b"FRAME\n");
+        expected.extend_from_slice(&[235, 82, 128, 90, 128, 240]);
+        expected.extend_from_slice(b"FRAME\n");
+        expected.extend_from_slice(&[16, 16, 128, 128, 128, 128]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn ppm_frames_are_numbered() {
+        let dir = temp_path("frames");
+        let mut recorder = VideoRecorder::create(&dir, VideoFormat::Ppm, 2, 1).unwrap();
+        recorder.add_frame(&image(WHITE, RED)).unwrap();
+        recorder.add_frame(&image(BLACK, WHITE)).unwrap();
+        assert_eq!(recorder.frames(), 2);
+        recorder.finish().unwrap();
+
+        let frame = |n: u32| fs::read(Path::new(&dir).join(format!("frame-{:06}.ppm", n))).unwrap();
+        assert_eq!(frame(0), [b"P6\n2 1\n255\n".as_slice(), &WHITE, &RED].concat());
+        assert_eq!(frame(1), [b"P6\n2 1\n255\n".as_slice(), &BLACK, &WHITE].concat());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+mod protocol;
+mod session;
+
+use std::io;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use protocol::read_message;
+use session::Session;
+
+/// Time between two emulated frames while the program runs, about 60Hz
+const FRAME_TIME: Duration = Duration::from_micros(16_667);
+
+/// Debug Adapter Protocol server on stdin/stdout, see Session for the launch arguments
+fn main() {
+    // Requests are read on their own thread so a running program can be paused
+    let (requests, incoming) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = io::stdin().lock();
+        loop {
+            match read_message(&mut input) {
+                Ok(Some(message)) => {
+                    if requests.send(message).is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("dap: {}", e);
+                    break;
+                },
+            }
+        }
+    });
+
+    let mut session = Session::new(io::stdout());
+    loop {
+        let request = if session.running() {
+            match incoming.try_recv() {
+                Ok(request) => Some(request),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match incoming.recv() {
+                Ok(request) => Some(request),
+                Err(_) => break,
+            }
+        };
+
+        match request {
+            Some(request) => {
+                if !session.handle(&request) {
+                    break;
+                }
+            },
+            None => {
+                session.run_frame();
+                thread::sleep(FRAME_TIME);
+            },
+        }
+    }
+}
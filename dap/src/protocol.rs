@@ -0,0 +1,162 @@
+use serde_json::{json, Value};
+
+use std::io::{self, BufRead, Write};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Reads one "Content-Length: n\r\n\r\n{json}" message, None at the end of the input
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes responses and events with increasing sequence numbers
+pub struct Output<W: Write> {
+    out: W,
+    seq: u64,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, seq: 0 }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        // The client is gone if this fails, the next read will notice
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.out.flush();
+    }
+
+    pub fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    pub fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    pub fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// None if text is not valid base64
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .trim_end_matches('=')
+        .bytes()
+        .map(|c| BASE64.iter().position(|&b| b == c).map(|d| d as u32))
+        .collect::<Option<Vec<u32>>>()?;
+    let mut out = Vec::with_capacity(digits.len() * 3 / 4);
+    for chunk in digits.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, d)| bits | d << (18 - 6 * i));
+        for i in 0..chunk.len() - 1 {
+            out.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        for (data, text) in [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"\x00\xff\x10\x80", "AP8QgA==")] {
+            assert_eq!(base64_encode(data), text);
+            assert_eq!(base64_decode(text).as_deref(), Some(data));
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&all)), Some(all));
+    }
+
+    #[test]
+    fn invalid_base64_is_rejected() {
+        assert_eq!(base64_decode("Zm9v!"), None);
+        assert_eq!(base64_decode("Z"), None);
+        assert_eq!(base64_decode("Zm9vY"), None);
+    }
+
+    #[test]
+    fn messages_are_framed_by_content_length() {
+        let input = b"Content-Length: 13\r\n\r\n{\"seq\":1}    content-length:9\r\nX-Other: 1\r\n\r\n{\"seq\":2}";
+        let mut input = &input[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 2 })));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn broken_messages_are_errors() {
+        let mut missing_length = &b"X-Other: 1\r\n\r\n{}"[..];
+        assert_eq!(read_message(&mut missing_length).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut short_body = &b"Content-Length: 10\r\n\r\n{}"[..];
+        assert_eq!(read_message(&mut short_body).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let mut bad_json = &b"Content-Length: 2\r\n\r\n{]"[..];
+        assert_eq!(read_message(&mut bad_json).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn responses_carry_increasing_sequence_numbers() {
+        let mut out = Output::new(Vec::new());
+        out.respond(&json!({ "seq": 7, "command": "threads" }), json!({}));
+        out.event("stopped", json!({}));
+        let text = String::from_utf8(out.out).unwrap();
+        let mut input = text.as_bytes();
+        let response = read_message(&mut input).unwrap().unwrap();
+        assert_eq!((&response["seq"], &response["request_seq"], &response["success"]), (&json!(1), &json!(7), &json!(true)));
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["seq"], json!(2));
+    }
+}
@@ -0,0 +1,606 @@
+use chip8_core::cartridge::Cartridge;
+use chip8_core::debugger::{Debugger, StopReason};
+use chip8_core::disasm::disassemble;
+use chip8_core::quirks::Quirks;
+use chip8_core::romdb::RomDb;
+use chip8_core::symbols::{SourceLine, SymbolMap};
+use chip8_core::Emu;
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::{base64_decode, base64_encode, Output};
+
+const DEFAULT_TICKS_PER_FRAME: usize = 10;
+/// The machine has a single thread of execution
+const THREAD_ID: u64 = 1;
+/// Extension of the symbol map looked for next to the ROM
+const SYMBOLS_EXTENSION: &str = "sym";
+
+/// variablesReference of each scope, the same for every stack frame
+const REGISTERS_REF: u64 = 1;
+const TIMERS_REF: u64 = 2;
+const STACK_REF: u64 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StepKind {
+    Into,
+    Over,
+    Out,
+}
+
+/// A step in progress and where it started
+struct Step {
+    kind: StepKind,
+    pc: u16,
+    /// stack depth when the step started
+    depth: usize,
+    /// source line when stepping by lines, None when stepping instructions
+    line: Option<SourceLine>,
+}
+
+enum State {
+    Stopped,
+    Running {
+        step: Option<Step>,
+        /// false until the first instruction ran, so resuming on a breakpoint moves on
+        moved: bool,
+    },
+}
+
+/// The launched program
+struct Target {
+    emu: Emu,
+    debugger: Debugger,
+    ticks_per_frame: usize,
+    symbols: SymbolMap,
+    /// directory the file names in the symbol map are relative to
+    symbols_dir: PathBuf,
+    /// breakpoint addresses per source file of the symbol map
+    source_breakpoints: HashMap<String, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+    state: State,
+}
+
+impl Target {
+    /// Path a client can open for a file of the symbol map
+    fn source(&self, line: &SourceLine) -> Value {
+        let path = self.symbols_dir.join(&line.file);
+        let name = path.file_name().map_or(line.file.clone(), |n| n.to_string_lossy().into_owned());
+        json!({ "name": name, "path": path.to_string_lossy() })
+    }
+
+    /// File name used by the symbol map for a path sent by the client
+    fn map_file(&self, path: &str) -> Option<String> {
+        let wanted = canonical(Path::new(path));
+        let files = self.symbols.files();
+        files
+            .iter()
+            .find(|f| canonical(&self.symbols_dir.join(f)) == wanted)
+            .or_else(|| files.iter().find(|f| Path::new(f).file_name() == wanted.file_name()))
+            .map(|f| f.to_string())
+    }
+
+    /// Installs the union of the source and instruction breakpoints
+    fn sync_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+        for &addr in self.source_breakpoints.values().flatten().chain(&self.instruction_breakpoints) {
+            self.debugger.add_breakpoint(addr);
+        }
+    }
+
+    /// Name of the subroutine addr is in
+    fn frame_name(&self, addr: u16) -> String {
+        match self.symbols.enclosing_label(addr) {
+            Some((_, label)) => label.to_string(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    fn resume(&mut self, step: Option<Step>) {
+        self.state = State::Running { step, moved: false };
+    }
+
+    fn start_step(&mut self, kind: StepKind, args: &Value) {
+        let pc = self.emu.pc();
+        let by_line = args["granularity"].as_str() != Some("instruction");
+        let line = by_line.then(|| self.symbols.nearest_source(pc).cloned()).flatten();
+        self.resume(Some(Step { kind, pc, depth: self.emu.stack().len(), line }));
+    }
+
+    /// Runs one frame worth of instructions, returns the reason if the machine stopped
+    fn run_frame(&mut self) -> Option<&'static str> {
+        for _ in 0..self.ticks_per_frame {
+            let State::Running { step, moved } = &mut self.state else {
+                return None;
+            };
+            let reason = if *moved {
+                self.debugger.run(&mut self.emu, 1)
+            } else {
+                *moved = true;
+                Some(self.debugger.step(&mut self.emu)).filter(|r| *r != StopReason::Step)
+            };
+            let stop = match reason {
+                Some(StopReason::Breakpoint(_)) => Some("breakpoint"),
+                Some(StopReason::Watchpoint { .. }) => Some("data breakpoint"),
+                Some(StopReason::IllegalInstruction(_) | StopReason::Fault { .. }) => Some("exception"),
+                _ => None,
+            };
+            let stop = stop.or_else(|| step.as_ref().filter(|s| step_done(&self.emu, &self.symbols, s)).map(|_| "step"));
+            if stop.is_some() {
+                self.state = State::Stopped;
+                return stop;
+            }
+        }
+        None
+    }
+}
+
+/// True once the machine got where the step was going
+fn step_done(emu: &Emu, symbols: &SymbolMap, step: &Step) -> bool {
+    let depth = emu.stack().len();
+    match step.kind {
+        StepKind::Out => return depth < step.depth,
+        StepKind::Over if depth > step.depth => return false,
+        _ => (),
+    }
+    // Stop on the first instruction of another line, or of the same line when it loops
+    let Some(line) = step.line.as_ref() else {
+        return true;
+    };
+    let pc = emu.pc();
+    symbols.source(pc).is_some_and(|source| source != line || pc == step.pc)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Parses a memoryReference or instructionReference, hex with an optional 0x
+fn parse_reference(value: &Value) -> Result<i64, String> {
+    let text = value.as_str().ok_or("missing memory reference")?;
+    i64::from_str_radix(text.trim_start_matches("0x"), 16).map_err(|_| format!("invalid memory reference '{}'", text))
+}
+
+/// Address of a memory reference moved by an optional offset, which fails instead of overflowing
+fn offset_reference(reference: &Value, offset: &Value) -> Result<i64, String> {
+    parse_reference(reference)?
+        .checked_add(offset.as_i64().unwrap_or(0))
+        .ok_or_else(|| "offset moves the address out of range".to_string())
+}
+
+/*
+    A Debug Adapter Protocol session for one program. The client launches a
+    ROM with:
+        { "program": "game.ch8", "symbols": "game.sym", "ticksPerFrame": 10,
+          "quirks": "schip", "seed": 0, "stopOnEntry": true }
+    where everything but program is optional. The symbol map defaults to
+    the ROM's name with a .sym extension (see SymbolMap, no assembler
+    writes one); without one breakpoints can only be set on addresses and
+    steps are single instructions.
+*/
+pub struct Session<W: Write> {
+    out: Output<W>,
+    target: Option<Target>,
+    /// stopped event to send once the response to the current request is out
+    pending_stop: Option<&'static str>,
+}
+
+impl<W: Write> Session<W> {
+    pub fn new(out: W) -> Self {
+        Self { out: Output::new(out), target: None, pending_stop: None }
+    }
+
+    /// True while the program runs and has to be given frames
+    pub fn running(&self) -> bool {
+        self.target.as_ref().is_some_and(|t| matches!(t.state, State::Running { .. }))
+    }
+
+    /// Gives the program one frame and reports if it stopped
+    pub fn run_frame(&mut self) {
+        if let Some(reason) = self.target.as_mut().and_then(Target::run_frame) {
+            self.stopped(reason);
+        }
+    }
+
+    fn stopped(&mut self, reason: &str) {
+        self.out.event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }));
+    }
+
+    /// Answers a request, false once the client ended the session
+    pub fn handle(&mut self, request: &Value) -> bool {
+        if request["type"] != "request" {
+            return true;
+        }
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "disconnect" | "terminate" => Ok(json!({})),
+            _ => match self.target.as_mut() {
+                Some(target) => Self::dispatch(target, command, args, &mut self.pending_stop),
+                None => Err("no program has been launched".to_string()),
+            },
+        };
+
+        match result {
+            Ok(body) => self.out.respond(request, body),
+            Err(e) => self.out.fail(request, &e),
+        }
+        match command {
+            "launch" if self.target.is_some() => self.out.event("initialized", json!({})),
+            "disconnect" | "terminate" => {
+                self.out.event("terminated", json!({}));
+                return false;
+            },
+            _ => (),
+        }
+        if let Some(reason) = self.pending_stop.take() {
+            self.stopped(reason);
+        }
+        true
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("launch needs a program")?;
+        let rom = fs::read(program).map_err(|e| format!("unable to open {}: {}", program, e))?;
+        if Cartridge::is_cartridge(&rom) {
+            return Err("Octo cartridges contain source code, launch the assembled ROM instead".to_string());
+        }
+
+        let info = RomDb::builtin().lookup(&rom).cloned().unwrap_or_default();
+        let quirks = match args["quirks"].as_str() {
+            Some(text) => Quirks::parse(text)?,
+            None => info.quirks,
+        };
+        let ticks_per_frame = args["ticksPerFrame"]
+            .as_u64()
+            .map(|t| t as usize)
+            .or(info.ticks_per_frame)
+            .unwrap_or(DEFAULT_TICKS_PER_FRAME);
+
+        let symbols_path = match args["symbols"].as_str() {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(Path::new(program).with_extension(SYMBOLS_EXTENSION)).filter(|p| p.exists()),
+        };
+        let symbols = match symbols_path.as_ref() {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("unable to open {}: {}", path.display(), e))?;
+                SymbolMap::parse(&text).map_err(|e| format!("invalid symbol map {}: {}", path.display(), e))?
+            },
+            None => SymbolMap::default(),
+        };
+        let symbols_dir = symbols_path
+            .as_ref()
+            .and_then(|p| p.parent())
+            .map_or(PathBuf::from("."), Path::to_path_buf);
+
+        let mut emu = Emu::with_seed(args["seed"].as_u64().unwrap_or(0));
+        emu.set_quirks(quirks);
+        emu.load(&rom).map_err(|e| format!("{}: {}", program, e))?;
+        self.target = Some(Target {
+            emu,
+            debugger: Debugger::new(ticks_per_frame),
+            ticks_per_frame,
+            symbols,
+            symbols_dir,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            state: State::Stopped,
+        });
+        Ok(json!({}))
+    }
+
+    /// Requests that need a launched program
+    fn dispatch(target: &mut Target, command: &str, args: &Value, pending_stop: &mut Option<&'static str>) -> Result<Value, String> {
+        match command {
+            "configurationDone" => {
+                if target.stop_on_entry {
+                    *pending_stop = Some("entry");
+                } else {
+                    target.resume(None);
+                }
+                Ok(json!({}))
+            },
+            "setBreakpoints" => {
+                let path = args["source"]["path"].as_str().ok_or("breakpoints need a source path")?;
+                let file = target.map_file(path);
+                let mut addrs = Vec::new();
+                let breakpoints: Vec<Value> = args["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|bp| {
+                        let line = bp["line"].as_u64().unwrap_or(0) as u32;
+                        match file.as_ref().and_then(|f| target.symbols.address(f, line)) {
+                            Some((addr, line)) => {
+                                addrs.push(addr);
+                                json!({ "verified": true, "line": line, "instructionReference": format!("0x{:03X}", addr) })
+                            },
+                            None if target.symbols.files().is_empty() => json!({
+                                "verified": false,
+                                "line": line,
+                                "message": "source breakpoints need a symbol map, use instruction breakpoints",
+                            }),
+                            None => json!({ "verified": false, "line": line, "message": "no code at this line" }),
+                        }
+                    })
+                    .collect();
+                if let Some(file) = file {
+                    target.source_breakpoints.insert(file, addrs);
+                }
+                target.sync_breakpoints();
+                Ok(json!({ "breakpoints": breakpoints }))
+            },
+            "setInstructionBreakpoints" => {
+                let mut results = Vec::new();
+                target.instruction_breakpoints.clear();
+                for bp in args["breakpoints"].as_array().into_iter().flatten() {
+                    let addr = offset_reference(&bp["instructionReference"], &bp["offset"]);
+                    match addr {
+                        Ok(addr @ 0..=0xFFF) => {
+                            target.instruction_breakpoints.push(addr as u16);
+                            results.push(json!({ "verified": true, "instructionReference": format!("0x{:03X}", addr) }));
+                        },
+                        _ => results.push(json!({ "verified": false, "message": "address outside of RAM" })),
+                    }
+                }
+                target.sync_breakpoints();
+                Ok(json!({ "breakpoints": results }))
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => {
+                // The current instruction, then every call on the stack, innermost first
+                let mut addrs = vec![target.emu.pc()];
+                addrs.extend(target.emu.stack().iter().rev().map(|ret| ret.wrapping_sub(2)));
+                let frames: Vec<Value> = addrs
+                    .iter()
+                    .enumerate()
+                    .map(|(id, &addr)| {
+                        let mut frame = json!({
+                            "id": id,
+                            "name": target.frame_name(addr),
+                            "line": 0,
+                            "column": 0,
+                            "instructionPointerReference": format!("0x{:03X}", addr),
+                        });
+                        if let Some(line) = target.symbols.nearest_source(addr) {
+                            frame["source"] = target.source(line);
+                            frame["line"] = json!(line.line);
+                            frame["column"] = json!(1);
+                        }
+                        frame
+                    })
+                    .collect();
+                Ok(json!({ "stackFrames": frames, "totalFrames": addrs.len() }))
+            },
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "presentationHint": "registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            ]})),
+            "variables" => {
+                let regs = target.emu.registers();
+                let var = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+                let pointer = |name: &str, addr: u16| {
+                    json!({ "name": name, "value": format!("0x{:03X}", addr), "variablesReference": 0, "memoryReference": format!("0x{:03X}", addr) })
+                };
+                let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+                    Some(REGISTERS_REF) => {
+                        let mut vars: Vec<Value> = regs
+                            .v
+                            .iter()
+                            .enumerate()
+                            .map(|(x, v)| var(format!("V{:X}", x), format!("0x{:02X} ({})", v, v)))
+                            .collect();
+                        vars.push(pointer("I", regs.i));
+                        vars.push(pointer("PC", regs.pc));
+                        vars.push(var("SP".to_string(), regs.sp.to_string()));
+                        vars
+                    },
+                    Some(TIMERS_REF) => vec![
+                        var("DT".to_string(), regs.dt.to_string()),
+                        var("ST".to_string(), regs.st.to_string()),
+                        var("frame".to_string(), target.emu.frame().to_string()),
+                    ],
+                    Some(STACK_REF) => target
+                        .emu
+                        .stack()
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .map(|(depth, &ret)| {
+                            let value = match target.symbols.enclosing_label(ret) {
+                                Some((_, label)) => format!("0x{:03X} ({})", ret, label),
+                                None => format!("0x{:03X}", ret),
+                            };
+                            var(format!("[{}]", depth), value)
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Ok(json!({ "variables": variables }))
+            },
+            "continue" => {
+                target.resume(None);
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" | "stepIn" | "stepOut" => {
+                let kind = match command {
+                    "next" => StepKind::Over,
+                    "stepIn" => StepKind::Into,
+                    _ => StepKind::Out,
+                };
+                target.start_step(kind, args);
+                Ok(json!({}))
+            },
+            "pause" => {
+                if matches!(target.state, State::Running { .. }) {
+                    target.state = State::Stopped;
+                    *pending_stop = Some("pause");
+                }
+                Ok(json!({}))
+            },
+            "readMemory" => {
+                let memory = target.emu.memory();
+                let start = parse_reference(&args["memoryReference"])?.saturating_add(args["offset"].as_i64().unwrap_or(0));
+                // Nothing past the 4 KiB address space can be read, so larger counts are cut down to it
+                let count = args["count"].as_i64().unwrap_or(0).clamp(0, memory.len() as i64);
+                let first = start.clamp(0, memory.len() as i64) as usize;
+                let last = start.saturating_add(count).clamp(0, memory.len() as i64) as usize;
+                let data = &memory[first..last.max(first)];
+                Ok(json!({
+                    "address": format!("0x{:03X}", start),
+                    "data": base64_encode(data),
+                    "unreadableBytes": count as usize - data.len(),
+                }))
+            },
+            "writeMemory" => {
+                let start = offset_reference(&args["memoryReference"], &args["offset"])?;
+                let data = base64_decode(args["data"].as_str().unwrap_or_default()).ok_or("invalid base64 data")?;
+                if start < 0 || start as u64 + data.len() as u64 > target.emu.memory().len() as u64 {
+                    return Err("write outside of RAM".to_string());
+                }
+                target.emu.write_memory(start as u16, &data);
+                Ok(json!({ "bytesWritten": data.len() }))
+            },
+            "disassemble" => {
+                let memory = target.emu.memory();
+                let start = parse_reference(&args["memoryReference"])?
+                    .saturating_add(args["offset"].as_i64().unwrap_or(0))
+                    .saturating_add(args["instructionOffset"].as_i64().unwrap_or(0).saturating_mul(2));
+                // At most as many instructions as the address space holds
+                let count = args["instructionCount"].as_i64().unwrap_or(0).clamp(0, memory.len() as i64 / 2);
+                let instructions: Vec<Value> = (0..count)
+                    .map(|n| {
+                        let addr = start.saturating_add(2 * n);
+                        if addr < 0 || addr as usize + 1 >= memory.len() {
+                            return json!({ "address": format!("0x{:X}", addr), "instruction": "??", "presentationHint": "invalid" });
+                        }
+                        let op = (memory[addr as usize] as u16) << 8 | memory[addr as usize + 1] as u16;
+                        let mut instruction = json!({
+                            "address": format!("0x{:03X}", addr),
+                            "instructionBytes": format!("{:04X}", op),
+                            "instruction": disassemble(op),
+                        });
+                        if let Some(label) = target.symbols.label(addr as u16) {
+                            instruction["symbol"] = json!(label);
+                        }
+                        if let Some(line) = target.symbols.source(addr as u16) {
+                            instruction["location"] = target.source(line);
+                            instruction["line"] = json!(line.line);
+                        }
+                        instruction
+                    })
+                    .collect();
+                Ok(json!({ "instructions": instructions }))
+            },
+            _ => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::read_message;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Output the test can read while the session writes to it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A session with BRIX launched, and what it writes
+    fn launched() -> (Session<Shared>, Shared) {
+        let out = Shared::default();
+        let mut session = Session::new(out.clone());
+        request(&mut session, &out, "launch", json!({ "program": "../games/BRIX" }));
+        (session, out)
+    }
+
+    /// Sends a request and returns its response
+    fn request(session: &mut Session<Shared>, out: &Shared, command: &str, arguments: Value) -> Value {
+        out.0.borrow_mut().clear();
+        session.handle(&json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }));
+        let written = out.0.borrow().clone();
+        let mut input = &written[..];
+        while let Some(message) = read_message(&mut input).unwrap() {
+            if message["type"] == "response" {
+                return message;
+            }
+        }
+        panic!("no response to {}", command);
+    }
+
+    #[test]
+    fn references_are_hex_addresses() {
+        assert_eq!(parse_reference(&json!("0x200")), Ok(0x200));
+        assert_eq!(parse_reference(&json!("FFF")), Ok(0xFFF));
+        assert!(parse_reference(&json!("0xZZ")).is_err());
+        assert!(parse_reference(&json!(512)).is_err());
+        assert!(parse_reference(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn offsets_move_references_without_overflowing() {
+        assert_eq!(offset_reference(&json!("0x200"), &json!(4)), Ok(0x204));
+        assert_eq!(offset_reference(&json!("0x200"), &json!(-0x201)), Ok(-1));
+        assert_eq!(offset_reference(&json!("0x200"), &Value::Null), Ok(0x200));
+        assert!(offset_reference(&json!("0x200"), &json!(i64::MAX)).is_err());
+        assert!(offset_reference(&json!("7FFFFFFFFFFFFFFF"), &json!(1)).is_err());
+    }
+
+    #[test]
+    fn memory_writes_stay_in_ram() {
+        let (mut session, out) = launched();
+        let write = |offset: Value| json!({ "memoryReference": "0x300", "offset": offset, "data": "AQI=" });
+        let response = request(&mut session, &out, "writeMemory", write(json!(i64::MAX)));
+        assert_eq!(response["success"], json!(false));
+        let response = request(&mut session, &out, "writeMemory", write(json!(0xCFF)));
+        assert_eq!(response["success"], json!(false));
+        let response = request(&mut session, &out, "writeMemory", write(json!(0xCFE)));
+        assert_eq!(response["body"]["bytesWritten"], json!(2));
+        let response = request(&mut session, &out, "readMemory", json!({ "memoryReference": "0xFFE", "count": 2 }));
+        assert_eq!(response["body"]["data"], json!("AQI="));
+    }
+
+    #[test]
+    fn instruction_breakpoints_outside_ram_are_not_verified() {
+        let (mut session, out) = launched();
+        let breakpoints = json!({ "breakpoints": [
+            { "instructionReference": "0x200", "offset": 2 },
+            { "instructionReference": "0x200", "offset": i64::MAX },
+            { "instructionReference": "0x200", "offset": 0xE00 },
+        ]});
+        let response = request(&mut session, &out, "setInstructionBreakpoints", breakpoints);
+        let verified: Vec<&Value> = response["body"]["breakpoints"].as_array().unwrap().iter().map(|bp| &bp["verified"]).collect();
+        assert_eq!(verified, [&json!(true), &json!(false), &json!(false)]);
+    }
+}
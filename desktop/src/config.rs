@@ -0,0 +1,88 @@
+use chip8_core::cheats::{Cheat, CheatDb};
+use chip8_core::romdb::{sha1_hex, RomDb};
+
+use crate::keymap::KeyMap;
+use crate::palette::Palettes;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Name of the ROM database override file inside the config directory
+const ROMDB_FILE: &str = "romdb.toml";
+/// Name of the key mapping file inside the config directory
+const KEYS_FILE: &str = "keys.toml";
+/// Name of the custom palette file inside the config directory
+const PALETTES_FILE: &str = "palettes.toml";
+/// Name of the cheat list file inside the config directory
+const CHEATS_FILE: &str = "cheats.toml";
+
+/// Directory the frontend reads its configuration from:
+/// $XDG_CONFIG_HOME/chip8 or ~/.config/chip8
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("chip8"))
+}
+
+/// Path given on the command line, otherwise name in the config directory if it exists
+fn config_file(path: Option<&str>, name: &str) -> Option<PathBuf> {
+    match path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config_dir().map(|dir| dir.join(name)).filter(|path| path.exists()),
+    }
+}
+
+/// Built-in ROM database with the user's overrides applied
+/// path is given on the command line, otherwise romdb.toml in the config directory is used if it exists
+pub fn load_romdb(path: Option<&str>) -> RomDb {
+    let mut db = RomDb::builtin();
+    let Some(path) = config_file(path, ROMDB_FILE) else {
+        return db;
+    };
+
+    let text = fs::read_to_string(&path).expect("Unable to open ROM database");
+    if let Err(e) = db.load_overrides(&text) {
+        panic!("Invalid ROM database {}: {}", path.display(), e);
+    }
+    db
+}
+
+/// Key mapping for a ROM, the default layout if there is no key mapping file
+/// path is given on the command line, otherwise keys.toml in the config directory is used if it exists
+pub fn load_keymap(path: Option<&str>, rom: &[u8]) -> KeyMap {
+    let Some(path) = config_file(path, KEYS_FILE) else {
+        return KeyMap::default();
+    };
+
+    let text = fs::read_to_string(&path).expect("Unable to open key mapping");
+    KeyMap::parse(&text, &sha1_hex(rom)).unwrap_or_else(|e| panic!("Invalid key mapping {}: {}", path.display(), e))
+}
+
+/// Built-in palettes followed by the ones in palettes.toml in the config directory
+pub fn load_palettes() -> Palettes {
+    let mut palettes = Palettes::default();
+    let Some(path) = config_file(None, PALETTES_FILE) else {
+        return palettes;
+    };
+
+    let text = fs::read_to_string(&path).expect("Unable to open palettes");
+    if let Err(e) = palettes.load(&text) {
+        panic!("Invalid palettes {}: {}", path.display(), e);
+    }
+    palettes
+}
+
+/// Cheats listed for a ROM, none if there is no cheat list file
+/// path is given on the command line, otherwise cheats.toml in the config directory is used if it exists
+pub fn load_cheats(path: Option<&str>, rom: &[u8]) -> Vec<Cheat> {
+    let Some(path) = config_file(path, CHEATS_FILE) else {
+        return Vec::new();
+    };
+
+    let text = fs::read_to_string(&path).expect("Unable to open cheat list");
+    let db = CheatDb::parse(&text).unwrap_or_else(|e| panic!("Invalid cheat list {}: {}", path.display(), e));
+    db.lookup(rom).to_vec()
+}
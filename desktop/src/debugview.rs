@@ -0,0 +1,184 @@
+use chip8_core::debugger::{Debugger, StopReason};
+use chip8_core::disasm::disassemble;
+use chip8_core::Emu;
+
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use crate::font::{draw_text, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+/// Host pixels per font pixel
+const TEXT_SCALE: u32 = 2;
+/// Characters that fit on a line of the panel
+const PANEL_COLUMNS: u32 = 30;
+/// Host pixels between the panel's edge and the text
+const MARGIN: i32 = 8;
+/// Width of the panel next to the game screen
+pub const PANEL_WIDTH: u32 = PANEL_COLUMNS * (GLYPH_WIDTH + 1) * TEXT_SCALE + 2 * MARGIN as u32;
+
+/// Instructions shown before and after the cursor
+const CODE_CONTEXT: u16 = 3;
+/// Return addresses shown, innermost first
+const STACK_LINES: usize = 3;
+const MEMORY_LINES: u16 = 4;
+const MEMORY_COLUMNS: u16 = 8;
+
+const BACKGROUND: Color = Color::RGB(24, 24, 24);
+const TEXT: Color = Color::RGB(255, 255, 255);
+const DIM: Color = Color::RGB(128, 128, 128);
+const HIGHLIGHT: Color = Color::RGB(64, 64, 64);
+const BREAKPOINT: Color = Color::RGB(255, 96, 96);
+
+/*
+    Panel drawn to the right of the game screen with the registers, timers,
+    call stack, the code around PC and a hex view of memory. The cursor in
+    the code follows PC until it is moved with Up/Down; F2 toggles a
+    breakpoint on its line. PageUp/PageDown scroll the memory view, which
+    otherwise follows I, and Home makes both follow the machine again.
+*/
+pub struct DebugView {
+    visible: bool,
+    /// address breakpoints are toggled at, None follows PC
+    cursor: Option<u16>,
+    /// first address of the memory view, None follows I
+    memory: Option<u16>,
+    /// why the machine last stopped
+    status: String,
+}
+
+impl DebugView {
+    pub fn new() -> Self {
+        Self { visible: false, cursor: None, memory: None, status: String::new() }
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    /// Shows why the machine stopped and brings the panel up if it was hidden
+    pub fn stopped(&mut self, reason: StopReason, emu: &Emu) {
+        self.status = match reason {
+            StopReason::Step => format!("STEP {:03X}", emu.pc()),
+            StopReason::Breakpoint(addr) => format!("BREAKPOINT {:03X}", addr),
+            StopReason::Watchpoint { addr, .. } => format!("WATCHPOINT {:03X}", addr),
+            StopReason::IllegalInstruction(addr) => format!("BAD OPCODE {:03X}", addr),
+            StopReason::Fault { addr, fault } => format!("{} {:03X}", fault.to_string().to_uppercase(), addr),
+        };
+        self.cursor = None;
+        self.visible = true;
+    }
+
+    /// Clears the status once the machine runs again
+    pub fn resumed(&mut self) {
+        self.status.clear();
+    }
+
+    /// Handles the panel's keys while it is shown, true if the key was used
+    pub fn handle_key(&mut self, key: Keycode, emu: &Emu, debugger: &mut Debugger) -> bool {
+        if !self.visible {
+            return false;
+        }
+        let cursor = self.cursor.unwrap_or(emu.pc());
+        let memory = self.memory.unwrap_or(emu.registers().i);
+        let page = MEMORY_LINES * MEMORY_COLUMNS;
+        match key {
+            Keycode::Up => self.cursor = Some(cursor.saturating_sub(2)),
+            Keycode::Down => self.cursor = Some((cursor + 2).min(0xFFE)),
+            Keycode::F2 => {
+                if !debugger.remove_breakpoint(cursor) {
+                    debugger.add_breakpoint(cursor);
+                }
+            },
+            Keycode::PageUp => self.memory = Some(memory.saturating_sub(page)),
+            Keycode::PageDown => self.memory = Some((memory + page).min(0x1000 - page)),
+            Keycode::Home => {
+                self.cursor = None;
+                self.memory = None;
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Draws the panel over the right edge of the output
+    pub fn draw(&self, canvas: &mut Canvas<Window>, emu: &Emu, debugger: &Debugger, paused: bool) {
+        let (width, height) = canvas.output_size().unwrap();
+        let left = width.saturating_sub(PANEL_WIDTH) as i32;
+        canvas.set_draw_color(BACKGROUND);
+        canvas.fill_rect(Rect::new(left, 0, PANEL_WIDTH, height)).unwrap();
+
+        let line_height = ((GLYPH_HEIGHT + 3) * TEXT_SCALE) as i32;
+        let mut y = MARGIN;
+        let line = |canvas: &mut Canvas<Window>, y: &mut i32, text: &str, color: Color| {
+            draw_text(canvas, text, left + MARGIN, *y, TEXT_SCALE, color);
+            *y += line_height;
+        };
+
+        let regs = emu.registers();
+        line(canvas, &mut y, &format!("PC {:03X}  I {:03X}  SP {}", regs.pc, regs.i, regs.sp), TEXT);
+        line(canvas, &mut y, &format!("DT {:02X}  ST {:02X}  FRAME {}", regs.dt, regs.st, emu.frame()), TEXT);
+        for (n, values) in regs.v.chunks(4).enumerate() {
+            let text: Vec<String> = values.iter().enumerate().map(|(i, v)| format!("V{:X} {:02X}", n * 4 + i, v)).collect();
+            line(canvas, &mut y, &text.join(" "), TEXT);
+        }
+
+        line(canvas, &mut y, "STACK", DIM);
+        let stack = emu.stack();
+        if stack.is_empty() {
+            line(canvas, &mut y, "  EMPTY", TEXT);
+        }
+        for ret in stack.iter().rev().take(STACK_LINES) {
+            line(canvas, &mut y, &format!("  {:03X}", ret), TEXT);
+        }
+        if stack.len() > STACK_LINES {
+            line(canvas, &mut y, &format!("  ... {} MORE", stack.len() - STACK_LINES), DIM);
+        }
+
+        line(canvas, &mut y, "CODE", DIM);
+        let cursor = self.cursor.unwrap_or(regs.pc);
+        let memory = emu.memory();
+        let first = cursor.saturating_sub(2 * CODE_CONTEXT);
+        for addr in (first..).step_by(2).take(2 * CODE_CONTEXT as usize + 1) {
+            let Some(bytes) = memory.get(addr as usize..addr as usize + 2) else {
+                break;
+            };
+            let op = (bytes[0] as u16) << 8 | bytes[1] as u16;
+            let breakpoint = debugger.breakpoints().any(|b| b == addr);
+            let marker = match (addr == regs.pc, breakpoint) {
+                (true, true) => ">*",
+                (true, false) => "> ",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            if addr == cursor && self.cursor.is_some() {
+                canvas.set_draw_color(HIGHLIGHT);
+                canvas.fill_rect(Rect::new(left + MARGIN - 2, y - 2, PANEL_WIDTH - MARGIN as u32, line_height as u32)).unwrap();
+            }
+            let color = if breakpoint { BREAKPOINT } else { TEXT };
+            line(canvas, &mut y, &format!("{}{:03X} {:04X} {}", marker, addr, op, disassemble(op)), color);
+        }
+
+        let start = self.memory.unwrap_or(regs.i).min(0x1000 - MEMORY_LINES * MEMORY_COLUMNS);
+        line(canvas, &mut y, if self.memory.is_some() { "MEMORY" } else { "MEMORY AT I" }, DIM);
+        for row in 0..MEMORY_LINES {
+            let addr = start + row * MEMORY_COLUMNS;
+            let bytes = &memory[addr as usize..(addr + MEMORY_COLUMNS) as usize];
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            line(canvas, &mut y, &format!("{:03X} {}", addr, hex.join(" ")), TEXT);
+        }
+
+        let state = match (paused, self.status.is_empty()) {
+            (false, _) => "RUNNING".to_string(),
+            (true, true) => "PAUSED".to_string(),
+            (true, false) => format!("PAUSED: {}", self.status),
+        };
+        draw_text(canvas, &state, left + MARGIN, height as i32 - MARGIN - line_height, TEXT_SCALE, DIM);
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use sdl2::controller::{Axis, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+
+use crate::keymap::Input;
+
+/*
+    Keeps the connected game controllers open and turns their events into
+    inputs being pressed or released.
+    Sticks and triggers press an input once they leave the deadzone and
+    release it when they come back or cross over to the other direction.
+    An input two controllers share stays pressed until both let go, and a
+    controller that is unplugged lets go of everything it held.
+*/
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    /// open controllers by joystick instance id
+    controllers: HashMap<u32, GameController>,
+    deadzone: i16,
+    /// direction every axis is pushed in, by joystick instance id and axis
+    axes: HashMap<(u32, Axis), bool>,
+    /// inputs every controller is holding down, by joystick instance id
+    held: Vec<(u32, Input)>,
+}
+
+impl Gamepads {
+    /// Opens every controller that is already connected
+    pub fn new(subsystem: GameControllerSubsystem, deadzone: i16) -> Self {
+        let mut pads = Self { subsystem, controllers: HashMap::new(), deadzone, axes: HashMap::new(), held: Vec::new() };
+        for idx in 0..pads.subsystem.num_joysticks().unwrap_or(0) {
+            pads.open(idx);
+        }
+        pads
+    }
+
+    fn open(&mut self, joystick_index: u32) {
+        if !self.subsystem.is_game_controller(joystick_index) {
+            return;
+        }
+        match self.subsystem.open(joystick_index) {
+            Ok(controller) => {
+                println!("Controller connected: {}", controller.name());
+                self.controllers.insert(controller.instance_id(), controller);
+            },
+            Err(e) => println!("Unable to open controller {}: {}", joystick_index, e),
+        }
+    }
+
+    /// Inputs pressed (true) or released (false) by an event
+    pub fn handle(&mut self, event: &Event) -> Vec<(Input, bool)> {
+        let mut changes = Vec::new();
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => self.open(which),
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.remove(&which);
+                self.axes.retain(|(id, _), _| *id != which);
+                // Let go of everything the controller was holding
+                let held: Vec<Input> = self.held.iter().filter(|(id, _)| *id == which).map(|&(_, input)| input).collect();
+                for input in held {
+                    self.set(which, input, false, &mut changes);
+                }
+            },
+            Event::ControllerButtonDown { which, button, .. } => self.set(which, Input::Button(button), true, &mut changes),
+            Event::ControllerButtonUp { which, button, .. } => self.set(which, Input::Button(button), false, &mut changes),
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                let pushed = match value {
+                    v if v > self.deadzone => Some(true),
+                    v if v < -self.deadzone => Some(false),
+                    _ => None,
+                };
+                let previous = match pushed {
+                    Some(positive) => self.axes.insert((which, axis), positive),
+                    None => self.axes.remove(&(which, axis)),
+                };
+
+                if previous != pushed {
+                    if let Some(positive) = previous {
+                        self.set(which, Input::Axis(axis, positive), false, &mut changes);
+                    }
+                    if let Some(positive) = pushed {
+                        self.set(which, Input::Axis(axis, positive), true, &mut changes);
+                    }
+                }
+            },
+            _ => (),
+        }
+        changes
+    }
+
+    /// Records one controller holding or letting go of an input, the input
+    /// only changes while no other controller holds it
+    fn set(&mut self, which: u32, input: Input, pressed: bool, changes: &mut Vec<(Input, bool)>) {
+        let was_held = self.held.contains(&(which, input));
+        if pressed == was_held {
+            return;
+        }
+        if pressed {
+            self.held.push((which, input));
+        } else {
+            self.held.retain(|&held| held != (which, input));
+        }
+        if !self.held.iter().any(|&(id, i)| id != which && i == input) {
+            changes.push((input, pressed));
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use chip8_core::NUM_KEYS;
+
+use sdl2::controller::{Axis, Button};
+use sdl2::keyboard::Keycode;
+use toml::{Table, Value};
+
+/// Host keys of every keypad button, 1234/QWER/ASDF/ZXCV on a QWERTY keyboard
+const DEFAULT_LAYOUT: [Keycode; NUM_KEYS] = [
+    Keycode::X,
+    Keycode::Num1,
+    Keycode::Num2,
+    Keycode::Num3,
+    Keycode::Q,
+    Keycode::W,
+    Keycode::E,
+    Keycode::A,
+    Keycode::S,
+    Keycode::D,
+    Keycode::Z,
+    Keycode::C,
+    Keycode::Num4,
+    Keycode::R,
+    Keycode::F,
+    Keycode::V,
+];
+
+/// Game controller inputs and their keypad button, D-pad and left stick act as WASD
+const DEFAULT_PAD: [(Input, usize); 10] = [
+    (Input::Button(Button::DPadUp), 0x5),
+    (Input::Button(Button::DPadLeft), 0x7),
+    (Input::Button(Button::DPadDown), 0x8),
+    (Input::Button(Button::DPadRight), 0x9),
+    (Input::Button(Button::A), 0x6),
+    (Input::Button(Button::B), 0x4),
+    (Input::Axis(Axis::LeftY, false), 0x5),
+    (Input::Axis(Axis::LeftX, false), 0x7),
+    (Input::Axis(Axis::LeftY, true), 0x8),
+    (Input::Axis(Axis::LeftX, true), 0x9),
+];
+
+/// Host keys the emulator itself uses (see the event loop), they cannot press a keypad button
+pub const HOTKEYS: [Keycode; 17] = [
+    Keycode::Escape,
+    Keycode::P,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F1,
+    Keycode::N,
+    Keycode::Tab,
+    Keycode::M,
+    Keycode::Equals,
+    Keycode::Minus,
+    Keycode::K,
+    Keycode::G,
+    Keycode::I,
+    Keycode::F12,
+    Keycode::F9,
+    Keycode::F11,
+    Keycode::H,
+];
+
+/// How far a stick has to be pushed before it presses a button, out of 32767
+const DEFAULT_DEADZONE: i16 = 8000;
+
+/// Prefix of game controller input names
+const PAD_PREFIX: &str = "pad:";
+
+/// Something on the host that can press a keypad button
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Key(Keycode),
+    Button(Button),
+    /// stick or trigger pushed past the deadzone, true in the positive direction
+    Axis(Axis, bool),
+}
+
+impl Input {
+    /// Parses an SDL key name, or a controller input such as "pad:dpup",
+    /// "pad:a" or "pad:leftx-" with SDL's controller button and axis names
+    pub fn from_name(name: &str) -> Option<Self> {
+        let Some(pad) = name.strip_prefix(PAD_PREFIX) else {
+            return Keycode::from_name(name).map(Input::Key);
+        };
+        if let Some(axis) = pad.strip_suffix('+') {
+            Axis::from_string(axis).map(|axis| Input::Axis(axis, true))
+        } else if let Some(axis) = pad.strip_suffix('-') {
+            Axis::from_string(axis).map(|axis| Input::Axis(axis, false))
+        } else {
+            Button::from_string(pad).map(Input::Button)
+        }
+    }
+}
+
+/*
+    Which host keys press which keypad button, any number of keys per button.
+    Read from a TOML file where buttons are hex digits and keys SDL key names
+    or controller inputs (see Input::from_name), [default] changes the layout
+    for every ROM and [rom.<sha1>] for one ROM:
+
+        [default]
+        1 = ["&", "Keypad 7"]
+        4 = "A"
+
+        [rom.6f6509f38220e057a7e32ebb22dd353c1078e3e7]
+        5 = ["Space", "Up", "pad:a"]
+
+        [pad]
+        deadzone = 0.3
+
+    Buttons a table does not mention keep their keys, and emulator hotkeys
+    (HOTKEYS) cannot be used.
+*/
+#[derive(Clone, Debug)]
+pub struct KeyMap {
+    buttons: [Vec<Input>; NUM_KEYS],
+    /// stick travel ignored around the center, out of 32767
+    deadzone: i16,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut map = Self {
+            buttons: DEFAULT_LAYOUT.map(|key| vec![Input::Key(key)]),
+            deadzone: DEFAULT_DEADZONE,
+        };
+        for (input, idx) in DEFAULT_PAD {
+            map.assign(input, idx);
+        }
+        map
+    }
+}
+
+impl KeyMap {
+    /// Default layout with a key map file applied for the ROM with the given SHA-1
+    pub fn parse(text: &str, sha1: &str) -> Result<Self, String> {
+        let table: Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        let mut map = Self::default();
+        for (name, value) in table.iter() {
+            match (name.as_str(), value) {
+                ("pad", Value::Table(pad)) => match pad.get("deadzone") {
+                    Some(Value::Float(f)) if (0.0..1.0).contains(f) => map.deadzone = (f * i16::MAX as f64) as i16,
+                    None => (),
+                    _ => return Err("[pad] invalid deadzone".to_string()),
+                },
+                ("default", Value::Table(buttons)) => map.apply(buttons).map_err(|e| format!("[default] {}", e))?,
+                ("rom", Value::Table(roms)) => {
+                    for (rom, buttons) in roms.iter() {
+                        let Value::Table(buttons) = buttons else {
+                            return Err(format!("[rom.{}] is not a table", rom));
+                        };
+                        if rom.eq_ignore_ascii_case(sha1) {
+                            map.apply(buttons).map_err(|e| format!("[rom.{}] {}", rom, e))?;
+                        }
+                    }
+                },
+                _ => return Err(format!("unknown section [{}]", name)),
+            }
+        }
+        Ok(map)
+    }
+
+    /// Replaces the keys of the buttons listed in a table
+    fn apply(&mut self, buttons: &Table) -> Result<(), String> {
+        for (button, keys) in buttons.iter() {
+            let idx = match usize::from_str_radix(button, 16) {
+                Ok(idx) if idx < NUM_KEYS => idx,
+                _ => return Err(format!("unknown button {}", button)),
+            };
+            let names = match keys {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names
+                    .iter()
+                    .map(|name| name.as_str().ok_or_else(|| format!("invalid keys for button {}", button)))
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(format!("invalid keys for button {}", button)),
+            };
+            self.buttons[idx].clear();
+            for name in names {
+                let input = Input::from_name(name).ok_or_else(|| format!("unknown key '{}'", name))?;
+                self.bind(input, idx)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes an input press a button, taking it away from any other button
+    /// Fails for the emulator's hotkeys, which never reach the keypad
+    pub fn bind(&mut self, input: Input, idx: usize) -> Result<(), String> {
+        if let Input::Key(key) = input {
+            if HOTKEYS.contains(&key) {
+                return Err(format!("key '{}' is an emulator hotkey", key.name()));
+            }
+        }
+        self.assign(input, idx);
+        Ok(())
+    }
+
+    fn assign(&mut self, input: Input, idx: usize) {
+        for inputs in self.buttons.iter_mut() {
+            inputs.retain(|i| *i != input);
+        }
+        self.buttons[idx].push(input);
+    }
+
+    /// Keypad index for a host input
+    pub fn lookup(&self, input: Input) -> Option<usize> {
+        self.buttons.iter().position(|inputs| inputs.contains(&input))
+    }
+
+    pub fn deadzone(&self) -> i16 {
+        self.deadzone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "6f6509f38220e057a7e32ebb22dd353c1078e3e7";
+
+    #[test]
+    fn map_file_rebinds_buttons() {
+        let text = format!(
+            "[default]\n1 = [\"Y\", \"pad:a\"]\n4 = \"Keypad 7\"\n\n\
+             [rom.{}]\n5 = [\"Space\", \"pad:lefty-\"]\n\n\
+             [rom.0000000000000000000000000000000000000000]\n6 = \"U\"\n\n\
+             [pad]\ndeadzone = 0.5\n",
+            SHA1.to_uppercase()
+        );
+        let map = KeyMap::parse(&text, SHA1).unwrap();
+        assert_eq!(map.lookup(Input::Key(Keycode::Y)), Some(0x1));
+        assert_eq!(map.lookup(Input::Key(Keycode::Num1)), None);
+        assert_eq!(map.lookup(Input::Key(Keycode::Kp7)), Some(0x4));
+        assert_eq!(map.lookup(Input::Key(Keycode::Space)), Some(0x5));
+        assert_eq!(map.lookup(Input::Axis(Axis::LeftY, false)), Some(0x5));
+        assert_eq!(map.lookup(Input::Key(Keycode::W)), None);
+        // Moved from button 6, and the other ROM's table is not applied
+        assert_eq!(map.lookup(Input::Button(Button::A)), Some(0x1));
+        assert_eq!(map.lookup(Input::Key(Keycode::U)), None);
+        // Buttons the file does not mention keep their keys
+        assert_eq!(map.lookup(Input::Key(Keycode::V)), Some(0xF));
+        assert_eq!(map.deadzone(), i16::MAX / 2);
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        let err = |text: &str| KeyMap::parse(text, SHA1).unwrap_err();
+        assert_eq!(err("[default]\n1 = \"NoSuchKey\""), "[default] unknown key 'NoSuchKey'");
+        assert_eq!(err("[default]\n1 = \"pad:nosuchbutton\""), "[default] unknown key 'pad:nosuchbutton'");
+        assert_eq!(err("[default]\n10 = \"Y\""), "[default] unknown button 10");
+        assert_eq!(err("[default]\n1 = 3"), "[default] invalid keys for button 1");
+        assert_eq!(err("[keys]\n1 = \"Y\""), "unknown section [keys]");
+        assert_eq!(err("[pad]\ndeadzone = 2.0"), "[pad] invalid deadzone");
+    }
+
+    #[test]
+    fn hotkeys_cannot_be_bound() {
+        let text = format!("[rom.{}]\n5 = [\"Space\", \"Escape\"]", SHA1);
+        assert_eq!(KeyMap::parse(&text, SHA1).unwrap_err(), format!("[rom.{}] key 'Escape' is an emulator hotkey", SHA1));
+        let mut map = KeyMap::default();
+        assert!(map.bind(Input::Key(Keycode::P), 0x5).is_err());
+        assert_eq!(map.lookup(Input::Key(Keycode::P)), None);
+        assert!(map.bind(Input::Key(Keycode::Space), 0x5).is_ok());
+        assert_eq!(map.lookup(Input::Key(Keycode::Space)), Some(0x5));
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chip8_core::cartridge::Cartridge;
+use chip8_core::romdb::RomDb;
+use chip8_core::MAX_ROM_SIZE;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::EventPump;
+
+use crate::font::{draw_text, text_width, GLYPH_HEIGHT};
+
+/// Host pixels per font pixel
+const TEXT_SCALE: u32 = 3;
+/// Host pixels between the window's edge and the text
+const MARGIN: i32 = 12;
+/// Extensions of plain ROM files, files without an extension are taken as ROMs too
+const ROM_EXTENSIONS: [&str; 2] = ["ch8", "c8"];
+/// Extension of Octo cartridges
+const CARTRIDGE_EXTENSION: &str = "gif";
+
+const BACKGROUND: Color = Color::RGB(0, 0, 0);
+const TEXT: Color = Color::RGB(255, 255, 255);
+const DIM: Color = Color::RGB(128, 128, 128);
+
+/// A ROM that can be picked
+struct Entry {
+    path: String,
+    /// title from the ROM database, the file name otherwise
+    title: String,
+}
+
+/*
+    Menu listing the ROMs of a directory, shown when the emulator is started
+    without a ROM. Up/Down/PageUp/PageDown/Home/End move, Enter starts the
+    game and Esc quits.
+*/
+pub struct Launcher {
+    entries: Vec<Entry>,
+    selected: usize,
+}
+
+impl Launcher {
+    /// Lists the ROMs and cartridges in dir, sorted by title. Files that are
+    /// neither, such as patches and symbol files, are left out
+    pub fn scan(dir: &Path, db: &RomDb) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let path = match file {
+                Ok(file) => file.path(),
+                Err(e) => {
+                    println!("Skipping an entry of {}: {}", dir.display(), e);
+                    continue;
+                },
+            };
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+            if !path.is_file() || name.starts_with('.') {
+                continue;
+            }
+            let extension = path.extension().and_then(|e| e.to_str()).map(str::to_lowercase);
+            let cartridge = match extension.as_deref() {
+                None => false,
+                Some(ext) if ROM_EXTENSIONS.contains(&ext) => false,
+                Some(CARTRIDGE_EXTENSION) => true,
+                Some(_) => continue,
+            };
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("Skipping {}: {}", path.display(), e);
+                    continue;
+                },
+            };
+            let listed = if cartridge { Cartridge::is_cartridge(&data) } else { data.len() <= MAX_ROM_SIZE };
+            if !listed {
+                continue;
+            }
+            let title = match db.lookup(&data) {
+                Some(info) if !info.title.is_empty() => info.title.clone(),
+                _ => name,
+            };
+            entries.push(Entry { path: path.to_string_lossy().into_owned(), title });
+        }
+        entries.sort_by_key(|e| e.title.to_lowercase());
+        Ok(Self { entries, selected: 0 })
+    }
+
+    /// Shows the menu until a ROM is picked, None if the user quits
+    pub fn choose(&mut self, canvas: &mut Canvas<Window>, event_pump: &mut EventPump) -> Option<String> {
+        canvas.window_mut().set_title("Chip-8 Emulator").unwrap();
+        loop {
+            let page = self.visible_lines(canvas).max(1);
+            let last = self.entries.len().saturating_sub(1);
+            for evt in event_pump.poll_iter() {
+                match evt {
+                    Event::Quit { .. } | Event::KeyDown{keycode: Some(Keycode::Escape), repeat: false, ..} => return None,
+                    Event::KeyDown{keycode: Some(Keycode::Up), ..} => self.selected = self.selected.saturating_sub(1),
+                    Event::KeyDown{keycode: Some(Keycode::Down), ..} => self.selected = (self.selected + 1).min(last),
+                    Event::KeyDown{keycode: Some(Keycode::PageUp), ..} => self.selected = self.selected.saturating_sub(page),
+                    Event::KeyDown{keycode: Some(Keycode::PageDown), ..} => self.selected = (self.selected + page).min(last),
+                    Event::KeyDown{keycode: Some(Keycode::Home), ..} => self.selected = 0,
+                    Event::KeyDown{keycode: Some(Keycode::End), ..} => self.selected = last,
+                    Event::KeyDown{keycode: Some(Keycode::Return | Keycode::KpEnter), repeat: false, ..} => {
+                        if let Some(entry) = self.entries.get(self.selected) {
+                            return Some(entry.path.clone());
+                        }
+                    },
+                    _ => (),
+                }
+            }
+            self.draw(canvas, page);
+        }
+    }
+
+    /// Number of entries that fit below the heading
+    fn visible_lines(&self, canvas: &Canvas<Window>) -> usize {
+        let (_, height) = canvas.output_size().unwrap();
+        let usable = height as i32 - 2 * MARGIN - line_height() as i32 * 2;
+        (usable.max(0) as u32 / line_height()) as usize
+    }
+
+    fn draw(&self, canvas: &mut Canvas<Window>, page: usize) {
+        canvas.set_draw_color(BACKGROUND);
+        canvas.clear();
+
+        let line = line_height() as i32;
+        let heading = format!("CHIP-8 ROMS ({})", self.entries.len());
+        draw_text(canvas, &heading, MARGIN, MARGIN, TEXT_SCALE, DIM);
+        if self.entries.is_empty() {
+            draw_text(canvas, "NO ROMS FOUND", MARGIN, MARGIN + 2 * line, TEXT_SCALE, TEXT);
+        }
+
+        // Scroll so the selected entry stays on screen
+        let first = (self.selected + 1).saturating_sub(page);
+        let (width, _) = canvas.output_size().unwrap();
+        for (row, entry) in self.entries.iter().enumerate().skip(first).take(page) {
+            let y = MARGIN + (2 + (row - first) as i32) * line;
+            if row == self.selected {
+                let text = format!("> {}", entry.title);
+                canvas.set_draw_color(DIM);
+                let highlight = (width as i32 - 2 * MARGIN).max(text_width(&text, TEXT_SCALE) as i32);
+                canvas.fill_rect(Rect::new(MARGIN - 4, y - 4, highlight as u32 + 8, line as u32)).unwrap();
+                draw_text(canvas, &text, MARGIN, y, TEXT_SCALE, TEXT);
+            } else {
+                draw_text(canvas, &format!("  {}", entry.title), MARGIN, y, TEXT_SCALE, TEXT);
+            }
+        }
+        canvas.present();
+    }
+}
+
+/// Host pixels from one line of text to the next
+fn line_height() -> u32 {
+    (GLYPH_HEIGHT + 3) * TEXT_SCALE
+}
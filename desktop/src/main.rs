@@ -1,37 +1,224 @@
+mod config;
+mod debugview;
+mod font;
+mod gamepad;
+mod keymap;
+mod launcher;
+mod octo;
+mod palette;
+mod speed;
+mod viewport;
+mod watch;
+
 use chip8_core::*;
+use chip8_core::audio::{Buzzer, WavWriter, DEFAULT_SAMPLE_RATE};
+use chip8_core::cartridge::Cartridge;
+use chip8_core::cheats::Freezer;
+use chip8_core::coverage::Coverage;
+use chip8_core::debugger::Debugger;
+use chip8_core::image::Image;
+use chip8_core::movie::{Movie, MoviePlayer, MovieRecorder, Verdict};
+use chip8_core::patch;
+use chip8_core::phosphor::{Phosphor, PhosphorMode};
+use chip8_core::profiler::Profiler;
+use chip8_core::quirks::Quirks;
+use chip8_core::reload::Preserve;
+use chip8_core::trace::{TraceFilter, TraceFormat, Tracer};
+use chip8_core::video::{VideoFormat, VideoRecorder};
+use debugview::{DebugView, PANEL_WIDTH};
+use gamepad::Gamepads;
+use keymap::Input;
+use launcher::Launcher;
+use palette::{Palette, DEFAULT_PALETTE};
+use speed::{Speed, DEFAULT_FF_MULTIPLIER, DEFAULT_TICKS_PER_FRAME};
+use viewport::Viewport;
+use watch::Watcher;
 
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::BufWriter;
 use std::env;
+use std::path::Path;
 
 use sdl2::event::Event;
+use sdl2::EventPump;
+use sdl2::Sdl;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
-use sdl2::rect::Rect;
 use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::video::{FullscreenType, Window};
+
+/// Host pixels per CHIP-8 pixel when the window opens
+const DEFAULT_SCALE: u32 = 15;
+/// Host pixels per CHIP-8 pixel in video recordings
+const VIDEO_SCALE: u32 = 4;
+/// Directory the launcher lists when started without a ROM
+const DEFAULT_ROM_DIR: &str = "games";
+
+const USAGE: &str = "Usage: cargo run [--ff multiplier] [--ticks ticks_per_frame] \
+                     [--record movie | --play movie] [--trace file] [--profile file] \
+                     [--coverage file] [--quirks profile|list] [--romdb file] \
+                     [--keys file] [--cheats file] [--palette name] [--grid] \
+                     [--phosphor off|decay[:F]|or[:N]] [--scale n] [--fractional] \
+                     [--video-format gif|y4m|ppm] [--wav file] \
+                     [--roms dir] [--watch [--keep-regs] [--keep-ram first-last (hex)]] [path/to/game|cartridge.gif]";
+
+/// Command line options for the desktop frontend
+struct Options {
+    /// ROM to run, the launcher is shown if there is none
+    rom: Option<String>,
+    /// directory the launcher lists ROMs from
+    roms: Option<String>,
+    ff_multiplier: usize,
+    /// overrides the ROM database's speed
+    ticks_per_frame: Option<usize>,
+    /// overrides the ROM database's quirks
+    quirks: Option<Quirks>,
+    /// ROM database override file
+    romdb: Option<String>,
+    /// key mapping file
+    keys: Option<String>,
+    /// cheat list file, the ROM's cheats in it are all enabled
+    cheats: Option<String>,
+    /// overrides the ROM database's colors
+    palette: Option<String>,
+    /// start with lines between the pixels
+    grid: bool,
+    /// how frames are blended to hide flicker
+    phosphor: Phosphor,
+    /// host pixels per CHIP-8 pixel when the window opens
+    scale: u32,
+    /// start scaling the display to fill the window instead of by whole pixels
+    fractional: bool,
+    /// what F9 records video to
+    video_format: VideoFormat,
+    /// file to write the buzzer's audio to
+    wav: Option<String>,
+    /// reload the ROM whenever the file changes
+    watch: bool,
+    /// registers and RAM kept when reloading
+    preserve: Preserve,
+    /// file to write a movie of the session to
+    record: Option<String>,
+    /// movie file to replay instead of reading the keyboard
+    play: Option<String>,
+    /// file to write a text trace of every executed instruction to
+    trace: Option<String>,
+    /// file to write a profiler report to when quitting
+    profile: Option<String>,
+    /// file to write an annotated disassembly with coverage flags to when quitting
+    coverage: Option<String>,
+}
 
-const SCALE: u32 = 15;
-const WINDOW_WIDTH: u32 = (SCREEN_WIDTH as u32) * SCALE;
-const WINDOW_HEIGHT: u32 = (SCREEN_HEIGHT as u32) * SCALE;
+/// Parses the command line, returns None if it is malformed
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut rom = None;
+    let mut roms = None;
+    let mut ff_multiplier = DEFAULT_FF_MULTIPLIER;
+    let mut ticks_per_frame = None;
+    let mut quirks = None;
+    let mut romdb = None;
+    let mut keys = None;
+    let mut cheats = None;
+    let mut palette = None;
+    let mut grid = false;
+    let mut phosphor = Phosphor::new(PhosphorMode::default());
+    let mut scale = DEFAULT_SCALE;
+    let mut fractional = false;
+    let mut video_format = VideoFormat::Gif;
+    let mut wav = None;
+    let mut watch = false;
+    let mut preserve = Preserve::default();
+    let mut record = None;
+    let mut play = None;
+    let mut trace = None;
+    let mut profile = None;
+    let mut coverage = None;
 
-const TICKS_PER_FRAME: usize = 10;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--ff" => ff_multiplier = iter.next()?.parse().ok()?,
+            "--ticks" => ticks_per_frame = Some(iter.next()?.parse().ok()?),
+            "--quirks" => quirks = Some(Quirks::parse(iter.next()?).ok()?),
+            "--romdb" => romdb = Some(iter.next()?.clone()),
+            "--keys" => keys = Some(iter.next()?.clone()),
+            "--cheats" => cheats = Some(iter.next()?.clone()),
+            "--palette" => palette = Some(iter.next()?.clone()),
+            "--grid" => grid = true,
+            "--phosphor" => phosphor = Phosphor::parse(iter.next()?).ok()?,
+            "--scale" => scale = iter.next()?.parse().ok().filter(|s| *s > 0)?,
+            "--fractional" => fractional = true,
+            "--wav" => wav = Some(iter.next()?.clone()),
+            "--watch" => watch = true,
+            "--keep-regs" => preserve.registers = true,
+            "--keep-ram" => preserve.add_ram(iter.next()?).ok()?,
+            "--video-format" => {
+                video_format = match iter.next()?.as_str() {
+                    "gif" => VideoFormat::Gif,
+                    "y4m" => VideoFormat::Y4m,
+                    "ppm" => VideoFormat::Ppm,
+                    _ => return None,
+                }
+            },
+            "--roms" => roms = Some(iter.next()?.clone()),
+            "--record" => record = Some(iter.next()?.clone()),
+            "--play" => play = Some(iter.next()?.clone()),
+            "--trace" => trace = Some(iter.next()?.clone()),
+            "--profile" => profile = Some(iter.next()?.clone()),
+            "--coverage" => coverage = Some(iter.next()?.clone()),
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+
+    if record.is_some() && play.is_some() {
+        return None;
+    }
+
+    Some(Options {
+        rom,
+        roms,
+        ff_multiplier,
+        ticks_per_frame,
+        quirks,
+        romdb,
+        keys,
+        cheats,
+        palette,
+        grid,
+        phosphor,
+        scale,
+        fractional,
+        video_format,
+        wav,
+        watch,
+        preserve,
+        record,
+        play,
+        trace,
+        profile,
+        coverage,
+    })
+}
 
 fn main() {
     let args: Vec<_> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: cargo run path/to/game");
+    let Some(options) = parse_args(&args) else {
+        println!("{}", USAGE);
         return;
-    }
+    };
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("Chip-8 Emulator", WINDOW_WIDTH, WINDOW_HEIGHT)
+    let (width, height) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    let mut window = video_subsystem
+        .window("Chip-8 Emulator", width * options.scale, height * options.scale)
         .position_centered()
+        .resizable()
         .opengl()
         .build()
         .unwrap();
+    window.set_minimum_size(width, height).unwrap();
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     canvas.clear();
@@ -39,86 +226,526 @@ fn main() {
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
+    if let Some(rom) = options.rom.as_ref() {
+        play(&options, rom, &sdl_context, &mut canvas, &mut event_pump);
+        return;
+    }
+
+    // Without a ROM, pick one from the launcher and come back to it after every game
+    let dir = options.roms.as_deref().unwrap_or(DEFAULT_ROM_DIR);
+    let db = config::load_romdb(options.romdb.as_deref());
+    let mut launcher = match Launcher::scan(Path::new(dir), &db) {
+        Ok(launcher) => launcher,
+        Err(e) => {
+            println!("Unable to read ROM directory {}: {}", dir, e);
+            return;
+        },
+    };
+    while let Some(rom) = launcher.choose(&mut canvas, &mut event_pump) {
+        if play(&options, &rom, &sdl_context, &mut canvas, &mut event_pump) == Exit::Quit {
+            break;
+        }
+    }
+}
+
+/// Reads a ROM, Octo cartridges carry source and settings instead so they are assembled first.
+/// A .bps or .ips patch next to the file is applied to the result.
+fn read_rom(path: &str) -> Result<(Vec<u8>, Option<Cartridge>), String> {
+    let data = fs::read(path).map_err(|e| format!("Unable to open file 💀 {}: {}", path, e))?;
+    let (rom, cart) = if Cartridge::is_cartridge(&data) {
+        let cart = Cartridge::decode(&data).map_err(|e| format!("Invalid cartridge {}: {}", path, e))?;
+        (octo::assemble(&cart.program)?, Some(cart))
+    } else {
+        (data, None)
+    };
+
+    let Some(patch_path) = patch::find_patch(Path::new(path)) else {
+        return Ok((rom, cart));
+    };
+    let patch = fs::read(&patch_path).map_err(|e| format!("Unable to open patch {}: {}", patch_path.display(), e))?;
+    let rom = patch::apply(&rom, &patch).map_err(|e| format!("Invalid patch {}: {}", patch_path.display(), e))?;
+    println!("Applied patch {}", patch_path.display());
+    Ok((rom, cart))
+}
+
+/// How a game ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Exit {
+    /// the window was closed
+    Quit,
+    /// Esc was pressed, or the game could not be started
+    Menu,
+}
+
+/// Runs a ROM or cartridge until the user quits or goes back to the launcher
+fn play(options: &Options, rom_path: &str, sdl_context: &Sdl, canvas: &mut Canvas<Window>, event_pump: &mut EventPump) -> Exit {
     let mut chip8 = Emu::new();
 
-    let mut rom = File::open(&args[1]).expect("Unable to open file 💀");
-    let mut buffer = Vec::new();
+    let (mut buffer, cartridge) = match read_rom(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            println!("{}", e);
+            return Exit::Menu;
+        },
+    };
+    if let Err(e) = chip8.load(&buffer) {
+        println!("{}: {}", rom_path, e);
+        return Exit::Menu;
+    }
 
-    rom.read_to_end(&mut buffer).unwrap();
-    chip8.load(&buffer);
+    // Pick quirks, speed, keys and colors for known games, the cartridge
+    // settings come next and the command line wins
+    let mut info = config::load_romdb(options.romdb.as_deref()).lookup(&buffer).cloned().unwrap_or_default();
+    if let Some(cart) = cartridge.as_ref() {
+        cart.options.apply_to(&mut info);
+    }
+    let rotation = cartridge.as_ref().map(|c| c.options.screen_rotation).unwrap_or(0);
+    if !info.title.is_empty() {
+        println!("{}{}", info.title, info.author.as_ref().map(|a| format!(" by {}", a)).unwrap_or_default());
+    }
+    chip8.set_quirks(options.quirks.unwrap_or(info.quirks));
+    let ticks_per_frame = options.ticks_per_frame.or(info.ticks_per_frame).unwrap_or(DEFAULT_TICKS_PER_FRAME);
+    let mut speed = Speed::new(ticks_per_frame, options.ff_multiplier);
+    // Keys from the ROM database are added on top of the key mapping file
+    let mut keymap = config::load_keymap(options.keys.as_deref(), &buffer);
+    for (name, idx) in info.keys.iter() {
+        let bound = Input::from_name(name).ok_or_else(|| format!("unknown key '{}'", name)).and_then(|input| keymap.bind(input, *idx));
+        if let Err(e) = bound {
+            println!("Ignoring ROM database key: {}", e);
+        }
+    }
+
+    // The ROM's own colors join the palettes that can be cycled through
+    let mut palettes = config::load_palettes();
+    let rom_palette = cartridge
+        .as_ref()
+        .and_then(|cart| Palette::from_cartridge("rom", &cart.options))
+        .or_else(|| info.colors.map(|(fg, bg)| Palette::two_color("rom", fg, bg)));
+    let start_palette = match (options.palette.as_deref(), rom_palette.is_some()) {
+        (Some(name), _) => name,
+        (None, true) => "rom",
+        (None, false) => DEFAULT_PALETTE,
+    };
+    if let Some(palette) = rom_palette {
+        palettes.add(palette);
+    }
+    if !palettes.select(start_palette) {
+        println!("Unknown palette {}", start_palette);
+        return Exit::Menu;
+    }
+    let mut look = Look { rotation, grid: options.grid, integer_scaling: !options.fractional };
+    let mut phosphor = options.phosphor.clone();
+    phosphor.push(chip8.get_display());
+    let game_title = if info.title.is_empty() { "Chip-8 Emulator".to_string() } else { info.title.clone() };
+
+    // Rotated screens turn the window on its side
+    let (width, height) = look.display_size();
+    let window = canvas.window_mut();
+    let (win_width, win_height) = window.size();
+    window.set_minimum_size(width, height).unwrap();
+    if (width > height) != (win_width > win_height) {
+        window.set_size(win_height, win_width).unwrap();
+    }
 
+    // Controllers are optional, the keyboard still works without them
+    let mut gamepads = sdl_context.game_controller().ok().map(|subsystem| Gamepads::new(subsystem, keymap.deadzone()));
+
+    let mut freezer = Freezer::new();
+    for cheat in config::load_cheats(options.cheats.as_deref(), &buffer) {
+        println!("Cheat: {}", cheat.name);
+        freezer.enable(&cheat);
+    }
+
+    // Movies carry the cheats that were on, playback uses those instead of the cheat list
+    let mut recorder = options.record.as_ref().map(|_| {
+        speed.lock_ticks();
+        MovieRecorder::new(&chip8, &buffer, speed.ticks_per_frame(), &freezer)
+    });
+
+    let mut player = options.play.as_ref().map(|path| {
+        let text = fs::read_to_string(path).expect("Unable to open movie");
+        let movie = Movie::parse(&text).unwrap_or_else(|e| panic!("Invalid movie {}: {}", path, e));
+        if !movie.matches_rom(&buffer) {
+            println!("Warning: movie was recorded on a different ROM");
+        }
+        speed = Speed::new(movie.ticks_per_frame, options.ff_multiplier);
+        speed.lock_ticks();
+        if movie.freezer != freezer {
+            println!("Playing with the cheats the movie was recorded with");
+        }
+        freezer = movie.freezer.clone();
+        MoviePlayer::new(movie, &mut chip8)
+    });
+
+    if let Some(path) = options.trace.as_ref() {
+        let file = File::create(path).expect("Unable to create trace file");
+        let tracer = Tracer::new(Box::new(BufWriter::new(file)), TraceFormat::Text, TraceFilter::default());
+        chip8.set_tracer(Some(tracer));
+    }
+
+    if options.profile.is_some() {
+        chip8.set_profiler(Some(Profiler::new()));
+    }
+
+    if options.coverage.is_some() {
+        chip8.set_coverage(Some(Coverage::new()));
+    }
+
+    let mut video: Option<(VideoRecorder, String)> = None;
+
+    // Frames run through the debugger so breakpoints can stop them halfway
+    let mut debugger = Debugger::new(speed.ticks_per_frame());
+    let mut debug_view = DebugView::new();
+    let mut panel_shown = false;
+    // instructions to single-step before the next draw
+    let mut steps = 0;
+
+    let mut buzzer = Buzzer::new(DEFAULT_SAMPLE_RATE);
+    let mut wav = options.wav.as_ref().map(|path| {
+        let file = File::create(path).expect("Unable to create WAV file");
+        WavWriter::new(BufWriter::new(file), buzzer.sample_rate()).expect("Unable to write WAV file")
+    });
+
+    // New builds of the ROM are picked up while playing, but reloading would break a movie
+    let mut watcher = (options.watch && recorder.is_none() && player.is_none()).then(|| Watcher::new(rom_path));
+
+    let mut exit = Exit::Quit;
     'gameloop: loop {
         for evt in event_pump.poll_iter() {
+            let pad_inputs = gamepads.as_mut().map(|pads| pads.handle(&evt)).unwrap_or_default();
+            if player.is_none() {
+                for (input, pressed) in pad_inputs {
+                    if let Some(k) = keymap.lookup(input) {
+                        press_button(&mut chip8, recorder.as_mut(), k, pressed);
+                    }
+                }
+            }
+
             match evt {
-                Event::Quit { .. } | Event::KeyDown{keycode: Some(Keycode::Escape), ..} => {
+                Event::Quit { .. } => break 'gameloop,
+                // Hotkeys, keep keymap::HOTKEYS in sync so no keypad button is bound to one
+                Event::KeyDown{keycode: Some(Keycode::Escape), ..} => {
+                    exit = Exit::Menu;
                     break 'gameloop;
                 },
-                Event::KeyDown{keycode: Some(key), ..} => {
-                    if let Some(k) = key2btn(key) {
-                        chip8.keypress(k, true);
+                Event::KeyDown{keycode: Some(Keycode::P | Keycode::F5), repeat: false, ..} => {
+                    speed.toggle_pause();
+                    if !speed.paused() {
+                        debug_view.resumed();
+                    }
+                },
+                Event::KeyDown{keycode: Some(Keycode::F6), ..} => {
+                    speed.pause();
+                    steps += 1;
+                },
+                Event::KeyDown{keycode: Some(Keycode::F1), repeat: false, ..} => {
+                    debug_view.set_visible(!debug_view.visible());
+                },
+                Event::KeyDown{keycode: Some(Keycode::N), ..} => speed.frame_advance(),
+                Event::KeyDown{keycode: Some(Keycode::Tab), ..} => speed.set_fast_forward(true),
+                Event::KeyUp{keycode: Some(Keycode::Tab), ..} => speed.set_fast_forward(false),
+                Event::KeyDown{keycode: Some(Keycode::M), repeat: false, ..} => speed.toggle_slow_motion(),
+                Event::KeyDown{keycode: Some(Keycode::Equals), ..} => speed.increase_ticks(),
+                Event::KeyDown{keycode: Some(Keycode::Minus), ..} => speed.decrease_ticks(),
+                Event::KeyDown{keycode: Some(Keycode::K), repeat: false, ..} => {
+                    println!("Palette: {}", palettes.cycle().name);
+                },
+                Event::KeyDown{keycode: Some(Keycode::G), repeat: false, ..} => look.grid = !look.grid,
+                Event::KeyDown{keycode: Some(Keycode::I), repeat: false, ..} => {
+                    look.integer_scaling = !look.integer_scaling;
+                },
+                Event::KeyDown{keycode: Some(Keycode::F12), repeat: false, ..} => {
+                    let palette = palettes.current();
+                    let image = Image::from_levels(phosphor.levels(), options.scale, palette.foreground(), palette.background());
+                    save_screenshot(&image, rom_path);
+                },
+                Event::KeyDown{keycode: Some(Keycode::F9), repeat: false, ..} => match video.take() {
+                    Some((recorder, path)) => finish_video(recorder, &path),
+                    None => video = start_video(rom_path, options.video_format),
+                },
+                Event::KeyDown{keycode: Some(Keycode::F11), repeat: false, ..} => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off,
+                    };
+                    window.set_fullscreen(fullscreen).unwrap();
+                },
+                Event::KeyDown{keycode: Some(Keycode::H), repeat: false, ..} => {
+                    phosphor.set_mode(phosphor.mode().next());
+                    phosphor.push(chip8.get_display());
+                    println!("Phosphor: {}", phosphor.mode().name());
+                },
+                Event::KeyDown{keycode: Some(key), ..} if speed.paused() && debug_view.handle_key(key, &chip8, &mut debugger) => (),
+                Event::KeyDown{keycode: Some(key), ..} if player.is_none() => {
+                    if let Some(k) = keymap.lookup(Input::Key(key)) {
+                        press_button(&mut chip8, recorder.as_mut(), k, true);
                     }
                 },
-                Event::KeyUp{keycode: Some(key), ..} => {
-                    if let Some(k) = key2btn(key) {
-                        chip8.keypress(k, false);
+                Event::KeyUp{keycode: Some(key), ..} if player.is_none() => {
+                    if let Some(k) = keymap.lookup(Input::Key(key)) {
+                        press_button(&mut chip8, recorder.as_mut(), k, false);
                     }
                 },
                 _ => ()
             }
         }
 
-        for _ in 0..TICKS_PER_FRAME {
-            chip8.tick();
+        if watcher.as_mut().is_some_and(|w| w.changed()) {
+            match read_rom(rom_path) {
+                Ok((rom, _)) => match chip8.reload(&rom, &options.preserve) {
+                    Ok(()) => {
+                        phosphor.push(chip8.get_display());
+                        buffer = rom;
+                        println!("Reloaded {}", rom_path);
+                    },
+                    Err(e) => println!("Not reloading {}: {}", rom_path, e),
+                },
+                Err(e) => println!("{}", e),
+            }
+        }
+
+        for _ in 0..speed.frames_to_run() {
+            if let Some(play) = player.as_mut() {
+                if play.is_finished(&chip8) {
+                    report_playback(play.verify(&mut chip8));
+                    player = None;
+                    speed.pause();
+                    break;
+                }
+                play.apply(&mut chip8);
+            }
+            if !debugger.mid_frame() {
+                freezer.apply(&mut chip8);
+            }
+            debugger.set_ticks_per_frame(speed.ticks_per_frame());
+            if let Some(reason) = debugger.run_frame(&mut chip8) {
+                speed.pause();
+                debug_view.stopped(reason, &chip8);
+                break;
+            }
+            finish_frame(&chip8, &mut phosphor, &mut buzzer, wav.as_mut(), &mut video, palettes.current());
+        }
+
+        for _ in 0..std::mem::take(&mut steps) {
+            if let Some(play) = player.as_mut() {
+                play.apply(&mut chip8);
+            }
+            if !debugger.mid_frame() {
+                freezer.apply(&mut chip8);
+            }
+            let reason = debugger.step(&mut chip8);
+            debug_view.stopped(reason, &chip8);
+            if !debugger.mid_frame() {
+                finish_frame(&chip8, &mut phosphor, &mut buzzer, wav.as_mut(), &mut video, palettes.current());
+            }
+        }
+
+        if debug_view.visible() != panel_shown {
+            panel_shown = debug_view.visible();
+            fit_panel(canvas, panel_shown);
+        }
+
+        let title = speed.title(&game_title);
+        if canvas.window().title() != title {
+            canvas.window_mut().set_title(&title).unwrap();
+        }
+        let (width, height) = canvas.output_size().unwrap();
+        let game_width = if panel_shown { width.saturating_sub(PANEL_WIDTH) } else { width };
+        draw_screen(phosphor.levels(), canvas, palettes.current(), &look, (game_width, height));
+        if panel_shown {
+            debug_view.draw(canvas, &chip8, &debugger, speed.paused());
+        }
+        canvas.present();
+    }
+
+    if panel_shown {
+        fit_panel(canvas, false);
+    }
+
+    if let Some((recorder, path)) = video {
+        finish_video(recorder, &path);
+    }
+
+    if let Some(wav) = wav {
+        wav.finish().expect("Unable to write WAV file");
+    }
+
+    if let Some(tracer) = chip8.set_tracer(None) {
+        tracer.finish().expect("Unable to write trace");
+    }
+
+    if let (Some(profiler), Some(path)) = (chip8.set_profiler(None), options.profile.as_ref()) {
+        fs::write(path, profiler.report(chip8.memory())).expect("Unable to write profile");
+    }
+
+    if let (Some(coverage), Some(path)) = (chip8.set_coverage(None), options.coverage.as_ref()) {
+        fs::write(path, coverage.annotated(chip8.memory(), buffer.len())).expect("Unable to write coverage");
+    }
+
+    if let (Some(rec), Some(path)) = (recorder, options.record.as_ref()) {
+        let movie = rec.finish(&chip8);
+        fs::write(path, movie.to_string()).expect("Unable to write movie");
+        println!("Recorded {} frames to {}", movie.end_frame, path);
+    }
+    exit
+}
+
+/// Feeds a completed frame to the phosphor effect and the audio and video being recorded
+fn finish_frame(
+    chip8: &Emu,
+    phosphor: &mut Phosphor,
+    buzzer: &mut Buzzer,
+    wav: Option<&mut WavWriter<BufWriter<File>>>,
+    video: &mut Option<(VideoRecorder, String)>,
+    palette: &Palette,
+) {
+    phosphor.push(chip8.get_display());
+    if let Some(wav) = wav {
+        wav.write_samples(&buzzer.frame(chip8.buzzer())).expect("Unable to write WAV file");
+    }
+    if let Some((recorder, path)) = video.as_mut() {
+        let image = Image::from_levels(phosphor.levels(), VIDEO_SCALE, palette.foreground(), palette.background());
+        if let Err(e) = recorder.add_frame(&image) {
+            println!("Unable to write video {}: {}", path, e);
+            *video = None;
         }
-        
-        chip8.tick_timers();
-        draw_screen(&chip8, &mut canvas);
     }
-    
 }
 
-fn draw_screen(emu: &Emu, canvas: &mut Canvas<Window>) {
-    // Set the screen to black (completely empty)
+/// Widens the window to make room for the debug panel, or narrows it back
+/// A fullscreen window keeps its size and the game screen shrinks instead
+fn fit_panel(canvas: &mut Canvas<Window>, shown: bool) {
+    let window = canvas.window_mut();
+    if window.fullscreen_state() != FullscreenType::Off {
+        return;
+    }
+    let (width, height) = window.size();
+    let width = if shown { width + PANEL_WIDTH } else { width.saturating_sub(PANEL_WIDTH) };
+    window.set_size(width.max(SCREEN_WIDTH as u32), height).unwrap();
+}
+
+/// Presses or releases a keypad button, adding it to the movie being recorded
+fn press_button(emu: &mut Emu, recorder: Option<&mut MovieRecorder>, idx: usize, pressed: bool) {
+    emu.keypress(idx, pressed);
+    if let Some(rec) = recorder {
+        rec.keypress(emu, idx, pressed);
+    }
+}
+
+/// First <rom name>-<n><ext> in the working directory that does not exist yet
+fn unused_path(rom: &str, ext: &str) -> String {
+    let stem = Path::new(rom).file_stem().and_then(|s| s.to_str()).unwrap_or("chip8");
+    (1..).map(|n| format!("{}-{}{}", stem, n, ext)).find(|p| !Path::new(p).exists()).unwrap()
+}
+
+/// Writes a screenshot to the working directory as <rom name>-<n>.png
+fn save_screenshot(image: &Image, rom: &str) {
+    let path = unused_path(rom, ".png");
+    let result = File::create(&path).map_err(|e| e.to_string()).and_then(|f| image.write_png(BufWriter::new(f)));
+    match result {
+        Ok(()) => println!("Saved screenshot {}", path),
+        Err(e) => println!("Unable to write screenshot {}: {}", path, e),
+    }
+}
+
+/// Starts recording video to the working directory, ppm frames go in a <rom name>-<n> directory
+fn start_video(rom: &str, format: VideoFormat) -> Option<(VideoRecorder, String)> {
+    let ext = match format {
+        VideoFormat::Gif => ".gif",
+        VideoFormat::Y4m => ".y4m",
+        VideoFormat::Ppm => "",
+    };
+    let path = unused_path(rom, ext);
+    let (width, height) = (SCREEN_WIDTH as u32 * VIDEO_SCALE, SCREEN_HEIGHT as u32 * VIDEO_SCALE);
+    match VideoRecorder::create(&path, format, width, height) {
+        Ok(recorder) => {
+            println!("Recording video to {}", path);
+            Some((recorder, path))
+        },
+        Err(e) => {
+            println!("Unable to create video {}: {}", path, e);
+            None
+        },
+    }
+}
+
+fn finish_video(recorder: VideoRecorder, path: &str) {
+    match recorder.finish() {
+        Ok(frames) => println!("Recorded {} frames to {}", frames, path),
+        Err(e) => println!("Unable to write video {}: {}", path, e),
+    }
+}
+
+fn report_playback(verdict: Verdict) {
+    match verdict {
+        Verdict::Match => println!("Playback finished: final state matches the recording"),
+        Verdict::Mismatch { expected, actual } => println!(
+            "Playback finished: final state hash {:016x} does not match recorded {:016x}",
+            actual, expected
+        ),
+    }
+}
+
+/// How the display is drawn into the window
+struct Look {
+    /// clockwise rotation in degrees: 0, 90, 180 or 270
+    rotation: u16,
+    /// lines between the pixels
+    grid: bool,
+    /// scale by whole host pixels so every CHIP-8 pixel is the same size
+    integer_scaling: bool,
+}
+
+impl Look {
+    /// Size of the display in CHIP-8 pixels once rotated
+    fn display_size(&self) -> (u32, u32) {
+        let (w, h) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        match self.rotation {
+            90 | 270 => (h, w),
+            _ => (w, h),
+        }
+    }
+}
+
+/// Converts a 0xRRGGBB color
+fn rgb(color: u32) -> Color {
+    Color::RGB((color >> 16) as u8, (color >> 8) as u8, color as u8)
+}
+
+/// Draws pixel brightness levels with a palette, letterboxed into the left area of the window
+fn draw_screen(levels: &[u8], canvas: &mut Canvas<Window>, palette: &Palette, look: &Look, area: (u32, u32)) {
+    // Black bars around the display
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
 
-    // Get the screen buffer for emu
-    let screen_buf = emu.get_display();
+    let (width, height) = look.display_size();
+    let viewport = Viewport::fit(area, width, height, look.integer_scaling);
+
+    // Fill the display with the background (completely empty), or the grid lines
+    canvas.set_draw_color(rgb(if look.grid { palette.grid() } else { palette.background() }));
+    canvas.fill_rect(viewport.rect()).unwrap();
 
-    canvas.set_draw_color(Color::RGB(255, 255, 255));
-    for (i, pixel) in screen_buf.iter().enumerate() {
-        if *pixel {
+    for (i, level) in levels.iter().enumerate() {
+        if *level > 0 || look.grid {
             // Convert our ID array's index to a 2D array (x, y) pos
             let x = (i % SCREEN_WIDTH) as u32;
             let y = (i / SCREEN_WIDTH) as u32;
-            
-            // Draw a rectangle at (x, y). color it white and scale is by SCALE
-            let rect = Rect::new((x * SCALE) as i32, (y * SCALE) as i32, SCALE, SCALE);
+            let (w, h) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+            let (x, y) = match look.rotation {
+                90 => (h - 1 - y, x),
+                180 => (w - 1 - x, h - 1 - y),
+                270 => (y, w - 1 - x),
+                _ => (x, y),
+            };
+
+            // Draw a rectangle at (x, y), one smaller in grid mode to leave the lines
+            let mut rect = viewport.pixel(x, y);
+            if look.grid && rect.width() > 2 && rect.height() > 2 {
+                rect.resize(rect.width() - 1, rect.height() - 1);
+            }
+            canvas.set_draw_color(rgb(palette.shade(*level)));
             canvas.fill_rect(rect).unwrap();
         }
     }
-    // Update the canvas
-    canvas.present();
 }
-
-fn key2btn(key: Keycode) -> Option<usize> {
-    match key {
-        Keycode::Num1 =>    Some(0x1),
-        Keycode::Num2 =>    Some(0x2),
-        Keycode::Num3 =>    Some(0x3),
-        Keycode::Num4 =>    Some(0xC),
-        Keycode::Q =>       Some(0x4),
-        Keycode::W =>       Some(0x5),
-        Keycode::E =>       Some(0x6),
-        Keycode::R =>       Some(0xD),
-        Keycode::A =>       Some(0x7),
-        Keycode::S =>       Some(0x8),
-        Keycode::D =>       Some(0x9),
-        Keycode::F =>       Some(0xE),
-        Keycode::Z =>       Some(0xA),
-        Keycode::X =>       Some(0x0),
-        Keycode::C =>       Some(0xB),
-        Keycode::V =>       Some(0xF),
-        _ =>                None
-    }
-}
\ No newline at end of file
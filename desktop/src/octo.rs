@@ -0,0 +1,60 @@
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Command used to assemble Octo source, can be changed with the OCTO environment variable
+const DEFAULT_OCTO: &str = "octo";
+/// Attempts at finding an unused name for the temporary directory
+const TEMP_ATTEMPTS: u32 = 16;
+
+/// Private directory for the assembler's files, removed with everything in it when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a new directory only the current user can access, never reusing an existing one
+    fn create() -> io::Result<Self> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        for attempt in 0..TEMP_ATTEMPTS {
+            let path = env::temp_dir().join(format!("chip8-cartridge-{}-{:08x}-{}", process::id(), nanos, attempt));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AlreadyExists, "no unused temporary directory name"))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Assembles Octo source into ROM bytes with Octo's command line tool
+/// (`octo source.8o out.ch8`), as cartridges only contain the source.
+/// The tool is not part of this emulator, it has to be installed separately
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let octo = env::var("OCTO").unwrap_or_else(|_| DEFAULT_OCTO.to_string());
+    let dir = TempDir::create().map_err(|e| format!("Unable to create a temporary directory for {}: {}", octo, e))?;
+    let src_path = dir.0.join("cartridge.8o");
+    let rom_path = dir.0.join("cartridge.ch8");
+
+    fs::write(&src_path, source).map_err(|e| format!("Unable to write {}: {}", src_path.display(), e))?;
+    match Command::new(&octo).arg(&src_path).arg(&rom_path).status() {
+        Ok(status) if status.success() => fs::read(&rom_path).map_err(|e| format!("Unable to read assembled ROM: {}", e)),
+        Ok(status) => Err(format!("The external Octo assembler '{}' failed to assemble the cartridge ({})", octo, status)),
+        Err(e) => Err(format!(
+            "Cartridges contain Octo source which needs the external Octo assembler, unable to run '{}': {}\n\
+             Install Octo's command line tool (it is not bundled) or point the OCTO environment variable at it",
+            octo, e
+        )),
+    }
+}
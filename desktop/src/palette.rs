@@ -0,0 +1,157 @@
+use toml::{Table, Value};
+
+use chip8_core::cartridge::OctoOptions;
+use chip8_core::romdb::parse_color;
+
+/// Name of the palette used when neither the ROM nor the command line pick one
+pub const DEFAULT_PALETTE: &str = "mono";
+
+/// name, background, plane 1, plane 2, both planes
+const BUILTIN: &[(&str, [u32; 4])] = &[
+    ("mono", [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]),
+    ("green", [0x0A1A0A, 0x33FF66, 0x1E9E3E, 0x145A28]),
+    ("amber", [0x1A1000, 0xFFB000, 0xB37B00, 0x664600]),
+    ("lcd", [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F]),
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+    ("xo-pico", [0x000000, 0xFF77A8, 0x29ADFF, 0xFFEC27]),
+    ("xo-cga", [0x000000, 0xFFFFFF, 0x55FFFF, 0xFF55FF]),
+];
+
+/*
+    Colors the screen is drawn with. The core only draws one plane, the
+    other two colors are there for XO-CHIP palettes and cartridges.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub name: String,
+    /// 0xRRGGBB for background, plane 1, plane 2 and both planes
+    pub colors: [u32; 4],
+}
+
+impl Palette {
+    pub fn new(name: &str, colors: [u32; 4]) -> Self {
+        Self { name: name.to_string(), colors }
+    }
+
+    /// Palette with only a foreground and background, the other planes fade between them
+    pub fn two_color(name: &str, fg: u32, bg: u32) -> Self {
+        Self::new(name, [bg, fg, blend(fg, bg, 2, 3), blend(fg, bg, 1, 3)])
+    }
+
+    /// Colors of an Octo cartridge, None if it does not set both foreground and background
+    pub fn from_cartridge(name: &str, options: &OctoOptions) -> Option<Self> {
+        let (fg, bg) = (options.fill_color?, options.background_color?);
+        let mut palette = Self::two_color(name, fg, bg);
+        if let Some(fg2) = options.fill_color2 {
+            palette.colors[2] = fg2;
+        }
+        if let Some(both) = options.blend_color {
+            palette.colors[3] = both;
+        }
+        Some(palette)
+    }
+
+    pub fn background(&self) -> u32 {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> u32 {
+        self.colors[1]
+    }
+
+    /// Foreground faded towards the background, level 255 is the full foreground
+    pub fn shade(&self, level: u8) -> u32 {
+        blend(self.foreground(), self.background(), level as u32, 255)
+    }
+
+    /// Lines drawn between pixels in grid mode, slightly off the background
+    pub fn grid(&self) -> u32 {
+        blend(self.foreground(), self.background(), 1, 8)
+    }
+}
+
+/// Mixes num/den of a into b, per channel
+fn blend(a: u32, b: u32, num: u32, den: u32) -> u32 {
+    (0..3).fold(0, |color, channel| {
+        let shift = channel * 8;
+        let (ca, cb) = ((a >> shift) & 0xFF, (b >> shift) & 0xFF);
+        color | (((ca * num + cb * (den - num)) / den) << shift)
+    })
+}
+
+/*
+    The palettes the user can cycle through: the built-in ones followed by
+    the ones from the palette file, which looks like:
+
+        [mine]
+        colors = ["#E0E0E0", "#000000"]                        # foreground, background
+        [xo-mine]
+        colors = ["#FFFFFF", "#FF0000", "#00FF00", "#000000"]  # planes 1, 2, both, background
+
+    A palette with the name of a built-in one replaces it.
+*/
+#[derive(Clone, Debug)]
+pub struct Palettes {
+    list: Vec<Palette>,
+    current: usize,
+}
+
+impl Default for Palettes {
+    fn default() -> Self {
+        let list = BUILTIN.iter().map(|(name, colors)| Palette::new(name, *colors)).collect();
+        Self { list, current: 0 }
+    }
+}
+
+impl Palettes {
+    /// Adds the palettes of a palette file
+    pub fn load(&mut self, text: &str) -> Result<(), String> {
+        let table: Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        for (name, value) in table.iter() {
+            let invalid = || format!("[{}] invalid colors", name);
+            let colors = match value.get("colors") {
+                Some(Value::Array(colors)) => colors
+                    .iter()
+                    .map(|c| c.as_str().and_then(parse_color).ok_or_else(invalid))
+                    .collect::<Result<Vec<u32>, _>>()?,
+                _ => return Err(invalid()),
+            };
+            let palette = match *colors.as_slice() {
+                [fg, bg] => Palette::two_color(name, fg, bg),
+                [fg, fg2, both, bg] => Palette::new(name, [bg, fg, fg2, both]),
+                _ => return Err(invalid()),
+            };
+            self.add(palette);
+        }
+        Ok(())
+    }
+
+    /// Adds a palette or replaces the one with the same name
+    pub fn add(&mut self, palette: Palette) {
+        match self.list.iter().position(|p| p.name == palette.name) {
+            Some(idx) => self.list[idx] = palette,
+            None => self.list.push(palette),
+        }
+    }
+
+    /// Switches to a palette by name, returns false if there is no such palette
+    pub fn select(&mut self, name: &str) -> bool {
+        match self.list.iter().position(|p| p.name == name) {
+            Some(idx) => {
+                self.current = idx;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn current(&self) -> &Palette {
+        &self.list[self.current]
+    }
+
+    /// Switches to the next palette, wrapping around
+    pub fn cycle(&mut self) -> &Palette {
+        self.current = (self.current + 1) % self.list.len();
+        self.current()
+    }
+}
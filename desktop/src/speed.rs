@@ -0,0 +1,125 @@
+/// Default number of instructions executed per 60Hz frame
+pub const DEFAULT_TICKS_PER_FRAME: usize = 10;
+/// Default number of emulated frames run per host frame while fast-forwarding
+pub const DEFAULT_FF_MULTIPLIER: usize = 4;
+/// Number of host frames each emulated frame is held for in slow motion
+const SLOW_MOTION_DIVISOR: usize = 4;
+
+const MIN_TICKS_PER_FRAME: usize = 1;
+const MAX_TICKS_PER_FRAME: usize = 1000;
+
+/*
+    Keeps track of how fast the emulator should run.
+    The frontend asks it how many emulated frames to run every host frame.
+*/
+pub struct Speed {
+    /// instructions executed for every emulated frame
+    ticks_per_frame: usize,
+    /// emulated frames per host frame while fast-forward is held
+    ff_multiplier: usize,
+    paused: bool,
+    /// set when a single frame should be run while paused
+    step: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    /// set while a movie is recorded or played, changing the speed would desync it
+    ticks_locked: bool,
+    /// counts host frames so slow motion can skip some of them
+    host_frames: usize,
+}
+
+impl Speed {
+    pub fn new(ticks_per_frame: usize, ff_multiplier: usize) -> Self {
+        Self {
+            ticks_per_frame: ticks_per_frame.clamp(MIN_TICKS_PER_FRAME, MAX_TICKS_PER_FRAME),
+            ff_multiplier: ff_multiplier.max(1),
+            paused: false,
+            step: false,
+            fast_forward: false,
+            slow_motion: false,
+            ticks_locked: false,
+            host_frames: 0,
+        }
+    }
+
+    pub fn ticks_per_frame(&self) -> usize {
+        self.ticks_per_frame
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Runs exactly one frame on the next host frame, pausing if needed
+    pub fn frame_advance(&mut self) {
+        self.paused = true;
+        self.step = true;
+    }
+
+    pub fn set_fast_forward(&mut self, held: bool) {
+        self.fast_forward = held;
+    }
+
+    pub fn toggle_slow_motion(&mut self) {
+        self.slow_motion = !self.slow_motion;
+    }
+
+    /// Prevents the ticks per frame from being changed at runtime
+    pub fn lock_ticks(&mut self) {
+        self.ticks_locked = true;
+    }
+
+    pub fn increase_ticks(&mut self) {
+        if self.ticks_locked {
+            return;
+        }
+        self.ticks_per_frame = (self.ticks_per_frame + 1).min(MAX_TICKS_PER_FRAME);
+    }
+
+    pub fn decrease_ticks(&mut self) {
+        if self.ticks_locked {
+            return;
+        }
+        self.ticks_per_frame = (self.ticks_per_frame - 1).max(MIN_TICKS_PER_FRAME);
+    }
+
+    /// Returns how many emulated frames to run during this host frame
+    pub fn frames_to_run(&mut self) -> usize {
+        self.host_frames = self.host_frames.wrapping_add(1);
+
+        if self.paused {
+            let step = self.step;
+            self.step = false;
+            return step as usize;
+        }
+        if self.fast_forward {
+            return self.ff_multiplier;
+        }
+        if self.slow_motion && !self.host_frames.is_multiple_of(SLOW_MOTION_DIVISOR) {
+            return 0;
+        }
+        1
+    }
+
+    /// Window title of game describing the current speed
+    pub fn title(&self, game: &str) -> String {
+        let state = if self.paused {
+            "paused".to_string()
+        } else if self.fast_forward {
+            format!("x{}", self.ff_multiplier)
+        } else if self.slow_motion {
+            format!("x1/{}", SLOW_MOTION_DIVISOR)
+        } else {
+            "x1".to_string()
+        };
+        format!("{} [{} | {} ticks/frame]", game, state, self.ticks_per_frame)
+    }
+}
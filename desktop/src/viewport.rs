@@ -0,0 +1,49 @@
+use sdl2::rect::Rect;
+
+/*
+    Where the CHIP-8 display goes inside the window: scaled as large as it
+    fits while keeping its aspect ratio, centered, with the rest of the
+    window left as black bars.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    x: i32,
+    y: i32,
+    /// host pixels per CHIP-8 pixel
+    scale: f32,
+    /// display size in CHIP-8 pixels
+    width: u32,
+    height: u32,
+}
+
+impl Viewport {
+    /// Fits a display of width x height into the output, integer scaling keeps
+    /// every CHIP-8 pixel the same size unless the output is too small for it
+    pub fn fit(output: (u32, u32), width: u32, height: u32, integer: bool) -> Self {
+        let mut scale = f32::min(output.0 as f32 / width as f32, output.1 as f32 / height as f32);
+        if integer && scale >= 1.0 {
+            scale = scale.floor();
+        }
+        let x = ((output.0 as f32 - width as f32 * scale) / 2.0) as i32;
+        let y = ((output.1 as f32 - height as f32 * scale) / 2.0) as i32;
+        Self { x, y, scale, width, height }
+    }
+
+    /// Area covered by the whole display
+    pub fn rect(&self) -> Rect {
+        let (w, h) = (self.offset(self.width), self.offset(self.height));
+        Rect::new(self.x, self.y, w.max(1) as u32, h.max(1) as u32)
+    }
+
+    /// Area covered by the CHIP-8 pixel at (x, y), neighbours never overlap or leave gaps
+    pub fn pixel(&self, x: u32, y: u32) -> Rect {
+        let (left, right) = (self.offset(x), self.offset(x + 1));
+        let (top, bottom) = (self.offset(y), self.offset(y + 1));
+        Rect::new(self.x + left, self.y + top, (right - left).max(1) as u32, (bottom - top).max(1) as u32)
+    }
+
+    /// Host pixels from the display's edge to the start of CHIP-8 pixel n
+    fn offset(&self, n: u32) -> i32 {
+        (n as f32 * self.scale).round() as i32
+    }
+}
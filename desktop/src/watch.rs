@@ -0,0 +1,41 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Host frames between two looks at the file, about half a second
+const POLL_INTERVAL: u32 = 30;
+
+/// Notices when a file is rewritten by polling its modification time
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// host frames until the next poll
+    countdown: u32,
+}
+
+impl Watcher {
+    pub fn new(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Self { path, modified, countdown: POLL_INTERVAL }
+    }
+
+    /// Called every host frame, true once the file has changed since the last time
+    pub fn changed(&mut self) -> bool {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return false;
+        }
+        self.countdown = POLL_INTERVAL;
+
+        // The file can briefly disappear while it is being rewritten
+        let Ok(modified) = fs::metadata(&self.path).and_then(|m| m.modified()) else {
+            return false;
+        };
+        if self.modified == Some(modified) {
+            return false;
+        }
+        self.modified = Some(modified);
+        true
+    }
+}
@@ -0,0 +1,511 @@
+mod script;
+
+use chip8_core::*;
+use chip8_core::audio::{Buzzer, WavWriter, DEFAULT_SAMPLE_RATE};
+use chip8_core::cheats::{CheatDb, Freezer, MemorySearch, SearchFilter};
+use chip8_core::coverage::Coverage;
+use chip8_core::flowgraph::{FlowGraph, RegionKind};
+use chip8_core::gdb::GdbStub;
+use chip8_core::hashlog::{Divergence, HashLog, Side};
+use chip8_core::image::Image;
+use chip8_core::movie::{Movie, MoviePlayer, Verdict};
+use chip8_core::patch;
+use chip8_core::profiler::Profiler;
+use chip8_core::quirks::Quirks;
+use chip8_core::romdb::RomDb;
+use chip8_core::trace::{TraceFilter, TraceFormat, Tracer};
+use chip8_core::video::{VideoFormat, VideoRecorder};
+use script::{Script, ScreenshotStyle};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::process::ExitCode;
+
+const DEFAULT_FRAMES: u64 = 600;
+const DEFAULT_TICKS_PER_FRAME: usize = 10;
+/// Addresses listed once the memory searches are done
+const MAX_SEARCH_RESULTS: usize = 32;
+
+const USAGE: &str = "Usage: headless [--frames n] [--ticks ticks_per_frame] [--seed n] [--play movie]
+                [--quirks profile|list] [--romdb file]
+                [--hash-log file] [--instr-frames first-last]
+                [--trace file] [--trace-binary] [--trace-addrs first-last (hex)]
+                [--trace-ops digits (hex, e.g. 8DF)] [--trace-frames first-last]
+                [--profile file] [--coverage file] [--lcov file]
+                [--screenshot file.png] [--video file.gif|file.y4m|dir] [--scale n]
+                [--wav file] [--cheats file] [--search frame:filter]...
+                [--patch file.ips|file.bps] [--script file.rhai]
+                [--gdb port] path/to/game
+       headless diff a.log b.log
+       headless cfg path/to/game [out.dot]";
+
+/// Command line options for a headless run
+struct Options {
+    rom: String,
+    /// IPS or BPS patch applied to the ROM before it is loaded
+    patch: Option<String>,
+    /// frames to run, defaults to the length of the movie when playing one
+    frames: Option<u64>,
+    /// overrides the ROM database's speed
+    ticks_per_frame: Option<usize>,
+    /// overrides the ROM database's quirks
+    quirks: Option<Quirks>,
+    /// ROM database override file
+    romdb: Option<String>,
+    seed: u64,
+    play: Option<String>,
+    /// file to write the per frame hash log to, "-" for stdout
+    hash_log: Option<String>,
+    /// frames whose instructions are also hashed one by one
+    instr_frames: Option<RangeInclusive<u64>>,
+    /// file to write an instruction trace to
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+    /// file to write a profiler report to, "-" for stdout
+    profile: Option<String>,
+    /// file to write an annotated disassembly with coverage flags to, "-" for stdout
+    coverage: Option<String>,
+    /// file to write an lcov tracefile to
+    lcov: Option<String>,
+    /// PNG file to write the final display to
+    screenshot: Option<String>,
+    /// file to record every frame to, the format depends on the extension
+    video: Option<String>,
+    /// size of a CHIP-8 pixel in the screenshot and video
+    scale: u32,
+    /// file to write the buzzer's audio to
+    wav: Option<String>,
+    /// cheat list file, the ROM's cheats in it are all enabled
+    cheats: Option<String>,
+    /// memory searches run at the end of the given frames, in order
+    searches: Vec<(u64, SearchFilter)>,
+    /// Rhai script run alongside the ROM
+    script: Option<String>,
+    /// local port to wait for a GDB client on instead of running the frames
+    gdb: Option<u16>,
+}
+
+/// Parses "first-last" or a single frame number
+fn parse_range(arg: &str) -> Option<RangeInclusive<u64>> {
+    match arg.split_once('-') {
+        Some((first, last)) => Some(first.parse().ok()?..=last.parse().ok()?),
+        None => {
+            let frame = arg.parse().ok()?;
+            Some(frame..=frame)
+        },
+    }
+}
+
+/// Parses a hex address range "first-last" or a single address
+fn parse_addr_range(arg: &str) -> Option<RangeInclusive<u16>> {
+    let hex = |s: &str| u16::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    match arg.split_once('-') {
+        Some((first, last)) => Some(hex(first)?..=hex(last)?),
+        None => {
+            let addr = hex(arg)?;
+            Some(addr..=addr)
+        },
+    }
+}
+
+/// Parses a list of opcode classes like "8DF" into a bitmask
+fn parse_classes(arg: &str) -> Option<u16> {
+    arg.chars()
+        .try_fold(0u16, |mask, c| Some(mask | (1 << c.to_digit(16)?)))
+}
+
+/// Parses the command line, returns None if it is malformed
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        rom: String::new(),
+        patch: None,
+        frames: None,
+        ticks_per_frame: None,
+        quirks: None,
+        romdb: None,
+        seed: 0,
+        play: None,
+        hash_log: None,
+        instr_frames: None,
+        trace: None,
+        trace_format: TraceFormat::Text,
+        trace_filter: TraceFilter::default(),
+        profile: None,
+        coverage: None,
+        lcov: None,
+        screenshot: None,
+        video: None,
+        scale: 1,
+        wav: None,
+        cheats: None,
+        searches: Vec::new(),
+        script: None,
+        gdb: None,
+    };
+    let mut rom = None;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--frames" => options.frames = Some(iter.next()?.parse().ok()?),
+            "--ticks" => options.ticks_per_frame = Some(iter.next()?.parse().ok()?),
+            "--quirks" => options.quirks = Some(Quirks::parse(iter.next()?).ok()?),
+            "--romdb" => options.romdb = Some(iter.next()?.clone()),
+            "--seed" => options.seed = iter.next()?.parse().ok()?,
+            "--play" => options.play = Some(iter.next()?.clone()),
+            "--hash-log" => options.hash_log = Some(iter.next()?.clone()),
+            "--instr-frames" => options.instr_frames = Some(parse_range(iter.next()?)?),
+            "--trace" => options.trace = Some(iter.next()?.clone()),
+            "--trace-binary" => options.trace_format = TraceFormat::Binary,
+            "--trace-addrs" => options.trace_filter.addrs = Some(parse_addr_range(iter.next()?)?),
+            "--trace-ops" => options.trace_filter.classes = Some(parse_classes(iter.next()?)?),
+            "--trace-frames" => options.trace_filter.frames = Some(parse_range(iter.next()?)?),
+            "--profile" => options.profile = Some(iter.next()?.clone()),
+            "--coverage" => options.coverage = Some(iter.next()?.clone()),
+            "--lcov" => options.lcov = Some(iter.next()?.clone()),
+            "--screenshot" => options.screenshot = Some(iter.next()?.clone()),
+            "--video" => options.video = Some(iter.next()?.clone()),
+            "--wav" => options.wav = Some(iter.next()?.clone()),
+            "--scale" => options.scale = iter.next()?.parse().ok().filter(|s| *s > 0)?,
+            "--script" => options.script = Some(iter.next()?.clone()),
+            "--patch" => options.patch = Some(iter.next()?.clone()),
+            "--gdb" => options.gdb = Some(iter.next()?.parse().ok()?),
+            "--cheats" => options.cheats = Some(iter.next()?.clone()),
+            "--search" => {
+                // Searches run at the end of a frame, so the first one is frame 1
+                let (frame, filter) = iter.next()?.split_once(':')?;
+                let frame = frame.parse().ok().filter(|f| *f > 0)?;
+                options.searches.push((frame, SearchFilter::parse(filter).ok()?));
+            },
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+
+    options.rom = rom?;
+    // A movie brings the cheats it was recorded with
+    if options.play.is_some() && options.cheats.is_some() {
+        return None;
+    }
+    options.searches.sort_by_key(|(frame, _)| *frame);
+    Some(options)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<_> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("diff") {
+        return match &args[2..] {
+            [a, b] => diff(a, b),
+            _ => {
+                println!("{}", USAGE);
+                ExitCode::FAILURE
+            },
+        };
+    }
+
+    if args.get(1).map(String::as_str) == Some("cfg") {
+        return match &args[2..] {
+            [rom] => cfg(rom, None),
+            [rom, out] => cfg(rom, Some(out)),
+            _ => {
+                println!("{}", USAGE);
+                ExitCode::FAILURE
+            },
+        };
+    }
+
+    let Some(options) = parse_args(&args) else {
+        println!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    run(&options)
+}
+
+/// Runs a ROM without a window and reports the final state
+fn run(options: &Options) -> ExitCode {
+    let mut rom = fs::read(&options.rom).expect("Unable to open file 💀");
+    if let Some(path) = options.patch.as_ref() {
+        let data = fs::read(path).expect("Unable to open patch");
+        rom = patch::apply(&rom, &data).unwrap_or_else(|e| panic!("Invalid patch {}: {}", path, e));
+    }
+    let mut chip8 = Emu::with_seed(options.seed);
+    chip8.load(&rom).unwrap_or_else(|e| panic!("Invalid ROM {}: {}", options.rom, e));
+
+    // Pick quirks and speed for known games, the command line wins
+    let mut db = RomDb::builtin();
+    if let Some(path) = options.romdb.as_ref() {
+        let text = fs::read_to_string(path).expect("Unable to open ROM database");
+        db.load_overrides(&text).unwrap_or_else(|e| panic!("Invalid ROM database {}: {}", path, e));
+    }
+    let info = db.lookup(&rom).cloned().unwrap_or_default();
+    chip8.set_quirks(options.quirks.unwrap_or(info.quirks));
+
+    let mut ticks_per_frame = options.ticks_per_frame.or(info.ticks_per_frame).unwrap_or(DEFAULT_TICKS_PER_FRAME);
+    let mut frames = options.frames.unwrap_or(DEFAULT_FRAMES);
+    let mut player = options.play.as_ref().map(|path| {
+        let text = fs::read_to_string(path).expect("Unable to open movie");
+        let movie = Movie::parse(&text).unwrap_or_else(|e| panic!("Invalid movie {}: {}", path, e));
+        if !movie.matches_rom(&rom) {
+            eprintln!("Warning: movie was recorded on a different ROM");
+        }
+        ticks_per_frame = movie.ticks_per_frame;
+        frames = options.frames.unwrap_or(movie.end_frame);
+        MoviePlayer::new(movie, &mut chip8)
+    });
+
+    if let Some(path) = options.trace.as_ref() {
+        let file = File::create(path).expect("Unable to create trace file");
+        let tracer = Tracer::new(Box::new(BufWriter::new(file)), options.trace_format, options.trace_filter.clone());
+        chip8.set_tracer(Some(tracer));
+    }
+
+    if options.profile.is_some() {
+        chip8.set_profiler(Some(Profiler::new()));
+    }
+
+    if options.coverage.is_some() || options.lcov.is_some() {
+        chip8.set_coverage(Some(Coverage::new()));
+    }
+
+    let (fg, bg) = info.colors.unwrap_or((0xFFFFFF, 0x000000));
+    let mut video = options.video.as_ref().map(|path| {
+        let (width, height) = (SCREEN_WIDTH as u32 * options.scale, SCREEN_HEIGHT as u32 * options.scale);
+        VideoRecorder::create(path, VideoFormat::from_path(path), width, height)
+            .unwrap_or_else(|e| panic!("Unable to create video {}: {}", path, e))
+    });
+
+    let mut buzzer = Buzzer::new(DEFAULT_SAMPLE_RATE);
+    let mut wav = options.wav.as_ref().map(|path| {
+        let file = File::create(path).expect("Unable to create WAV file");
+        WavWriter::new(BufWriter::new(file), buzzer.sample_rate()).expect("Unable to write WAV file")
+    });
+
+    // The debugger drives the machine instead, the reports below cover its session
+    if let Some(port) = options.gdb {
+        let listener = TcpListener::bind(("127.0.0.1", port)).expect("Unable to listen for gdb");
+        println!("Waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, _) = listener.accept().expect("Unable to accept gdb connection");
+        GdbStub::new(stream, ticks_per_frame).serve(&mut chip8).expect("gdb connection failed");
+        frames = 0;
+    }
+
+    let mut freezer = Freezer::new();
+    if let Some(path) = options.cheats.as_ref() {
+        let text = fs::read_to_string(path).expect("Unable to open cheat list");
+        let db = CheatDb::parse(&text).unwrap_or_else(|e| panic!("Invalid cheat list {}: {}", path, e));
+        for cheat in db.lookup(&rom) {
+            println!("cheat: {}", cheat.name);
+            freezer.enable(cheat);
+        }
+    }
+    if let Some(play) = player.as_ref() {
+        freezer = play.movie().freezer.clone();
+    }
+
+    // A broken script ends the run like a bad argument, with the error and no backtrace
+    let script_failed = |e: String| {
+        println!("Script error in {}: {}", options.script.as_deref().unwrap_or_default(), e);
+        ExitCode::FAILURE
+    };
+    let mut script = match options.script.as_ref() {
+        Some(path) => {
+            let source = fs::read_to_string(path).expect("Unable to open script");
+            let style = ScreenshotStyle { scale: options.scale, fg, bg };
+            match Script::new(&source, &mut chip8, style) {
+                Ok(script) => Some(script),
+                Err(e) => return script_failed(e),
+            }
+        },
+        None => None,
+    };
+
+    // The first snapshot is the freshly loaded ROM
+    let mut search = (!options.searches.is_empty()).then(|| MemorySearch::new(chip8.memory()));
+    let mut searches = options.searches.iter().peekable();
+
+    let mut log = HashLog::new();
+    for _ in 0..frames {
+        let frame = chip8.frame();
+        if let Some(play) = player.as_mut() {
+            play.apply(&mut chip8);
+        }
+        freezer.apply(&mut chip8);
+
+        let hash_instrs = options.instr_frames.as_ref().is_some_and(|r| r.contains(&frame));
+        for index in 0..ticks_per_frame {
+            if let Err(e) = script.as_mut().map_or(Ok(()), |s| s.before_tick(&mut chip8)) {
+                return script_failed(e);
+            }
+            let pc = chip8.pc();
+            chip8.tick();
+            if hash_instrs {
+                log.record_instr(frame, index, pc, &chip8);
+            }
+            if let Err(e) = script.as_mut().map_or(Ok(()), |s| s.after_tick(&mut chip8)) {
+                return script_failed(e);
+            }
+        }
+        chip8.tick_timers();
+        if let Err(e) = script.as_mut().map_or(Ok(()), |s| s.end_frame(&mut chip8)) {
+            return script_failed(e);
+        }
+        log.record_frame(frame, &chip8);
+        while let Some((_, filter)) = searches.next_if(|(at, _)| *at <= chip8.frame()) {
+            let left = search.as_mut().unwrap().refine(chip8.memory(), *filter);
+            println!("search at frame {}: {} leaves {} addresses", chip8.frame(), filter, left);
+        }
+        if let Some(video) = video.as_mut() {
+            let image = Image::from_display(chip8.get_display(), options.scale, fg, bg);
+            video.add_frame(&image).expect("Unable to write video frame");
+        }
+        if let Some(wav) = wav.as_mut() {
+            wav.write_samples(&buzzer.frame(chip8.buzzer())).expect("Unable to write WAV file");
+        }
+        if script.as_ref().is_some_and(Script::stopped) {
+            break;
+        }
+    }
+
+    if let Some(wav) = wav {
+        wav.finish().expect("Unable to write WAV file");
+    }
+
+    if let Some(video) = video {
+        video.finish().expect("Unable to write video");
+    }
+
+    if let Some(tracer) = chip8.set_tracer(None) {
+        tracer.finish().expect("Unable to write trace");
+    }
+
+    if let (Some(profiler), Some(path)) = (chip8.set_profiler(None), options.profile.as_deref()) {
+        let report = profiler.report(chip8.memory());
+        match path {
+            "-" => print!("{}", report),
+            _ => fs::write(path, report).expect("Unable to write profile"),
+        }
+    }
+
+    if let Some(coverage) = chip8.set_coverage(None) {
+        if let Some(path) = options.coverage.as_deref() {
+            let report = coverage.annotated(chip8.memory(), rom.len());
+            match path {
+                "-" => print!("{}", report),
+                _ => fs::write(path, report).expect("Unable to write coverage"),
+            }
+        }
+        if let Some(path) = options.lcov.as_deref() {
+            fs::write(path, coverage.lcov(&options.rom, rom.len())).expect("Unable to write lcov");
+        }
+    }
+
+    if let Some(path) = options.screenshot.as_ref() {
+        let image = Image::from_display(chip8.get_display(), options.scale, fg, bg);
+        let file = File::create(path).expect("Unable to create screenshot");
+        image.write_png(BufWriter::new(file)).unwrap_or_else(|e| panic!("Unable to write screenshot {}: {}", path, e));
+    }
+
+    match options.hash_log.as_deref() {
+        Some("-") => print!("{}", log),
+        Some(path) => fs::write(path, log.to_string()).expect("Unable to write hash log"),
+        None => (),
+    }
+
+    for (frame, filter) in searches {
+        println!("search at frame {}: {} did not run, the run ended at frame {}", frame, filter, chip8.frame());
+    }
+    if let Some(search) = search.as_ref() {
+        for addr in search.candidates().iter().take(MAX_SEARCH_RESULTS) {
+            println!("{:03X}: {:02X}", addr, search.previous(*addr));
+        }
+        if search.candidates().len() > MAX_SEARCH_RESULTS {
+            println!("... {} more", search.candidates().len() - MAX_SEARCH_RESULTS);
+        }
+    }
+
+    println!("frame {} display {:016x} state {:016x}", chip8.frame(), chip8.display_hash(), chip8.state_hash());
+
+    match player.as_mut() {
+        Some(play) if chip8.frame() == play.movie().end_frame => match play.verify(&mut chip8) {
+            Verdict::Match => println!("movie: final state matches the recording"),
+            Verdict::Mismatch { expected, actual } => {
+                println!("movie: final state {:016x} does not match recorded {:016x}", actual, expected);
+                return ExitCode::FAILURE;
+            },
+        },
+        _ => (),
+    }
+    ExitCode::SUCCESS
+}
+
+/// Compares two hash logs and prints where they diverge
+fn diff(a: &str, b: &str) -> ExitCode {
+    let read = |path: &str| {
+        let text = fs::read_to_string(path).expect("Unable to open hash log");
+        HashLog::parse(&text).unwrap_or_else(|e| panic!("Invalid hash log {}: {}", path, e))
+    };
+    let (log_a, log_b) = (read(a), read(b));
+
+    match log_a.first_divergence(&log_b) {
+        None => {
+            println!("no divergence");
+            ExitCode::SUCCESS
+        },
+        Some(Divergence { frame, only_in: Some(side), .. }) => {
+            let (has, lacks) = if side == Side::This { (a, b) } else { (b, a) };
+            println!("first divergence at frame {} (only in {}, missing from {})", frame, has, lacks);
+            ExitCode::FAILURE
+        },
+        Some(div) => {
+            let what = if div.display_differs { "display and state" } else { "state" };
+            println!("first divergence at frame {} ({} differ)", div.frame, what);
+            match div.instr {
+                Some((index, pc)) => println!("first diverging instruction: #{} at {:03x}", index, pc),
+                None => println!("rerun with --instr-frames {} to find the instruction", div.frame),
+            }
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Statically analyzes a ROM, prints a summary and writes its control-flow graph as DOT
+fn cfg(path: &str, out: Option<&String>) -> ExitCode {
+    let rom = fs::read(path).expect("Unable to open file 💀");
+    // BNNN depends on the jump quirk, known games get theirs from the ROM database
+    let quirks = RomDb::builtin().lookup(&rom).map(|info| info.quirks).unwrap_or_default();
+    let graph = FlowGraph::analyze(&rom, quirks);
+
+    let mut chip8 = Emu::new();
+    chip8.load(&rom).unwrap_or_else(|e| panic!("Invalid ROM {}: {}", path, e));
+    let dot = graph.to_dot(chip8.memory());
+    match out {
+        Some(out) => fs::write(out, dot).expect("Unable to write graph"),
+        None => print!("{}", dot),
+    }
+
+    // Keep stdout clean for the graph
+    eprintln!("{} blocks, {} subroutines", graph.blocks.len(), graph.subroutines.len());
+    for region in graph.regions.iter() {
+        let kind = match region.kind {
+            RegionKind::Code => "code",
+            RegionKind::Data => "data",
+            RegionKind::Unreachable => "unreachable",
+        };
+        eprintln!("  {:03X}-{:03X} {}", region.start, region.end - 1, kind);
+    }
+    for jump in graph.computed_jumps.iter() {
+        let targets = jump.targets();
+        eprintln!(
+            "  computed jump (BNNN) at {:03X} depends on V{:X}, targets {:03X}-{:03X}",
+            jump.addr, jump.reg, targets.start(), targets.end()
+        );
+    }
+    for (addr, target) in graph.bad_targets.iter() {
+        eprintln!("  {:03X} branches to {:03X} outside the ROM", addr, target);
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,357 @@
+use chip8_core::debugger::Registers;
+use chip8_core::image::Image;
+use chip8_core::Emu;
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, Scope, AST, INT};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::rc::Rc;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// How screenshots taken by a script are drawn
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenshotStyle {
+    pub scale: u32,
+    pub fg: u32,
+    pub bg: u32,
+}
+
+/// Callbacks a script registered, run in the order they were added
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    pc: BTreeMap<u16, Vec<FnPtr>>,
+    write: BTreeMap<u16, Vec<FnPtr>>,
+}
+
+/*
+    Copy of the machine a callback works on. It is taken from the emulator
+    before the callback runs, and whatever the script changed is written
+    back once it returns.
+*/
+#[derive(Default)]
+struct Machine {
+    regs: Registers,
+    memory: Vec<u8>,
+    display: Vec<bool>,
+    frame: u64,
+    /// keypad changes made by the script, in order
+    keys: Vec<(usize, bool)>,
+    regs_changed: bool,
+    memory_changed: bool,
+    /// set by stop(), ends the run after the callback
+    stopped: bool,
+}
+
+impl Machine {
+    fn load(&mut self, emu: &Emu) {
+        self.regs = emu.registers();
+        self.memory.clear();
+        self.memory.extend_from_slice(emu.memory());
+        self.display.clear();
+        self.display.extend_from_slice(emu.get_display());
+        self.frame = emu.frame();
+    }
+
+    fn store(&mut self, emu: &mut Emu) -> Result<(), String> {
+        if self.regs_changed {
+            emu.set_registers(&self.regs)?;
+        }
+        if self.memory_changed {
+            emu.write_memory(0, &self.memory);
+        }
+        for (key, pressed) in self.keys.drain(..) {
+            emu.keypress(key, pressed);
+        }
+        self.regs_changed = false;
+        self.memory_changed = false;
+        Ok(())
+    }
+}
+
+/*
+    A Rhai script driving a headless run. The script body runs once after the
+    ROM is loaded and registers callbacks:
+
+        on_frame(|frame| ...)        at the end of every frame
+        on_pc(addr, |addr| ...)      before the instruction at addr runs
+        on_write(addr, |addr, value| ...)   after an instruction writes addr
+
+    Everywhere, including the body, it can use:
+
+        frame()                      frames run so far
+        pc(), set_pc(x), i(), set_i(x), v(n), set_v(n, x)
+        dt(), set_dt(x), st(), set_st(x)
+        peek(addr), poke(addr, value)
+        press(key), release(key)     key is 0-F
+        screenshot("file.png")
+        stop()                       ends the run after this frame
+*/
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    hooks: Rc<RefCell<Hooks>>,
+    machine: Rc<RefCell<Machine>>,
+}
+
+impl Script {
+    /// Compiles source and runs its body against emu
+    pub fn new(source: &str, emu: &mut Emu, style: ScreenshotStyle) -> Result<Self, String> {
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let machine = Rc::new(RefCell::new(Machine::default()));
+        let mut engine = Engine::new();
+        register_hooks(&mut engine, &hooks);
+        register_machine(&mut engine, &machine, style);
+
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let script = Self { engine, ast, hooks, machine };
+        script.machine.borrow_mut().load(emu);
+        script.engine.run_ast_with_scope(&mut Scope::new(), &script.ast).map_err(|e| e.to_string())?;
+        script.machine.borrow_mut().store(emu)?;
+        Ok(script)
+    }
+
+    /// True once the script called stop()
+    pub fn stopped(&self) -> bool {
+        self.machine.borrow().stopped
+    }
+
+    /// Runs the on_pc callbacks for the instruction about to execute
+    pub fn before_tick(&mut self, emu: &mut Emu) -> Result<(), String> {
+        let pc = emu.pc();
+        let callbacks = match self.hooks.borrow().pc.get(&pc) {
+            Some(callbacks) => callbacks.clone(),
+            None => return Ok(()),
+        };
+        self.call(emu, &callbacks, || (pc as INT,))
+    }
+
+    /// Runs the on_write callbacks for the bytes the last instruction wrote
+    pub fn after_tick(&mut self, emu: &mut Emu) -> Result<(), String> {
+        let Some(access) = emu.last_access().filter(|a| a.write) else {
+            return Ok(());
+        };
+        let last = access.addr.saturating_add(access.len.saturating_sub(1) as u16);
+        let written: Vec<(u16, Vec<FnPtr>)> = self
+            .hooks
+            .borrow()
+            .write
+            .range(access.addr..=last)
+            .map(|(addr, callbacks)| (*addr, callbacks.clone()))
+            .collect();
+        for (addr, callbacks) in written {
+            let value = emu.memory()[addr as usize];
+            self.call(emu, &callbacks, || (addr as INT, value as INT))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the on_frame callbacks, call once the frame's timers ticked
+    pub fn end_frame(&mut self, emu: &mut Emu) -> Result<(), String> {
+        let callbacks = self.hooks.borrow().frame.clone();
+        if callbacks.is_empty() {
+            return Ok(());
+        }
+        let frame = emu.frame();
+        self.call(emu, &callbacks, || (frame as INT,))
+    }
+
+    fn call<A: rhai::FuncArgs>(&mut self, emu: &mut Emu, callbacks: &[FnPtr], args: impl Fn() -> A) -> Result<(), String> {
+        self.machine.borrow_mut().load(emu);
+        for callback in callbacks {
+            // Whatever the callback evaluates to is ignored
+            let _ = callback.call::<Dynamic>(&self.engine, &self.ast, args()).map_err(|e| e.to_string())?;
+        }
+        self.machine.borrow_mut().store(emu)
+    }
+}
+
+/// Checks an address or register number given by a script
+fn checked<T: TryFrom<INT>>(value: INT, max: INT, what: &str) -> ScriptResult<T> {
+    (0..=max)
+        .contains(&value)
+        .then(|| T::try_from(value).ok())
+        .flatten()
+        .ok_or_else(|| format!("invalid {} {}", what, value).into())
+}
+
+fn register_hooks(engine: &mut Engine, hooks: &Rc<RefCell<Hooks>>) {
+    let h = hooks.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| h.borrow_mut().frame.push(callback));
+    let h = hooks.clone();
+    engine.register_fn("on_pc", move |addr: INT, callback: FnPtr| -> ScriptResult<()> {
+        h.borrow_mut().pc.entry(checked(addr, 0xFFF, "address")?).or_default().push(callback);
+        Ok(())
+    });
+    let h = hooks.clone();
+    engine.register_fn("on_write", move |addr: INT, callback: FnPtr| -> ScriptResult<()> {
+        h.borrow_mut().write.entry(checked(addr, 0xFFF, "address")?).or_default().push(callback);
+        Ok(())
+    });
+}
+
+fn register_machine(engine: &mut Engine, machine: &Rc<RefCell<Machine>>, style: ScreenshotStyle) {
+    let m = machine.clone();
+    engine.register_fn("frame", move || m.borrow().frame as INT);
+
+    let m = machine.clone();
+    engine.register_fn("pc", move || m.borrow().regs.pc as INT);
+    let m = machine.clone();
+    engine.register_fn("set_pc", move |value: INT| -> ScriptResult<()> {
+        let mut m = m.borrow_mut();
+        m.regs.pc = checked(value, 0xFFF, "address")?;
+        m.regs_changed = true;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("i", move || m.borrow().regs.i as INT);
+    let m = machine.clone();
+    engine.register_fn("set_i", move |value: INT| -> ScriptResult<()> {
+        let mut m = m.borrow_mut();
+        m.regs.i = checked(value, 0xFFF, "address")?;
+        m.regs_changed = true;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("v", move |n: INT| -> ScriptResult<INT> {
+        Ok(m.borrow().regs.v[checked::<usize>(n, 0xF, "register")?] as INT)
+    });
+    let m = machine.clone();
+    engine.register_fn("set_v", move |n: INT, value: INT| -> ScriptResult<()> {
+        let mut m = m.borrow_mut();
+        m.regs.v[checked::<usize>(n, 0xF, "register")?] = checked(value, 0xFF, "value")?;
+        m.regs_changed = true;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("dt", move || m.borrow().regs.dt as INT);
+    let m = machine.clone();
+    engine.register_fn("set_dt", move |value: INT| -> ScriptResult<()> {
+        let mut m = m.borrow_mut();
+        m.regs.dt = checked(value, 0xFF, "value")?;
+        m.regs_changed = true;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("st", move || m.borrow().regs.st as INT);
+    let m = machine.clone();
+    engine.register_fn("set_st", move |value: INT| -> ScriptResult<()> {
+        let mut m = m.borrow_mut();
+        m.regs.st = checked(value, 0xFF, "value")?;
+        m.regs_changed = true;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("peek", move |addr: INT| -> ScriptResult<INT> {
+        Ok(m.borrow().memory[checked::<usize>(addr, 0xFFF, "address")?] as INT)
+    });
+    let m = machine.clone();
+    engine.register_fn("poke", move |addr: INT, value: INT| -> ScriptResult<()> {
+        let mut m = m.borrow_mut();
+        let addr: usize = checked(addr, 0xFFF, "address")?;
+        m.memory[addr] = checked(value, 0xFF, "value")?;
+        m.memory_changed = true;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("press", move |key: INT| -> ScriptResult<()> {
+        m.borrow_mut().keys.push((checked(key, 0xF, "key")?, true));
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("release", move |key: INT| -> ScriptResult<()> {
+        m.borrow_mut().keys.push((checked(key, 0xF, "key")?, false));
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        let image = Image::from_display(&m.borrow().display, style.scale, style.fg, style.bg);
+        let file = File::create(path).map_err(|e| format!("Unable to create screenshot {}: {}", path, e))?;
+        image.write_png(BufWriter::new(file)).map_err(|e| format!("Unable to write screenshot {}: {}", path, e))?;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("stop", move || m.borrow_mut().stopped = true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// V0 = 5, I = 300, then forever: store V0 at 300, V0 += 1
+    const ROM: &[u8] = &[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x70, 0x01, 0x12, 0x04];
+    const STYLE: ScreenshotStyle = ScreenshotStyle { scale: 1, fg: 0xFFFFFF, bg: 0 };
+
+    /// Runs frames of 3 instructions with the script's hooks like the headless loop does
+    fn run(source: &str, frames: usize) -> Result<Emu, String> {
+        let mut emu = Emu::new();
+        emu.load(ROM).unwrap();
+        let mut script = Script::new(source, &mut emu, STYLE)?;
+        for _ in 0..frames {
+            for _ in 0..3 {
+                script.before_tick(&mut emu)?;
+                emu.tick();
+                script.after_tick(&mut emu)?;
+            }
+            emu.tick_timers();
+            script.end_frame(&mut emu)?;
+            if script.stopped() {
+                break;
+            }
+        }
+        Ok(emu)
+    }
+
+    #[test]
+    fn body_changes_the_machine() {
+        let emu = run("set_v(3, 0x42); poke(0x400, peek(0x200)); set_dt(9);", 0).unwrap();
+        assert_eq!(emu.registers().v[3], 0x42);
+        assert_eq!(emu.registers().dt, 9);
+        assert_eq!(emu.memory()[0x400], 0x60);
+    }
+
+    #[test]
+    fn frame_hook_runs_after_every_frame() {
+        let emu = run("on_frame(|f| poke(0x400 + f, f));", 4).unwrap();
+        assert_eq!(&emu.memory()[0x401..0x406], &[1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn pc_hook_runs_before_the_instruction() {
+        // Counts the increments in V1, and V0 is still the stored value when it runs
+        let emu = run("on_pc(0x206, |addr| { set_v(1, v(1) + 1); set_v(2, peek(0x300) - v(0)); });", 5).unwrap();
+        assert_eq!(emu.registers().v[1], 4);
+        assert_eq!(emu.registers().v[2], 0);
+    }
+
+    #[test]
+    fn write_hook_sees_the_written_value() {
+        let emu = run("on_write(0x300, |addr, value| poke(0x400 + value, addr - 0x300 + 1));", 3).unwrap();
+        assert_eq!(emu.memory()[0x300], 7);
+        assert_eq!(&emu.memory()[0x405..0x408], &[1, 1, 1]);
+        assert_eq!(emu.memory()[0x404], 0);
+    }
+
+    #[test]
+    fn stop_ends_the_run() {
+        let emu = run("on_frame(|f| if f == 2 { stop() });", 10).unwrap();
+        assert_eq!(emu.frame(), 2);
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        let error = |source: &str| run(source, 1).err().unwrap();
+        assert!(run("let x = ;", 1).is_err());
+        assert!(error("set_v(16, 0);").contains("invalid register 16"));
+        assert!(error("on_frame(|f| poke(0x1000, 0));").contains("invalid address 4096"));
+        assert!(error("set_i(0x1000);").contains("invalid address 4096"));
+    }
+}